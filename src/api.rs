@@ -1,27 +1,57 @@
-use anyhow::{anyhow, Result};
 use serde::Deserialize;
+use std::env;
 
+use crate::error::ConnectError;
 use crate::server::Server;
 
-const RELAY_LIST_URL: &str = "https://api.mullvad.net/public/relays/wireguard/v1/";
-const REGISTER_KEY_URL: &str = "https://api.mullvad.net/wg";
+const DEFAULT_API_URL: &str = "https://api.mullvad.net";
+const RELAY_LIST_PATH: &str = "/public/relays/wireguard/v1/";
+const REGISTER_KEY_PATH: &str = "/wg";
+
+/// Environment variable overriding the API address, e.g. to point at a local stand-in
+const API_URL_ENV: &str = "MVTUI_API_URL";
+
+const WIREGUARD_PORT: u16 = 51820;
 
 #[derive(Debug, Deserialize)]
 struct ApiRelay {
     hostname: String,
     public_key: String,
     ipv4_addr_in: String,
+    #[serde(default)]
+    ipv6_addr_in: Option<String>,
+    #[serde(default = "default_active")]
+    active: bool,
+    #[serde(default)]
+    owned: bool,
+    #[serde(default)]
+    provider: String,
+    #[serde(default)]
+    multihop_port: Option<u16>,
+    #[serde(default)]
+    socks_name: Option<String>,
+    #[serde(default)]
+    weight: u64,
+}
+
+/// Relays without an `active` field are assumed to be usable
+fn default_active() -> bool {
+    true
 }
 
 #[derive(Debug, Deserialize)]
 struct ApiCity {
     name: String,
+    #[serde(default)]
+    code: String,
     relays: Vec<ApiRelay>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ApiCountry {
     name: String,
+    #[serde(default)]
+    code: String,
     cities: Vec<ApiCity>,
 }
 
@@ -30,13 +60,25 @@ struct ApiResponse {
     countries: Vec<ApiCountry>,
 }
 
+/// Base URL of the API: the environment overrides the settings, which override the default
+pub fn base_url(configured: Option<&str>) -> String {
+    env::var(API_URL_ENV)
+        .ok()
+        .filter(|url| !url.is_empty())
+        .or_else(|| configured.map(str::to_string))
+        .unwrap_or_else(|| DEFAULT_API_URL.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
 /// Fetch the list of WireGuard servers from Mullvad API
-pub async fn fetch_servers() -> Result<Vec<Server>> {
+pub async fn fetch_servers(base_url: &str) -> Result<Vec<Server>, ConnectError> {
     let client = reqwest::Client::new();
     let response: ApiResponse = client
-        .get(RELAY_LIST_URL)
+        .get(format!("{}{}", base_url, RELAY_LIST_PATH))
         .send()
         .await?
+        .error_for_status()?
         .json()
         .await?;
 
@@ -57,9 +99,18 @@ pub async fn fetch_servers() -> Result<Vec<Server>> {
                     hostname: relay.hostname,
                     public_key: relay.public_key,
                     ipv4_addr: relay.ipv4_addr_in,
-                    port: 51820,
+                    ipv6_addr: relay.ipv6_addr_in.filter(|addr| !addr.is_empty()),
+                    port: WIREGUARD_PORT,
+                    multihop_port: relay.multihop_port,
                     country: country.name.clone(),
+                    country_code: country.code.clone(),
                     city: city.name.clone(),
+                    city_code: city.code.clone(),
+                    active: relay.active,
+                    owned: relay.owned,
+                    provider: relay.provider,
+                    socks_name: relay.socks_name,
+                    weight: relay.weight,
                 });
             }
         }
@@ -70,21 +121,136 @@ pub async fn fetch_servers() -> Result<Vec<Server>> {
 
 /// Register a WireGuard public key with Mullvad account
 /// Returns the assigned IP addresses on success
-pub async fn register_public_key(account: &str, public_key: &str) -> Result<String> {
+pub async fn register_public_key(
+    base_url: &str,
+    account: &str,
+    public_key: &str,
+) -> Result<String, ConnectError> {
     let client = reqwest::Client::new();
     let response = client
-        .post(REGISTER_KEY_URL)
+        .post(format!("{}{}", base_url, REGISTER_KEY_PATH))
         .form(&[("account", account), ("pubkey", public_key)])
         .send()
         .await?;
 
+    let status = response.status();
     let text = response.text().await?;
+    let text = text.trim();
+
+    // Proxies and outages answer with an HTML page, which is no use in the message bar
+    if text.starts_with('<') {
+        return Err(ConnectError::Api(format!("Unexpected response (HTTP {})", status)));
+    }
 
     // Mullvad returns IP addresses on success, or an error message
     // Valid response format: "10.x.x.x/32,fc00:bbbb:bbbb:bb01::x:x/128"
-    if text.chars().all(|c| c.is_ascii_hexdigit() || c == ':' || c == '/' || c == '.' || c == ',') {
-        Ok(text)
+    let is_address = |c: char| c.is_ascii_hexdigit() || c == ':' || c == '/' || c == '.' || c == ',';
+    if status.is_success() && !text.is_empty() && text.chars().all(is_address) {
+        Ok(text.to_string())
     } else {
-        Err(anyhow!("Mullvad API error: {}", text))
+        Err(ConnectError::Api(text.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockApi;
+
+    const PUBLIC_KEY: &str = "mQ4m0/3yKh9iQ+fn2nZS9tPfXcC9x5ZrJ1x1CHMn0Fk=";
+
+    #[tokio::test]
+    async fn fetch_servers_parses_relay_list() {
+        let api = MockApi::start().await;
+
+        let servers = fetch_servers(&api.url()).await.unwrap();
+
+        let codes: Vec<&str> = servers.iter().map(|s| s.code.as_str()).collect();
+        assert_eq!(codes, ["se-mma-wg-001", "se-mma-wg-002", "ch-zrh-wg-001"]);
+
+        let malmo = &servers[0];
+        assert_eq!(malmo.country, "Sweden");
+        assert_eq!(malmo.city_code, "mma");
+        assert_eq!(malmo.endpoint(), "193.138.218.220:51820");
+        assert_eq!(malmo.ipv6_addr.as_deref(), Some("2a03:1b20:1:f410::a01f"));
+        assert_eq!(malmo.endpoint_v6().as_deref(), Some("[2a03:1b20:1:f410::a01f]:51820"));
+        assert!(malmo.owned);
+
+        // An empty IPv6 address means there is none
+        assert_eq!(servers[1].ipv6_addr, None);
+        assert_eq!(servers[1].endpoint_v6(), None);
+        assert!(!servers[1].active);
+
+        assert_eq!(servers[2].provider, "M247");
+        assert!(!servers[2].owned);
+    }
+
+    #[tokio::test]
+    async fn fetch_servers_reports_http_errors() {
+        let api = MockApi::start().await;
+
+        let error = fetch_servers(&format!("{}/missing", api.url())).await.unwrap_err();
+
+        assert!(matches!(error, ConnectError::Api(msg) if msg.contains("404")));
+    }
+
+    #[tokio::test]
+    async fn register_public_key_returns_addresses() {
+        let api = MockApi::start().await;
+        api.respond_wg(200, "10.68.12.34/32,fc00:bbbb:bbbb:bb01::5:c21/128\n");
+
+        let address = register_public_key(&api.url(), "1234567890123456", PUBLIC_KEY)
+            .await
+            .unwrap();
+
+        assert_eq!(address, "10.68.12.34/32,fc00:bbbb:bbbb:bb01::5:c21/128");
+        let request = &api.requests()[0];
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/wg"));
+        assert!(request.body.contains("account=1234567890123456"));
+        assert!(request.body.contains("pubkey=mQ4m0%2F3yKh9iQ%2Bfn2nZS9tPfXcC9x5ZrJ1x1CHMn0Fk%3D"));
+    }
+
+    #[tokio::test]
+    async fn register_public_key_reports_invalid_account() {
+        let api = MockApi::start().await;
+        api.respond_wg(400, "Invalid account");
+
+        let error = register_public_key(&api.url(), "0000000000000000", PUBLIC_KEY)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(&error, ConnectError::Api(msg) if msg == "Invalid account"));
+        assert_eq!(error.exit_code(), 7);
+    }
+
+    #[tokio::test]
+    async fn register_public_key_reports_too_many_keys() {
+        let api = MockApi::start().await;
+        api.respond_wg(400, "You have too many WireGuard keys registered");
+
+        let error = register_public_key(&api.url(), "1234567890123456", PUBLIC_KEY)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, ConnectError::Api(msg) if msg.contains("too many")));
+    }
+
+    #[tokio::test]
+    async fn register_public_key_summarizes_html_error_page() {
+        let api = MockApi::start().await;
+        api.respond_wg_html(502, "<html><body><h1>502 Bad Gateway</h1></body></html>");
+
+        let error = register_public_key(&api.url(), "1234567890123456", PUBLIC_KEY)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, ConnectError::Api(msg) if msg == "Unexpected response (HTTP 502 Bad Gateway)"));
+    }
+
+    #[test]
+    fn base_url_prefers_settings_over_default() {
+        // MVTUI_API_URL is not set in tests
+        assert_eq!(base_url(None), "https://api.mullvad.net");
+        assert_eq!(base_url(Some("http://127.0.0.1:8080/")), "http://127.0.0.1:8080");
     }
 }
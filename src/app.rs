@@ -1,12 +1,29 @@
 use anyhow::Result;
+use std::collections::HashMap;
 use std::fs;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::api;
 use crate::config;
-use crate::server::{group_servers, get_cities, get_countries, get_servers_in_city, Server, ServerCache, ServerTree};
-use crate::wireguard::{self, ConnectionStatus};
+use crate::dns::{Blocklist, DnsBackend};
+use crate::error::{ConnectError, Remedy};
+use crate::favorites;
+use crate::firewall::{self, FirewallSettings, KillSwitch, Tunnel};
+use crate::history::{self, Action, HistoryEntry};
+use crate::journal;
+use crate::latency::{self, Latency, LatencyCache};
+use crate::runner::{CommandRunner, SystemRunner};
+use crate::search::{self, SearchResult};
+use crate::select::{self, Constraints, Strategy};
+use crate::server::{group_servers, get_cities, get_countries, get_providers, get_servers_in_city, Server, ServerCache, ServerTree};
+use crate::settings::{self, Settings};
+use crate::stats::TunnelMonitor;
+use crate::task::{Progress, Task};
+use crate::watchdog::{self, Incident, Step, Watchdog};
+use crate::wireguard::{self, Backend, ConnectionStatus, TunnelStats};
 
 /// Current view/screen in the TUI
 #[derive(Debug, Clone, PartialEq)]
@@ -15,6 +32,11 @@ pub enum View {
     Cities,
     Servers,
     Setup,
+    Search,
+    Favorites,
+    History,
+    Stats,
+    Dns,
 }
 
 /// Input mode for text entry
@@ -22,6 +44,7 @@ pub enum View {
 pub enum InputMode {
     Normal,
     AccountInput,
+    Search,
 }
 
 /// Application state
@@ -46,20 +69,64 @@ pub struct App {
     pub selected_country: Option<String>,
     pub selected_city: Option<String>,
 
+    // Search state
+    pub search_query: String,
+    pub search_results: Vec<SearchResult>,
+    pub selected_search_idx: usize,
+    search_return_view: View,
+
+    // Favorite server codes, in the order they were added
+    pub favorites: Vec<String>,
+    pub selected_favorite_idx: usize,
+
+    // Connection history, oldest first
+    pub history: Vec<HistoryEntry>,
+    pub selected_history_idx: usize,
+
+    // Cursor in the DNS content blocking list
+    pub selected_dns_idx: usize,
+
+    // Latest measured latency per server code
+    pub latencies: LatencyCache,
+
     // Connection status
     pub connection_status: ConnectionStatus,
 
+    // Live statistics of the connected tunnel
+    pub tunnel: TunnelMonitor,
+
+    // Dead tunnel detection and its log
+    pub watchdog: Watchdog,
+
     // Autostart server (enabled for systemd)
     pub autostart_server: Option<String>,
 
     // Messages
     pub message: Option<String>,
     pub error: Option<String>,
+    // Typed cause of `error`, kept together with the message it was shown as
+    failure: Option<(String, ConnectError)>,
 
     // Setup state
     pub private_key: Option<String>,
     pub address: Option<String>,
 
+    // Persistent settings (server filter)
+    pub settings: Settings,
+
+    // DNS backend in use, detected at startup unless set in the settings
+    pub dns_backend: DnsBackend,
+
+    // Runs wg, wg-quick, systemctl and friends
+    pub runner: Arc<dyn CommandRunner>,
+
+    // Connect, refresh, setup or latency probe running in the background
+    pub task: Option<Task<Outcome>>,
+
+    // Tunnel stats or watchdog probe running in the background; unlike `task`
+    // it doesn't hold up other actions and isn't shown
+    monitor: Option<Task<Outcome>>,
+
     // Should quit
     pub should_quit: bool,
 }
@@ -85,34 +152,115 @@ impl App {
             selected_country: None,
             selected_city: None,
 
+            search_query: String::new(),
+            search_results: Vec::new(),
+            selected_search_idx: 0,
+            search_return_view: View::Countries,
+
+            favorites: Vec::new(),
+            selected_favorite_idx: 0,
+
+            history: Vec::new(),
+            selected_history_idx: 0,
+
+            selected_dns_idx: 0,
+
+            latencies: LatencyCache::new(),
+
             connection_status: ConnectionStatus::Disconnected,
 
+            tunnel: TunnelMonitor::default(),
+
+            watchdog: Watchdog::default(),
+
             autostart_server: None,
 
             message: None,
             error: None,
+            failure: None,
 
             private_key: None,
             address: None,
 
+            settings: Settings::default(),
+
+            dns_backend: DnsBackend::Auto,
+
+            runner: Arc::new(SystemRunner),
+
+            task: None,
+
+            monitor: None,
+
             should_quit: false,
         }
     }
 
-    /// Initialize the app - load cache and check status
+    /// Initialize the app - load cache and check status, then roll back what a
+    /// crashed mvtui left behind and bring the kill switch in line
     pub async fn init(&mut self) -> Result<()> {
+        self.load()?;
+
+        // Another mvtui is connecting or disconnecting: what it is doing is not leftover state
+        let _lock = match journal::try_lock() {
+            Ok(Some(lock)) => lock,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                self.fail(format!("Failed to repair leftover state: {}", e), e);
+                return Ok(());
+            }
+        };
+
+        // Roll back anything a crashed connect or disconnect left behind
+        self.reconcile();
+        self.connection_status = wireguard::get_status(self.runner.as_ref());
+
+        // Lockdown must hold from startup, and an existing tunnel keeps its kill switch
+        if self.settings.firewall.kill_switch != KillSwitch::Off {
+            if let Err(e) = self.sync_kill_switch() {
+                self.fail(format!("Failed to apply kill switch: {}", e), e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load settings, caches and the connection status without changing the system
+    pub fn load(&mut self) -> Result<()> {
+        // Load settings before grouping servers so the filter applies
+        match Settings::load() {
+            Ok(settings) => self.settings = settings,
+            Err(e) => self.error = Some(format!("Failed to load settings: {}", e)),
+        }
+
+        match favorites::load() {
+            Ok(favorites) => self.favorites = favorites,
+            Err(e) => self.error = Some(format!("Failed to load favorites: {}", e)),
+        }
+
+        match history::load() {
+            Ok(history) => self.history = history,
+            Err(e) => self.error = Some(format!("Failed to load history: {}", e)),
+        }
+
+        match latency::load() {
+            Ok(latencies) => self.latencies = latencies,
+            Err(e) => self.error = Some(format!("Failed to load latencies: {}", e)),
+        }
+
         // Load cached servers
         if let Ok(Some(cache)) = load_cache() {
             self.servers = cache.servers;
-            self.server_tree = group_servers(&self.servers);
-            self.countries = get_countries(&self.server_tree);
+            self.rebuild_tree();
         }
 
+        self.dns_backend = self.settings.dns.backend.detect();
+
         // Check connection status
-        self.connection_status = wireguard::get_status();
+        self.connection_status = wireguard::get_status(self.runner.as_ref());
 
         // Check which server is enabled for autostart
-        self.autostart_server = wireguard::get_enabled_server();
+        self.autostart_server = wireguard::get_enabled_server(self.runner.as_ref());
 
         // Try to find existing private key
         self.private_key = config::find_existing_private_key()?;
@@ -120,95 +268,528 @@ impl App {
         Ok(())
     }
 
-    /// Refresh servers from API
-    pub async fn refresh_servers(&mut self) -> Result<()> {
-        self.message = Some("Fetching servers...".to_string());
-        self.error = None;
+    /// Reconcile the system with the state journal and report what was repaired,
+    /// unless another mvtui is connecting or disconnecting right now
+    pub fn repair(&mut self) {
+        match journal::try_lock() {
+            Ok(Some(_lock)) => self.reconcile(),
+            Ok(None) => {
+                self.error = Some("Another mvtui is connecting or disconnecting, try again later".to_string());
+            }
+            Err(e) => self.fail(format!("Failed to repair leftover state: {}", e), e),
+        }
+    }
 
-        match api::fetch_servers().await {
-            Ok(servers) => {
-                self.servers = servers;
-                self.server_tree = group_servers(&self.servers);
-                self.countries = get_countries(&self.server_tree);
+    /// Roll back what the journal says a crashed mvtui left behind. Needs the journal lock.
+    fn reconcile(&mut self) {
+        match wireguard::reconcile(self.runner.as_ref()) {
+            Ok(actions) if !actions.is_empty() => {
+                self.message = Some(format!("Repaired: {}", actions.join(", ")));
+            }
+            Ok(_) => {}
+            Err(e) => self.fail(format!("Failed to repair leftover state: {}", e), e),
+        }
+
+        // DNS rules without a tunnel predating the journal still block all DNS
+        if wireguard::get_status(self.runner.as_ref()) == ConnectionStatus::Disconnected {
+            if let Err(e) = firewall::remove_dns_rules(self.runner.as_ref()) {
+                self.fail(format!("Failed to remove stale DNS rules: {}", e), e);
+            }
+        }
+    }
 
-                // Reset navigation
-                self.selected_country_idx = 0;
-                self.selected_city_idx = 0;
-                self.selected_server_idx = 0;
+    /// Refresh servers from API in the background
+    pub fn refresh_servers(&mut self) {
+        if self.busy() {
+            return;
+        }
+
+        let url = self.api_url();
+        self.start(Task::spawn("Fetching servers...", |_| async move {
+            Outcome::Servers(api::fetch_servers(&url).await)
+        }));
+    }
+
+    fn finish_refresh(&mut self, result: std::result::Result<Vec<Server>, ConnectError>) {
+        match result {
+            Ok(servers) => {
+                self.set_servers(servers);
 
                 // Save cache
-                save_cache(&self.servers)?;
+                if let Err(e) = save_cache(&self.servers) {
+                    self.error = Some(format!("Failed to save server cache: {}", e));
+                    return;
+                }
 
                 self.message = Some(format!("Loaded {} servers", self.servers.len()));
             }
             Err(e) => {
-                self.error = Some(format!("Failed to fetch servers: {}", e));
+                self.fail(format!("Failed to fetch servers: {}", e), e);
             }
         }
+    }
+
+    /// Run a task in the background, replacing the message with its progress
+    fn start(&mut self, task: Task<Outcome>) {
+        self.message = None;
+        self.error = None;
+        self.task = Some(task);
+    }
+
+    /// Whether a task is running. Refuses the action with a message if so,
+    /// since the tunnel and firewall are in flux until it is done.
+    fn busy(&mut self) -> bool {
+        let Some(task) = &self.task else {
+            return false;
+        };
+        self.error = Some(format!("Busy: {}", task.status()));
+        true
+    }
+
+    /// Apply the result of the running task if it is done
+    pub fn poll_task(&mut self) {
+        let Some(task) = self.task.as_mut() else {
+            return;
+        };
+        if let Some(result) = task.poll() {
+            self.task = None;
+            self.finish_task(result);
+        }
+    }
 
-        Ok(())
+    /// Apply the stats sample or probe result once it arrived
+    fn poll_monitor(&mut self) {
+        let Some(monitor) = self.monitor.as_mut() else {
+            return;
+        };
+        if let Some(result) = monitor.poll() {
+            self.monitor = None;
+            self.finish_task(result);
+        }
     }
 
-    /// Update connection status
-    pub fn update_status(&mut self) {
-        self.connection_status = wireguard::get_status();
+    /// Stop the running task. Fetches stop right away; a connect first rolls
+    /// back what it did and is recorded when that is done.
+    pub fn cancel_task(&mut self) {
+        let Some(task) = self.task.as_mut() else {
+            return;
+        };
+        if task.cancel() {
+            self.task = None;
+            self.fail("Cancelled".to_string(), ConnectError::Cancelled);
+        }
     }
 
-    /// Navigate to next item in current list
-    pub fn next(&mut self) {
-        match self.view {
-            View::Countries => {
-                if !self.countries.is_empty() {
-                    self.selected_country_idx =
-                        (self.selected_country_idx + 1) % self.countries.len();
-                }
+    /// Wait for the running task and apply its result
+    pub async fn wait(&mut self) {
+        if let Some(task) = self.task.take() {
+            let result = task.wait().await;
+            self.finish_task(result);
+        }
+    }
+
+    fn finish_task(&mut self, result: Result<Outcome>) {
+        match result {
+            Ok(Outcome::Servers(result)) => self.finish_refresh(result),
+            Ok(Outcome::Connect(report)) => self.finish_connect(report),
+            Ok(Outcome::Setup(result)) => self.finish_setup(result),
+            Ok(Outcome::Latency(servers, results)) => self.finish_latency(servers, results),
+            Ok(Outcome::Disconnect(code, result, kill_switch)) => {
+                self.finish_disconnect(code, result, kill_switch)
             }
-            View::Cities => {
-                if !self.cities.is_empty() {
-                    self.selected_city_idx = (self.selected_city_idx + 1) % self.cities.len();
+            Ok(Outcome::KillSwitch(mode, result)) => self.finish_kill_switch(mode, result),
+            Ok(Outcome::Resolver(resolver, result)) => self.finish_resolver(resolver, result),
+            Ok(Outcome::Autostart(code, enable, result)) => self.finish_autostart(code, enable, result),
+            Ok(Outcome::Stats(code, sample)) => {
+                // Samples of a tunnel that is gone by now are no use
+                if self.connection_status == ConnectionStatus::Connected(code) {
+                    self.tunnel.update(sample);
                 }
             }
-            View::Servers => {
-                if !self.city_servers.is_empty() {
-                    self.selected_server_idx =
-                        (self.selected_server_idx + 1) % self.city_servers.len();
-                }
+            Ok(Outcome::Probe(code, stale, alive)) => self.finish_probe(code, stale, alive),
+            Ok(Outcome::Status(status)) => self.connection_status = status,
+            Ok(Outcome::Module(result)) => self.finish_load_module(result),
+            Ok(Outcome::RemoveInterface(name, result, status)) => {
+                self.finish_remove_interface(name, result, status)
             }
-            View::Setup => {}
+            Err(e) => self.error = Some(e.to_string()),
         }
     }
 
-    /// Navigate to previous item in current list
-    pub fn previous(&mut self) {
-        match self.view {
-            View::Countries => {
-                if !self.countries.is_empty() {
-                    self.selected_country_idx = if self.selected_country_idx == 0 {
-                        self.countries.len() - 1
-                    } else {
-                        self.selected_country_idx - 1
-                    };
-                }
+    /// Replace the server list and start navigating it from the top
+    pub fn set_servers(&mut self, servers: Vec<Server>) {
+        self.servers = servers;
+        self.rebuild_tree();
+
+        self.selected_country_idx = 0;
+        self.selected_city_idx = 0;
+        self.selected_server_idx = 0;
+    }
+
+    /// Address of the Mullvad API
+    fn api_url(&self) -> String {
+        api::base_url(self.settings.api_url.as_deref())
+    }
+
+    /// Regroup servers after the server list or the filter changed
+    fn rebuild_tree(&mut self) {
+        let filtered: Vec<Server> = self
+            .servers
+            .iter()
+            .filter(|s| self.settings.filter.matches(s))
+            .cloned()
+            .collect();
+        self.server_tree = group_servers(&filtered);
+        self.countries = get_countries(&self.server_tree);
+    }
+
+    /// Cycle the ownership filter (any -> owned -> rented)
+    pub fn cycle_ownership_filter(&mut self) {
+        self.settings.filter.ownership = self.settings.filter.ownership.next();
+        self.apply_filter();
+    }
+
+    /// Cycle the provider filter through the hosting providers of the relays
+    /// the ownership filter shows
+    pub fn cycle_provider_filter(&mut self) {
+        let filter = &self.settings.filter;
+        let providers = get_providers(self.servers.iter().filter(|s| filter.matches_ownership(s)));
+        self.settings.filter.provider = match &self.settings.filter.provider {
+            None => providers.first().cloned(),
+            Some(current) => providers
+                .iter()
+                .position(|p| p == current)
+                .and_then(|i| providers.get(i + 1))
+                .cloned(),
+        };
+        self.apply_filter();
+    }
+
+    /// Regroup with the new filter, go back to the country list and persist it
+    fn apply_filter(&mut self) {
+        self.rebuild_tree();
+
+        // The selected country or city may no longer contain matching servers
+        self.view = View::Countries;
+        self.selected_country = None;
+        self.selected_city = None;
+        self.selected_country_idx = 0;
+        self.selected_city_idx = 0;
+        self.selected_server_idx = 0;
+
+        if let Err(e) = self.settings.save() {
+            self.error = Some(format!("Failed to save settings: {}", e));
+            return;
+        }
+
+        self.error = None;
+        self.message = Some(if self.settings.filter.is_active() {
+            format!("Filter: {}", self.settings.filter.describe())
+        } else {
+            "Filter cleared".to_string()
+        });
+    }
+
+    /// Ping the servers in scope: the selected city, the selected country or everything
+    pub fn measure_latency(&mut self) {
+        if self.busy() {
+            return;
+        }
+
+        let servers: Vec<Server> = match self.view {
+            View::Servers => self.city_servers.clone(),
+            View::Cities => self
+                .selected_country
+                .as_ref()
+                .and_then(|country| self.server_tree.get(country))
+                .map(|cities| cities.values().flatten().cloned().collect())
+                .unwrap_or_default(),
+            View::Favorites => self.favorite_servers().into_iter().cloned().collect(),
+            _ => self
+                .server_tree
+                .values()
+                .flat_map(|cities| cities.values().flatten())
+                .cloned()
+                .collect(),
+        };
+        if servers.is_empty() {
+            return;
+        }
+
+        let runner = self.runner.clone();
+        let status = format!("Measuring latency to {} servers...", servers.len());
+        self.start(Task::spawn(status, |_| async move {
+            let results = latency::probe_all(runner, &servers).await;
+            Outcome::Latency(servers.len(), results)
+        }));
+    }
+
+    fn finish_latency(&mut self, count: usize, results: HashMap<String, Option<f64>>) {
+        let measured_at = unix_time();
+        let reachable = results.values().filter(|ms| ms.is_some()).count();
+        for (code, ms) in results {
+            self.latencies.insert(code, Latency { ms, measured_at });
+        }
+        self.sort_city_servers();
+
+        match latency::save(&self.latencies) {
+            Ok(()) => {
+                self.message = Some(format!(
+                    "Measured {} servers ({} reachable)",
+                    count, reachable
+                ));
+                self.error = None;
             }
-            View::Cities => {
-                if !self.cities.is_empty() {
-                    self.selected_city_idx = if self.selected_city_idx == 0 {
-                        self.cities.len() - 1
-                    } else {
-                        self.selected_city_idx - 1
-                    };
+            Err(e) => {
+                self.error = Some(format!("Failed to save latencies: {}", e));
+            }
+        }
+    }
+
+    /// Last measured round trip time to a server, if it answered
+    pub fn latency_ms(&self, code: &str) -> Option<f64> {
+        self.latencies.get(code).and_then(|l| l.ms)
+    }
+
+    /// Switch the Servers view between sorting by code and by latency
+    pub fn toggle_latency_sort(&mut self) {
+        self.settings.sort_by_latency = !self.settings.sort_by_latency;
+        self.sort_city_servers();
+
+        match self.settings.save() {
+            Ok(()) => {
+                self.message = Some(if self.settings.sort_by_latency {
+                    "Sorting servers by latency".to_string()
+                } else {
+                    "Sorting servers by name".to_string()
+                });
+                self.error = None;
+            }
+            Err(e) => {
+                self.error = Some(format!("Failed to save settings: {}", e));
+            }
+        }
+    }
+
+    /// Sort the current city's servers, keeping the cursor on the same server
+    fn sort_city_servers(&mut self) {
+        let selected = self
+            .city_servers
+            .get(self.selected_server_idx)
+            .map(|s| s.code.clone());
+
+        if self.settings.sort_by_latency {
+            // Unmeasured and unreachable servers go last
+            let latencies = &self.latencies;
+            let key = |s: &Server| latencies.get(&s.code).and_then(|l| l.ms).unwrap_or(f64::MAX);
+            self.city_servers
+                .sort_by(|a, b| key(a).total_cmp(&key(b)).then_with(|| a.code.cmp(&b.code)));
+        } else {
+            self.city_servers.sort_by(|a, b| a.code.cmp(&b.code));
+        }
+
+        if let Some(code) = selected {
+            if let Some(idx) = self.city_servers.iter().position(|s| s.code == code) {
+                self.selected_server_idx = idx;
+            }
+        }
+    }
+
+    /// Check the connection status in the background
+    pub fn update_status(&mut self) {
+        if self.busy() {
+            return;
+        }
+
+        let runner = self.runner.clone();
+        self.start(Task::spawn_blocking("Checking status...", move |_| {
+            Outcome::Status(wireguard::get_status(runner.as_ref()))
+        }));
+    }
+
+    /// Periodic work, run on every pass of the event loop. Anything that
+    /// runs a program is started in the background and applied when done.
+    pub fn tick(&mut self) {
+        self.poll_task();
+        self.poll_monitor();
+
+        // The connection is in flux until the task is done,
+        // and there is no point in checking it twice at once
+        if self.task.is_some() || self.monitor.is_some() {
+            return;
+        }
+
+        if self.tunnel.due() {
+            match &self.connection_status {
+                ConnectionStatus::Connected(code) => {
+                    let runner = self.runner.clone();
+                    let code = code.clone();
+                    self.monitor = Some(Task::spawn_blocking("Reading tunnel stats...", move |_| {
+                        let sample = wireguard::get_stats(runner.as_ref(), &code);
+                        Outcome::Stats(code, sample)
+                    }));
+                    return;
                 }
+                ConnectionStatus::Disconnected => self.tunnel.reset(),
             }
-            View::Servers => {
-                if !self.city_servers.is_empty() {
-                    self.selected_server_idx = if self.selected_server_idx == 0 {
-                        self.city_servers.len() - 1
-                    } else {
-                        self.selected_server_idx - 1
-                    };
+        }
+
+        if self.settings.watchdog.enabled && self.watchdog.check_due() {
+            self.check_tunnel();
+        }
+    }
+
+    /// Watchdog check: probe the tunnel if its handshake is stale
+    fn check_tunnel(&mut self) {
+        if self.watchdog.incident.as_ref().is_some_and(|i| i.step.is_none()) {
+            return;
+        }
+
+        let code = match self.connection_status.clone() {
+            ConnectionStatus::Connected(code) => code,
+            // The last recovery attempt failed to connect; try the next step
+            ConnectionStatus::Disconnected if self.watchdog.incident.is_some() => {
+                return self.recover();
+            }
+            ConnectionStatus::Disconnected => return,
+        };
+
+        // While recovering, make sure the new tunnel really works
+        let stale = self.stale_handshake(&code);
+        if stale.is_none() && self.watchdog.incident.is_none() {
+            return;
+        }
+        let runner = self.runner.clone();
+        self.monitor = Some(Task::spawn("Probing tunnel...", |_| async move {
+            let alive = latency::probe(runner, watchdog::PROBE_ADDR).await.is_some();
+            Outcome::Probe(code, stale, alive)
+        }));
+    }
+
+    /// Recover the tunnel if the probe found it dead
+    fn finish_probe(&mut self, code: String, stale: Option<u64>, alive: bool) {
+        // Connected elsewhere or disconnected while probing
+        if self.task.is_some() || self.connection_status != ConnectionStatus::Connected(code.clone()) {
+            return;
+        }
+        if alive {
+            if self.watchdog.incident.take().is_some() {
+                self.watchdog_log(format!("Connection restored via {}", code));
+            }
+            return;
+        }
+
+        if self.watchdog.incident.is_none() {
+            let handshake = match stale {
+                Some(secs) => format!("no handshake for {}s", secs),
+                None => "handshake unknown".to_string(),
+            };
+            self.watchdog_log(format!("{} looks dead: {} and tunnel probe failed", code, handshake));
+
+            let server = self.servers.iter().find(|s| s.code == code);
+            self.watchdog.incident = Some(Incident {
+                code: code.clone(),
+                country: server.map(|s| s.country.clone()).unwrap_or_default(),
+                city: server.map(|s| s.city.clone()).unwrap_or_default(),
+                step: Some(Step::SameRelay),
+                tried: Vec::new(),
+            });
+        }
+        self.recover();
+    }
+
+    /// Seconds since the last handshake if older than the watchdog threshold.
+    /// Without any handshake the session age counts instead.
+    fn stale_handshake(&self, code: &str) -> Option<u64> {
+        let now = unix_time();
+        let since = self
+            .tunnel
+            .current
+            .as_ref()
+            .and_then(|stats| stats.latest_handshake)
+            .or_else(|| history::connected_since(&self.history, code))?;
+        let age = now.saturating_sub(since);
+        (age > self.settings.watchdog.handshake_timeout_secs).then_some(age)
+    }
+
+    /// Take recovery steps until one yields a server to reconnect to
+    fn recover(&mut self) {
+        while let Some(step) = self.watchdog.advance() {
+            let Some(incident) = self.watchdog.incident.clone() else {
+                return;
+            };
+
+            let target = match step {
+                Step::SameRelay => Some(incident.code.clone()),
+                Step::SameCity => self.replacement(&incident, true),
+                Step::SameCountry => self.replacement(&incident, false),
+                Step::GiveUp => {
+                    self.watchdog_log(format!(
+                        "Giving up on recovering {}. Reconnect manually.",
+                        incident.code
+                    ));
+                    return;
                 }
+            };
+
+            let Some(target) = target else {
+                continue;
+            };
+            if let Some(incident) = self.watchdog.incident.as_mut() {
+                incident.tried.push(target.clone());
+            }
+
+            // A failure is logged when the connect finishes
+            self.watchdog_log(format!("Reconnecting to {}", target));
+            self.connect(&target);
+            return;
+        }
+    }
+
+    /// Another server near the dead one, in the same city or the same country
+    fn replacement(&self, incident: &Incident, same_city: bool) -> Option<String> {
+        let mut exclude_servers = incident.tried.clone();
+        exclude_servers.push(incident.code.clone());
+        let constraints = Constraints {
+            country: Some(incident.country.clone()),
+            city: same_city.then(|| incident.city.clone()),
+            exclude_servers,
+            ..Default::default()
+        };
+
+        select::select_server(
+            &self.server_tree,
+            &constraints,
+            self.settings.strategy,
+            &self.latencies,
+            &self.history,
+        )
+        .map(|server| server.code.clone())
+    }
+
+    /// Add a line to the watchdog log and show it in the message bar
+    fn watchdog_log(&mut self, message: String) {
+        self.message = Some(format!("Watchdog: {}", message));
+        self.watchdog.record(unix_time(), message);
+    }
+
+    /// Navigate to next item in current list
+    pub fn next(&mut self) {
+        let len = self.current_list_len();
+        if let Some(idx) = self.current_selection_mut() {
+            if len > 0 {
+                *idx = (*idx + 1) % len;
+            }
+        }
+    }
+
+    /// Navigate to previous item in current list
+    pub fn previous(&mut self) {
+        let len = self.current_list_len();
+        if let Some(idx) = self.current_selection_mut() {
+            if len > 0 {
+                *idx = if *idx == 0 { len - 1 } else { *idx - 1 };
             }
-            View::Setup => {}
         }
     }
 
@@ -229,6 +810,7 @@ impl App {
                         self.selected_city = Some(city.clone());
                         self.city_servers = get_servers_in_city(&self.server_tree, country, city);
                         self.selected_server_idx = 0;
+                        self.sort_city_servers();
                         self.view = View::Servers;
                     }
                 }
@@ -239,7 +821,13 @@ impl App {
                     self.connect_to_server(&server.code.clone());
                 }
             }
-            View::Setup => {}
+            View::Search | View::Favorites | View::History => {
+                if let Some(server) = self.selected_server() {
+                    self.connect_to_server(&server.code.clone());
+                }
+            }
+            View::Dns => self.toggle_blocklist(),
+            View::Setup | View::Stats => {}
         }
     }
 
@@ -259,115 +847,526 @@ impl App {
                 self.view = View::Countries;
                 self.input_mode = InputMode::Normal;
             }
+            View::Search => {
+                self.exit_search();
+            }
+            View::Favorites | View::History | View::Stats | View::Dns => {
+                self.view = View::Countries;
+            }
         }
     }
 
     /// Connect to a server
     pub fn connect_to_server(&mut self, code: &str) {
-        // First disconnect if connected
-        if let ConnectionStatus::Connected(current) = &self.connection_status {
-            if let Err(e) = wireguard::disconnect(current) {
-                self.error = Some(format!("Failed to disconnect: {}", e));
-                return;
-            }
-        }
+        // Choosing a server by hand ends any recovery in progress
+        self.watchdog.incident = None;
+        self.connect(code);
+    }
 
-        // Check if config exists
-        if !config::config_exists(code) {
-            self.error = Some(format!("No config for {}. Press 'i' to initialize.", code));
+    /// Start connecting in the background; the attempt is recorded when it finishes
+    fn connect(&mut self, code: &str) {
+        if self.busy() {
             return;
         }
 
-        // Connect
-        match wireguard::connect(code) {
+        // Refuse relays marked inactive; their handshake never completes
+        if self.servers.iter().any(|s| s.code == code && !s.active) {
+            let error = ConnectError::Other(format!("{} is offline. Pick another server.", code));
+            return self.finish_connect(ConnectReport {
+                code: code.to_string(),
+                disconnected: None,
+                result: Err(error),
+            });
+        }
+
+        let job = ConnectJob {
+            runner: self.runner.clone(),
+            code: code.to_string(),
+            current: match &self.connection_status {
+                ConnectionStatus::Connected(current) => Some(current.clone()),
+                ConnectionStatus::Disconnected => None,
+            },
+            backend: self.settings.backend,
+            firewall: self.settings.firewall.clone(),
+            dns: self.dns_backend,
+            resolver: self.settings.dns.resolver(),
+        };
+        let status = format!("Connecting to {}...", code);
+        self.start(Task::spawn_blocking(status, move |progress| {
+            Outcome::Connect(job.run(&progress))
+        }));
+    }
+
+    /// Record a finished connect attempt and the disconnect that preceded it
+    fn finish_connect(&mut self, report: ConnectReport) {
+        let ConnectReport {
+            code,
+            disconnected,
+            result,
+        } = report;
+
+        if let Some((previous, error)) = disconnected {
+            if error.is_none() {
+                self.connection_status = ConnectionStatus::Disconnected;
+            }
+            self.record_history(Action::Disconnect, &previous, error);
+        }
+
+        let error = match result {
             Ok(()) => {
-                self.connection_status = ConnectionStatus::Connected(code.to_string());
+                self.connection_status = ConnectionStatus::Connected(code.clone());
+                // Graphs and rates belong to the previous session
+                self.tunnel.reset();
                 self.message = Some(format!("Connected to {}", code));
                 self.error = None;
+                None
+            }
+            Err(ConnectError::Cancelled) => {
+                // Stopping a reconnect by hand also ends the recovery
+                self.watchdog.incident = None;
+                let message = format!("Cancelled connecting to {}", code);
+                self.fail(message, ConnectError::Cancelled);
+                Some(ConnectError::Cancelled.to_string())
             }
             Err(e) => {
-                self.error = Some(format!("Failed to connect: {}", e));
+                let error = e.to_string();
+                self.fail(format!("Failed to connect: {}", e), e);
+                // Let the watchdog log why its reconnect failed
+                if self.watchdog.incident.is_some() {
+                    let error = self.error.clone().unwrap_or_default();
+                    self.watchdog_log(error);
+                }
+                Some(error)
             }
-        }
+        };
+        self.record_history(Action::Connect, &code, error);
     }
 
-    /// Disconnect from current server
-    pub fn disconnect(&mut self) {
-        if let ConnectionStatus::Connected(code) = &self.connection_status.clone() {
-            match wireguard::disconnect(code) {
-                Ok(()) => {
-                    self.connection_status = ConnectionStatus::Disconnected;
-                    self.message = Some("Disconnected".to_string());
-                    self.error = None;
-                }
-                Err(e) => {
-                    self.error = Some(format!("Failed to disconnect: {}", e));
-                }
+    /// Connect to the best relay matching the constraints
+    pub fn connect_best(&mut self, constraints: &Constraints, strategy: Strategy) {
+        let best = select::select_server(
+            &self.server_tree,
+            constraints,
+            strategy,
+            &self.latencies,
+            &self.history,
+        );
+
+        match best {
+            Some(server) => {
+                let code = server.code.clone();
+                self.connect_to_server(&code);
+            }
+            None => {
+                self.error = Some("No server matches the constraints".to_string());
             }
         }
     }
 
-    /// Enter setup mode
-    pub fn enter_setup(&mut self) {
-        self.view = View::Setup;
-        self.input_mode = InputMode::AccountInput;
-        self.input_buffer.clear();
+    /// Connect to the best relay in the country or city under the cursor
+    pub fn connect_best_here(&mut self) {
+        let constraints = match self.view {
+            View::Countries => Constraints {
+                country: self.countries.get(self.selected_country_idx).cloned(),
+                ..Default::default()
+            },
+            View::Cities => Constraints {
+                country: self.selected_country.clone(),
+                city: self.cities.get(self.selected_city_idx).cloned(),
+                ..Default::default()
+            },
+            _ => return,
+        };
+
+        self.connect_best(&constraints, self.settings.strategy);
     }
 
-    /// Handle setup submission (account number entered)
-    pub async fn submit_setup(&mut self) -> Result<()> {
-        let account = self.input_buffer.trim().to_string();
-        if account.is_empty() {
-            self.error = Some("Account number cannot be empty".to_string());
-            return Ok(());
+    /// Disconnect from the current server in the background
+    pub fn disconnect(&mut self) {
+        if self.busy() {
+            return;
         }
+        self.watchdog.incident = None;
 
-        self.message = Some("Setting up...".to_string());
-        self.error = None;
+        let runner = self.runner.clone();
+        let current = match &self.connection_status {
+            ConnectionStatus::Connected(code) => Some(code.clone()),
+            ConnectionStatus::Disconnected => None,
+        };
+        let backend = self.settings.backend;
+        let firewall = self.settings.firewall.clone();
+        let status = match &current {
+            Some(code) => format!("Disconnecting from {}...", code),
+            None => "Disconnecting...".to_string(),
+        };
+        self.start(Task::spawn_blocking(status, move |_| {
+            let _lock = match journal::lock() {
+                Ok(lock) => lock,
+                Err(e) => return Outcome::Disconnect(current, Err(e), None),
+            };
+            let result = match &current {
+                Some(code) => wireguard::disconnect(runner.as_ref(), code, backend),
+                None => Ok(()),
+            };
+            // Lift the kill switch, or in lockdown mode keep blocking without a relay.
+            // This also releases a kill switch left behind by a failed connect.
+            let kill_switch = (result.is_ok() && firewall.kill_switch != KillSwitch::Off)
+                .then(|| sync_kill_switch(runner.as_ref(), &firewall, &ConnectionStatus::Disconnected));
+            Outcome::Disconnect(current, result, kill_switch)
+        }));
+    }
 
-        // Get or generate private key
-        let private_key = match &self.private_key {
-            Some(key) => {
-                self.message = Some("Using existing private key...".to_string());
-                key.clone()
+    /// Record a finished disconnect, and the kill switch update that followed it
+    fn finish_disconnect(
+        &mut self,
+        code: Option<String>,
+        result: std::result::Result<(), ConnectError>,
+        kill_switch: Option<std::result::Result<(), ConnectError>>,
+    ) {
+        if let Some(code) = code {
+            if result.is_ok() {
+                self.connection_status = ConnectionStatus::Disconnected;
+            }
+            let error = result.as_ref().err().map(|e| format!("Failed to disconnect: {}", e));
+            self.record_history(Action::Disconnect, &code, error);
+        }
+
+        match result {
+            Ok(()) => {
+                self.message = Some("Disconnected".to_string());
+                self.error = None;
+            }
+            Err(e) => {
+                self.fail(format!("Failed to disconnect: {}", e), e);
+                return;
+            }
+        }
+
+        if let Some(Err(e)) = kill_switch {
+            self.fail(format!("Failed to update kill switch: {}", e), e);
+        }
+    }
+
+    /// Cycle the kill switch mode (off -> on -> lockdown) and apply it in the background
+    pub fn cycle_kill_switch(&mut self) {
+        if self.busy() {
+            return;
+        }
+
+        let runner = self.runner.clone();
+        let mut firewall = self.settings.firewall.clone();
+        firewall.kill_switch = firewall.kill_switch.next();
+        let status = self.connection_status.clone();
+        self.start(Task::spawn_blocking("Updating kill switch...", move |_| {
+            let result = journal::lock().and_then(|_lock| sync_kill_switch(runner.as_ref(), &firewall, &status));
+            Outcome::KillSwitch(firewall.kill_switch, result)
+        }));
+    }
+
+    /// Keep the new kill switch mode if it could be applied
+    fn finish_kill_switch(&mut self, mode: KillSwitch, result: std::result::Result<(), ConnectError>) {
+        if let Err(e) = result {
+            self.fail(format!("Failed to update kill switch: {}", e), e);
+            return;
+        }
+        self.settings.firewall.kill_switch = mode;
+
+        match self.settings.save() {
+            Ok(()) => {
+                self.message = Some(format!("Kill switch: {}", describe_kill_switch(mode)));
+                self.error = None;
+            }
+            Err(e) => {
+                self.error = Some(format!("Failed to save settings: {}", e));
+            }
+        }
+    }
+
+    /// Bring the kill switch rules in line with the mode and connection state
+    fn sync_kill_switch(&self) -> std::result::Result<(), ConnectError> {
+        sync_kill_switch(self.runner.as_ref(), &self.settings.firewall, &self.connection_status)
+    }
+
+    /// Show an error together with its typed cause, so a remedy can be offered
+    fn fail(&mut self, message: String, failure: ConnectError) {
+        self.error = Some(message.clone());
+        self.failure = Some((message, failure));
+    }
+
+    /// Typed cause of the error currently shown, if it is still the one shown
+    pub fn failure(&self) -> Option<&ConnectError> {
+        match (&self.error, &self.failure) {
+            (Some(error), Some((message, failure))) if error == message => Some(failure),
+            _ => None,
+        }
+    }
+
+    /// Fix for the error currently shown, if one is known
+    pub fn remedy(&self) -> Option<Remedy> {
+        self.failure().and_then(ConnectError::remedy)
+    }
+
+    /// Take the typed cause of the error currently shown
+    pub fn take_failure(&mut self) -> Option<ConnectError> {
+        let failure = self.failure.take()?;
+        (self.error.as_ref() == Some(&failure.0)).then_some(failure.1)
+    }
+
+    /// Run the fix offered for the error currently shown
+    pub fn apply_remedy(&mut self) {
+        let Some(remedy) = self.remedy() else {
+            return;
+        };
+        if self.busy() {
+            return;
+        }
+
+        let runner = self.runner.clone();
+        match remedy {
+            Remedy::Setup => self.enter_setup(),
+            Remedy::LoadModule => {
+                self.start(Task::spawn_blocking("Loading wireguard module...", move |_| {
+                    Outcome::Module(wireguard::load_module(runner.as_ref()))
+                }));
+            }
+            Remedy::RemoveInterface(name) => {
+                let backend = self.settings.backend;
+                let status = format!("Removing interface {}...", name);
+                self.start(Task::spawn_blocking(status, move |_| {
+                    let result = journal::lock()
+                        .and_then(|_lock| wireguard::disconnect(runner.as_ref(), &name, backend));
+                    let status = wireguard::get_status(runner.as_ref());
+                    Outcome::RemoveInterface(name, result, status)
+                }));
+            }
+        }
+    }
+
+    fn finish_load_module(&mut self, result: std::result::Result<(), ConnectError>) {
+        match result {
+            Ok(()) => {
+                self.message = Some("Loaded wireguard module".to_string());
+                self.error = None;
+            }
+            Err(e) => self.fail(format!("Failed to load module: {}", e), e),
+        }
+    }
+
+    fn finish_remove_interface(
+        &mut self,
+        name: String,
+        result: std::result::Result<(), ConnectError>,
+        status: ConnectionStatus,
+    ) {
+        self.connection_status = status;
+        match result {
+            Ok(()) => {
+                self.message = Some(format!("Removed interface {}", name));
+                self.error = None;
+            }
+            Err(e) => self.fail(format!("Failed to remove interface: {}", e), e),
+        }
+    }
+
+    /// Reconnect to the server of the most recent successful connection
+    pub fn reconnect_last(&mut self) {
+        match history::last_connected(&self.history) {
+            Some(code) => {
+                let code = code.to_string();
+                self.connect_to_server(&code);
             }
             None => {
-                self.message = Some("Generating new private key...".to_string());
-                wireguard::generate_private_key()?
+                self.error = Some("No previous connection to reconnect to".to_string());
             }
+        }
+    }
+
+    /// Show the connection history
+    pub fn show_history(&mut self) {
+        self.view = View::History;
+        self.selected_history_idx = 0;
+    }
+
+    /// Show the throughput graphs of the current session
+    pub fn show_stats(&mut self) {
+        self.view = View::Stats;
+    }
+
+    /// Show the DNS content blocking settings
+    pub fn show_dns(&mut self) {
+        self.view = View::Dns;
+        self.selected_dns_idx = 0;
+    }
+
+    /// Block or unblock the category under the cursor, in the configs and the live tunnel
+    pub fn toggle_blocklist(&mut self) {
+        if self.busy() {
+            return;
+        }
+        let Some(list) = Blocklist::ALL.get(self.selected_dns_idx).copied() else {
+            return;
         };
+        self.settings.dns.toggle(list);
+        let resolver = self.settings.dns.resolver();
 
-        // Get public key
-        let public_key = wireguard::get_public_key(&private_key)?;
+        if let Err(e) = self.settings.save() {
+            self.error = Some(format!("Failed to save settings: {}", e));
+            return;
+        }
 
-        // Register with Mullvad
-        self.message = Some("Registering with Mullvad...".to_string());
-        let address = api::register_public_key(&account, &public_key).await?;
+        let runner = self.runner.clone();
+        let live = match &self.connection_status {
+            ConnectionStatus::Connected(code) => Some(code.clone()),
+            ConnectionStatus::Disconnected => None,
+        };
+        let dns = self.dns_backend;
+        self.start(Task::spawn_blocking("Switching resolver...", move |_| {
+            // wg-quick and autostart read the resolver from the config files
+            let result = config::set_dns(resolver)
+                .map(|_| ())
+                .map_err(|e| ConnectError::Other(format!("Failed to update configs: {}", e)))
+                // Point the live tunnel at the new resolver
+                .and_then(|()| match &live {
+                    Some(code) => dns.apply(runner.as_ref(), code, &[resolver]),
+                    None => Ok(()),
+                });
+            Outcome::Resolver(resolver, result)
+        }));
+    }
 
-        // Fetch servers if needed
-        if self.servers.is_empty() {
-            self.message = Some("Fetching servers...".to_string());
-            self.servers = api::fetch_servers().await?;
-            self.server_tree = group_servers(&self.servers);
-            self.countries = get_countries(&self.server_tree);
-            save_cache(&self.servers)?;
+    fn finish_resolver(&mut self, resolver: IpAddr, result: std::result::Result<(), ConnectError>) {
+        if let Err(e) = result {
+            self.fail(format!("Failed to switch resolver: {}", e), e);
+            return;
         }
 
-        // Generate all configs
-        self.message = Some("Generating config files...".to_string());
-        let count = config::generate_all_configs(&self.servers, &private_key, &address)?;
+        self.message = Some(format!(
+            "DNS: blocking {} ({})",
+            self.settings.dns.describe(),
+            resolver
+        ));
+        self.error = None;
+    }
+
+    /// History entry at a list position; the list shows the newest entry first
+    pub fn history_entry(&self, idx: usize) -> Option<&HistoryEntry> {
+        self.history.iter().rev().nth(idx)
+    }
+
+    fn record_history(&mut self, action: Action, code: &str, error: Option<String>) {
+        let timestamp = unix_time();
+        let duration = match action {
+            Action::Connect => None,
+            Action::Disconnect => history::connected_since(&self.history, code)
+                .map(|since| timestamp.saturating_sub(since)),
+        };
+
+        self.history.push(HistoryEntry {
+            timestamp,
+            action,
+            code: code.to_string(),
+            duration,
+            error,
+        });
+        let excess = self.history.len().saturating_sub(history::MAX_ENTRIES);
+        self.history.drain(..excess);
+
+        // Don't hide the outcome of the action itself behind this error
+        if let Err(e) = history::save(&self.history) {
+            if self.error.is_none() {
+                self.error = Some(format!("Failed to save history: {}", e));
+            }
+        }
+    }
+
+    /// Enter search mode, keeping the previous query
+    pub fn enter_search(&mut self) {
+        if self.view != View::Search {
+            self.search_return_view = self.view.clone();
+        }
+        self.view = View::Search;
+        self.input_mode = InputMode::Search;
+        self.update_search();
+    }
+
+    /// Leave search and return to the view it was started from
+    pub fn exit_search(&mut self) {
+        self.view = self.search_return_view.clone();
+        self.input_mode = InputMode::Normal;
+        self.search_query.clear();
+        self.search_results.clear();
+        self.selected_search_idx = 0;
+    }
 
-        self.private_key = Some(private_key);
-        self.address = Some(address);
+    /// Re-run the search after the query changed
+    pub fn update_search(&mut self) {
+        let servers = self.server_tree.values().flat_map(|cities| cities.values().flatten());
+        self.search_results = search::search(servers, &self.search_query);
+        self.selected_search_idx = 0;
+    }
+
+    /// Enter setup mode
+    pub fn enter_setup(&mut self) {
+        self.view = View::Setup;
+        self.input_mode = InputMode::AccountInput;
+        self.input_buffer.clear();
+    }
+
+    /// Handle setup submission (account number entered), running it in the background
+    pub fn submit_setup(&mut self) {
+        let account = self.input_buffer.trim().to_string();
+        if account.is_empty() {
+            self.error = Some("Account number cannot be empty".to_string());
+            return;
+        }
+        if self.busy() {
+            return;
+        }
+
+        let job = SetupJob {
+            runner: self.runner.clone(),
+            url: self.api_url(),
+            account,
+            private_key: self.private_key.clone(),
+            servers: self.servers.clone(),
+            resolver: self.settings.dns.resolver(),
+            ipv6: self.settings.ipv6_endpoints,
+        };
+        self.start(Task::spawn("Setting up...", |progress| async move {
+            Outcome::Setup(job.run(&progress).await)
+        }));
+    }
+
+    fn finish_setup(&mut self, result: Result<SetupReport>) {
+        let report = match result {
+            Ok(report) => report,
+            Err(e) => {
+                let message = format!("Setup failed: {}", e);
+                match e.downcast::<ConnectError>() {
+                    Ok(e) => self.fail(message, e),
+                    Err(_) => self.error = Some(message),
+                }
+                return;
+            }
+        };
+
+        self.private_key = Some(report.private_key);
+        self.address = Some(report.address);
+
+        if let Some(servers) = report.fetched {
+            self.servers = servers;
+            self.rebuild_tree();
+            if let Err(e) = save_cache(&self.servers) {
+                self.error = Some(format!("Failed to save server cache: {}", e));
+            }
+        }
 
         self.message = Some(format!(
             "Setup complete! Generated {} config files.",
-            count
+            report.count
         ));
         self.input_mode = InputMode::Normal;
         self.view = View::Countries;
-
-        Ok(())
     }
 
     /// Get current list length for display
@@ -376,7 +1375,11 @@ impl App {
             View::Countries => self.countries.len(),
             View::Cities => self.cities.len(),
             View::Servers => self.city_servers.len(),
-            View::Setup => 0,
+            View::Search => self.search_results.len(),
+            View::Favorites => self.favorite_servers().len(),
+            View::History => self.history.len(),
+            View::Dns => Blocklist::ALL.len(),
+            View::Setup | View::Stats => 0,
         }
     }
 
@@ -386,59 +1389,392 @@ impl App {
             View::Countries => self.selected_country_idx,
             View::Cities => self.selected_city_idx,
             View::Servers => self.selected_server_idx,
-            View::Setup => 0,
+            View::Search => self.selected_search_idx,
+            View::Favorites => self.selected_favorite_idx,
+            View::History => self.selected_history_idx,
+            View::Dns => self.selected_dns_idx,
+            View::Setup | View::Stats => 0,
+        }
+    }
+
+    fn current_selection_mut(&mut self) -> Option<&mut usize> {
+        match self.view {
+            View::Countries => Some(&mut self.selected_country_idx),
+            View::Cities => Some(&mut self.selected_city_idx),
+            View::Servers => Some(&mut self.selected_server_idx),
+            View::Search => Some(&mut self.selected_search_idx),
+            View::Favorites => Some(&mut self.selected_favorite_idx),
+            View::History => Some(&mut self.selected_history_idx),
+            View::Dns => Some(&mut self.selected_dns_idx),
+            View::Setup | View::Stats => None,
+        }
+    }
+
+    /// Server under the cursor in any of the server lists
+    pub fn selected_server(&self) -> Option<&Server> {
+        match self.view {
+            View::Servers => self.city_servers.get(self.selected_server_idx),
+            View::Search => self
+                .search_results
+                .get(self.selected_search_idx)
+                .map(|result| &result.server),
+            View::Favorites => self
+                .favorite_servers()
+                .into_iter()
+                .nth(self.selected_favorite_idx),
+            View::History => self
+                .history_entry(self.selected_history_idx)
+                .and_then(|entry| self.servers.iter().find(|s| s.code == entry.code)),
+            _ => None,
+        }
+    }
+
+    /// Favorite servers that are in the server list, in favorites order
+    pub fn favorite_servers(&self) -> Vec<&Server> {
+        self.favorites
+            .iter()
+            .filter_map(|code| self.servers.iter().find(|s| &s.code == code))
+            .collect()
+    }
+
+    pub fn is_favorite(&self, code: &str) -> bool {
+        self.favorites.iter().any(|c| c == code)
+    }
+
+    /// Show the favorites list
+    pub fn show_favorites(&mut self) {
+        self.view = View::Favorites;
+        self.selected_favorite_idx = 0;
+    }
+
+    /// Star or unstar the currently selected server
+    pub fn toggle_favorite(&mut self) {
+        if let Some(server) = self.selected_server() {
+            let code = server.code.clone();
+            let favorite = !self.is_favorite(&code);
+            self.set_favorite(&code, favorite);
+        }
+    }
+
+    /// Add or remove a server from favorites and persist the list
+    pub fn set_favorite(&mut self, code: &str, favorite: bool) {
+        if favorite == self.is_favorite(code) {
+            return;
+        }
+
+        if favorite {
+            self.favorites.push(code.to_string());
+        } else {
+            self.favorites.retain(|c| c != code);
+            // Keep the cursor on the list when removing the last entry
+            let len = self.favorite_servers().len();
+            if self.selected_favorite_idx >= len {
+                self.selected_favorite_idx = len.saturating_sub(1);
+            }
+        }
+
+        match favorites::save(&self.favorites) {
+            Ok(()) => {
+                self.message = Some(if favorite {
+                    format!("Added {} to favorites", code)
+                } else {
+                    format!("Removed {} from favorites", code)
+                });
+                self.error = None;
+            }
+            Err(e) => {
+                self.error = Some(format!("Failed to save favorites: {}", e));
+            }
         }
     }
 
-    /// Toggle autostart for the currently selected server
+    /// Toggle autostart for the currently selected server in the background
     pub fn toggle_autostart(&mut self) {
-        if self.view != View::Servers {
+        let Some(server) = self.selected_server() else {
+            return;
+        };
+        let code = server.code.clone();
+        if self.busy() {
             return;
         }
 
-        if let Some(server) = self.city_servers.get(self.selected_server_idx) {
-            let code = server.code.clone();
+        // Enabling disables any other server
+        let enable = self.autostart_server.as_ref() != Some(&code);
+        let runner = self.runner.clone();
+        let status = match enable {
+            true => format!("Enabling autostart for {}...", code),
+            false => format!("Disabling autostart for {}...", code),
+        };
+        self.start(Task::spawn_blocking(status, move |_| {
+            let result = match enable {
+                true => wireguard::enable_autostart(runner.as_ref(), &code),
+                false => wireguard::disable_autostart(runner.as_ref(), &code),
+            };
+            Outcome::Autostart(code, enable, result)
+        }));
+    }
 
-            // Check if this server is already enabled
-            let is_currently_enabled = self.autostart_server.as_ref() == Some(&code);
+    fn finish_autostart(&mut self, code: String, enable: bool, result: std::result::Result<(), ConnectError>) {
+        match (enable, result) {
+            (true, Ok(())) => {
+                self.message = Some(format!("Enabled autostart for {}", code));
+                self.error = None;
+                self.autostart_server = Some(code);
+            }
+            (false, Ok(())) => {
+                self.autostart_server = None;
+                self.message = Some(format!("Disabled autostart for {}", code));
+                self.error = None;
+            }
+            (true, Err(e)) => self.fail(format!("Failed to enable autostart: {}", e), e),
+            (false, Err(e)) => self.fail(format!("Failed to disable autostart: {}", e), e),
+        }
+    }
+}
 
-            if is_currently_enabled {
-                // Disable it
-                match wireguard::disable_autostart(&code) {
-                    Ok(()) => {
-                        self.autostart_server = None;
-                        self.message = Some(format!("Disabled autostart for {}", code));
-                        self.error = None;
-                    }
-                    Err(e) => {
-                        self.error = Some(format!("Failed to disable autostart: {}", e));
-                    }
-                }
-            } else {
-                // Enable it (will disable any other)
-                match wireguard::enable_autostart(&code) {
-                    Ok(()) => {
-                        self.autostart_server = Some(code.clone());
-                        self.message = Some(format!("Enabled autostart for {}", code));
-                        self.error = None;
-                    }
-                    Err(e) => {
-                        self.error = Some(format!("Failed to enable autostart: {}", e));
-                    }
-                }
+/// Result of a background task
+pub enum Outcome {
+    Servers(std::result::Result<Vec<Server>, ConnectError>),
+    Connect(ConnectReport),
+    Setup(Result<SetupReport>),
+    /// How many servers were probed, and their round trip times
+    Latency(usize, HashMap<String, Option<f64>>),
+    /// Server disconnected from, if any, and how updating the kill switch went afterwards
+    Disconnect(
+        Option<String>,
+        std::result::Result<(), ConnectError>,
+        Option<std::result::Result<(), ConnectError>>,
+    ),
+    /// The new kill switch mode
+    KillSwitch(KillSwitch, std::result::Result<(), ConnectError>),
+    /// Resolver the live tunnel was switched to
+    Resolver(IpAddr, std::result::Result<(), ConnectError>),
+    /// Server, and whether autostart was enabled or disabled for it
+    Autostart(String, bool, std::result::Result<(), ConnectError>),
+    /// A stats sample of a tunnel
+    Stats(String, Option<TunnelStats>),
+    /// Watchdog probe of a tunnel: how stale its handshake was and whether it answered
+    Probe(String, Option<u64>, bool),
+    /// Connection status checked on request
+    Status(ConnectionStatus),
+    /// Whether loading the wireguard module worked
+    Module(std::result::Result<(), ConnectError>),
+    /// Leftover interface taken down, and the connection status afterwards
+    RemoveInterface(String, std::result::Result<(), ConnectError>, ConnectionStatus),
+}
+
+/// What a connect did, recorded in the history once it finished
+pub struct ConnectReport {
+    code: String,
+    /// Server disconnected from first, and the error if that failed
+    disconnected: Option<(String, Option<String>)>,
+    result: std::result::Result<(), ConnectError>,
+}
+
+/// Everything a connect needs, taken from the app so it can run off the event loop
+struct ConnectJob {
+    runner: Arc<dyn CommandRunner>,
+    code: String,
+    /// Server connected to now
+    current: Option<String>,
+    /// Brings the new tunnel up; tunnels are taken down with the backend the journal recorded
+    backend: Backend,
+    firewall: FirewallSettings,
+    dns: DnsBackend,
+    resolver: IpAddr,
+}
+
+impl ConnectJob {
+    fn run(self, progress: &Progress<Outcome>) -> ConnectReport {
+        let mut disconnected = None;
+        let result = journal::lock().and_then(|_lock| match self.connect(progress, &mut disconnected) {
+            // Cancelled while the tunnel was coming up
+            Ok(()) if progress.cancelled() => self.roll_back(progress, true),
+            Err(ConnectError::Cancelled) => self.roll_back(progress, false),
+            result => result,
+        });
+        ConnectReport {
+            code: self.code,
+            disconnected,
+            result,
+        }
+    }
+
+    fn connect(
+        &self,
+        progress: &Progress<Outcome>,
+        disconnected: &mut Option<(String, Option<String>)>,
+    ) -> std::result::Result<(), ConnectError> {
+        // Steps can't be interrupted, so cancelling takes effect between them
+        let check = || match progress.cancelled() {
+            true => Err(ConnectError::Cancelled),
+            false => Ok(()),
+        };
+
+        // First disconnect if connected
+        if let Some(current) = &self.current {
+            progress.report(format!("Disconnecting from {}...", current));
+            let result = wireguard::disconnect(self.runner.as_ref(), current, self.backend);
+            let error = result.as_ref().err().map(|e| format!("Failed to disconnect: {}", e));
+            *disconnected = Some((current.clone(), error));
+            result?;
+        }
+        check()?;
+
+        // Check if config exists
+        if !config::config_exists(&self.code) {
+            return Err(ConnectError::MissingConfig(self.code.clone()));
+        }
+
+        // Block everything but the new relay before the tunnel comes up
+        if self.firewall.kill_switch != KillSwitch::Off {
+            progress.report("Applying kill switch...");
+            let tunnel = tunnel_for(&self.code)?;
+            firewall::apply_kill_switch(self.runner.as_ref(), Some(&tunnel), &self.firewall)?;
+        }
+        check()?;
+
+        // Connect
+        progress.report(format!("Bringing up {}...", self.code));
+        wireguard::connect(
+            self.runner.as_ref(),
+            &self.code,
+            self.backend,
+            self.dns,
+            self.resolver,
+        )
+    }
+
+    /// Undo a cancelled connect: take the tunnel down if it came up, which also
+    /// reverts its DNS settings, and leave the kill switch as it is when disconnected.
+    /// Returns `Cancelled` once everything is undone.
+    fn roll_back(&self, progress: &Progress<Outcome>, up: bool) -> std::result::Result<(), ConnectError> {
+        progress.report(format!("Rolling back {}...", self.code));
+        if up {
+            wireguard::disconnect(self.runner.as_ref(), &self.code, self.backend)?;
+        }
+
+        match self.firewall.kill_switch {
+            KillSwitch::Off => {}
+            KillSwitch::On => firewall::remove_kill_switch(self.runner.as_ref())?,
+            KillSwitch::Lockdown => {
+                firewall::apply_kill_switch(self.runner.as_ref(), None, &self.firewall)?
             }
         }
+        Err(ConnectError::Cancelled)
+    }
+}
+
+/// What setup produced, applied to the app once it finished
+pub struct SetupReport {
+    private_key: String,
+    address: String,
+    /// Server list, if it had to be fetched
+    fetched: Option<Vec<Server>>,
+    count: usize,
+}
+
+/// Everything setup needs, taken from the app so it can run off the event loop
+struct SetupJob {
+    runner: Arc<dyn CommandRunner>,
+    url: String,
+    account: String,
+    private_key: Option<String>,
+    servers: Vec<Server>,
+    resolver: IpAddr,
+    /// Generate configs with IPv6 endpoints
+    ipv6: bool,
+}
+
+impl SetupJob {
+    async fn run(self, progress: &Progress<Outcome>) -> Result<SetupReport> {
+        // Get or generate private key
+        let private_key = match self.private_key {
+            Some(key) => {
+                progress.report("Using existing private key...");
+                key
+            }
+            None => {
+                progress.report("Generating new private key...");
+                wireguard::generate_private_key(self.runner.as_ref())?
+            }
+        };
+
+        // Get public key
+        let public_key = wireguard::get_public_key(self.runner.as_ref(), &private_key)?;
+
+        // Register with Mullvad
+        progress.report("Registering with Mullvad...");
+        let address = api::register_public_key(&self.url, &self.account, &public_key).await?;
+
+        // Fetch servers if needed
+        let fetched = if self.servers.is_empty() {
+            progress.report("Fetching servers...");
+            Some(api::fetch_servers(&self.url).await?)
+        } else {
+            None
+        };
+        let servers = fetched.as_deref().unwrap_or(&self.servers);
+
+        // Generate all configs
+        progress.report("Generating config files...");
+        let count = config::generate_all_configs(servers, &private_key, &address, self.resolver, self.ipv6)?;
+
+        Ok(SetupReport {
+            private_key,
+            address,
+            fetched,
+            count,
+        })
+    }
+}
+
+/// Bring the kill switch rules in line with the mode and connection state
+fn sync_kill_switch(
+    runner: &dyn CommandRunner,
+    settings: &FirewallSettings,
+    status: &ConnectionStatus,
+) -> std::result::Result<(), ConnectError> {
+    match (settings.kill_switch, status) {
+        (KillSwitch::Off, _) | (KillSwitch::On, ConnectionStatus::Disconnected) => {
+            firewall::remove_kill_switch(runner)
+        }
+        (_, ConnectionStatus::Connected(code)) => {
+            firewall::apply_kill_switch(runner, Some(&tunnel_for(code)?), settings)
+        }
+        (KillSwitch::Lockdown, ConnectionStatus::Disconnected) => {
+            firewall::apply_kill_switch(runner, None, settings)
+        }
+    }
+}
+
+/// Kill switch exception for a server's tunnel, using the endpoint from its config
+fn tunnel_for(code: &str) -> std::result::Result<Tunnel, ConnectError> {
+    let config = config::read_config(code)?;
+    let endpoint = config
+        .endpoint
+        .parse()
+        .map_err(|_| ConnectError::Firewall(format!("Invalid endpoint in config: {}", config.endpoint)))?;
+    Ok(Tunnel {
+        interface: code.to_string(),
+        endpoint,
+    })
+}
+
+/// Kill switch mode as shown to the user
+pub fn describe_kill_switch(mode: KillSwitch) -> &'static str {
+    match mode {
+        KillSwitch::Off => "off",
+        KillSwitch::On => "on",
+        KillSwitch::Lockdown => "lockdown (blocks even when disconnected)",
     }
 }
 
 fn cache_path() -> PathBuf {
-    dirs::cache_dir()
-        .unwrap_or_else(|| PathBuf::from("/tmp"))
-        .join("mullvadtui")
-        .join("servers.json")
+    settings::data_dir().join("servers.json")
 }
 
-fn load_cache() -> Result<Option<ServerCache>> {
+pub fn load_cache() -> Result<Option<ServerCache>> {
     let path = cache_path();
     if !path.exists() {
         return Ok(None);
@@ -457,10 +1793,7 @@ fn save_cache(servers: &[Server]) -> Result<()> {
 
     let cache = ServerCache {
         servers: servers.to_vec(),
-        timestamp: SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
+        timestamp: unix_time(),
     };
 
     let content = serde_json::to_string_pretty(&cache)?;
@@ -468,3 +1801,525 @@ fn save_cache(servers: &[Server]) -> Result<()> {
 
     Ok(())
 }
+
+/// Current time in seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ConnectError;
+    use crate::runner::FakeRunner;
+    use crate::server::Ownership;
+    use crate::testing::{sample_app, MockApi};
+
+    const PRIVATE_KEY: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const PUBLIC_KEY: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
+    /// App that generates keys with a fake `wg` and registers them with the stand-in
+    fn setup_app(api: &MockApi, runner: Arc<FakeRunner>, account: &str) -> App {
+        runner
+            .respond("wg genkey", 0, &format!("{}\n", PRIVATE_KEY), "")
+            .respond("wg pubkey", 0, &format!("{}\n", PUBLIC_KEY), "");
+
+        let mut app = App::new();
+        app.runner = runner;
+        app.settings.api_url = Some(api.url());
+        app.enter_setup();
+        app.input_buffer = account.to_string();
+        app
+    }
+
+    #[tokio::test]
+    async fn submit_setup_registers_key_and_generates_configs() {
+        let api = MockApi::start().await;
+        api.respond_wg(200, "10.68.12.34/32,fc00:bbbb:bbbb:bb01::5:c21/128");
+        let runner = Arc::new(FakeRunner::new());
+        let mut app = setup_app(&api, runner.clone(), "1234567890123456");
+
+        app.submit_setup();
+        app.wait().await;
+
+        assert_eq!(runner.calls(), ["wg genkey", "wg pubkey"]);
+        assert_eq!(app.servers.len(), 3);
+        assert_eq!(app.private_key.as_deref(), Some(PRIVATE_KEY));
+        assert_eq!(app.message.as_deref(), Some("Setup complete! Generated 3 config files."));
+        assert_eq!(app.view, View::Countries);
+
+        let content = fs::read_to_string(config::config_path("ch-zrh-wg-001")).unwrap();
+        assert!(content.contains(&format!("PrivateKey = {}", PRIVATE_KEY)));
+        assert!(content.contains("Address = 10.68.12.34/32,fc00:bbbb:bbbb:bb01::5:c21/128"));
+        assert!(content.contains("DNS = 10.64.0.1"));
+        assert!(content.contains("Endpoint = 193.32.127.66:51820"));
+
+        let paths: Vec<String> = api.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths, ["/wg", "/public/relays/wireguard/v1/"]);
+    }
+
+    #[tokio::test]
+    async fn submit_setup_stops_on_invalid_account() {
+        let api = MockApi::start().await;
+        api.respond_wg(400, "Invalid account");
+        let mut app = setup_app(&api, Arc::new(FakeRunner::new()), "0000000000000000");
+
+        app.submit_setup();
+        app.wait().await;
+
+        assert_eq!(app.error.as_deref(), Some("Setup failed: Mullvad API error: Invalid account"));
+        assert!(matches!(
+            app.failure(),
+            Some(ConnectError::Api(msg)) if msg == "Invalid account"
+        ));
+        assert_eq!(app.private_key, None);
+        assert_eq!(app.view, View::Setup);
+        // Nothing is fetched once registration failed
+        assert_eq!(api.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn submit_setup_rejects_empty_account() {
+        let api = MockApi::start().await;
+        let runner = Arc::new(FakeRunner::new());
+        let mut app = setup_app(&api, runner.clone(), "   ");
+
+        app.submit_setup();
+
+        assert!(app.task.is_none());
+        assert_eq!(app.error.as_deref(), Some("Account number cannot be empty"));
+        assert!(runner.calls().is_empty());
+        assert!(api.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn refresh_runs_in_the_background() {
+        let api = MockApi::start().await;
+        let mut app = App::new();
+        app.settings.api_url = Some(api.url());
+
+        app.refresh_servers();
+        assert!(app.servers.is_empty());
+        assert_eq!(app.task.as_ref().map(|t| t.status()), Some("Fetching servers..."));
+
+        app.wait().await;
+        assert!(app.task.is_none());
+        assert_eq!(app.servers.len(), 3);
+        assert_eq!(app.message.as_deref(), Some("Loaded 3 servers"));
+    }
+
+    #[tokio::test]
+    async fn connect_is_recorded_when_it_finishes() {
+        let mut app = sample_app();
+
+        app.connect_to_server("de-fra-wg-002");
+        assert_eq!(app.task.as_ref().map(|t| t.status()), Some("Connecting to de-fra-wg-002..."));
+
+        app.wait().await;
+        assert!(matches!(app.failure(), Some(ConnectError::MissingConfig(code)) if code == "de-fra-wg-002"));
+        let entry = app.history.last().unwrap();
+        assert_eq!((entry.action, entry.code.as_str()), (Action::Connect, "de-fra-wg-002"));
+        assert!(entry.error.is_some());
+    }
+
+    #[test]
+    fn provider_filter_cycles_through_providers_the_ownership_filter_shows() {
+        let cases = [
+            (Ownership::Any, vec!["31173", "M247", "xtom"]),
+            (Ownership::Owned, vec!["31173"]),
+            (Ownership::Rented, vec!["M247", "xtom"]),
+        ];
+
+        for (ownership, expected) in cases {
+            let mut app = sample_app();
+            app.settings.filter.ownership = ownership;
+
+            let mut providers = Vec::new();
+            app.cycle_provider_filter();
+            while let Some(provider) = app.settings.filter.provider.clone() {
+                providers.push(provider);
+                app.cycle_provider_filter();
+            }
+
+            assert_eq!(providers, expected, "{:?}", ownership);
+        }
+    }
+
+    #[test]
+    fn repair_leaves_connect_in_flight_alone() {
+        let _journal = crate::testing::lock_journal();
+        let in_flight = journal::Journal {
+            in_progress: Some(journal::Operation::Connect),
+            applied: vec![journal::Applied::KillSwitch { mode: KillSwitch::On }],
+        };
+        journal::save(&in_flight).unwrap();
+        let runner = Arc::new(FakeRunner::new());
+        let mut app = sample_app();
+        app.runner = runner.clone();
+
+        // Held by the mvtui that is connecting
+        let lock = journal::lock().unwrap();
+        app.repair();
+
+        assert!(runner.calls().is_empty());
+        assert_eq!(journal::load().unwrap().in_progress, Some(journal::Operation::Connect));
+        assert!(app.error.as_deref().unwrap().starts_with("Another mvtui is connecting"));
+
+        // Once it died, the same journal is leftover state
+        drop(lock);
+        app.repair();
+
+        assert_eq!(runner.calls()[0], "nft -f -");
+        assert!(journal::load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_is_capped_in_memory_too() {
+        let mut app = sample_app();
+        app.history = (0..history::MAX_ENTRIES as u64)
+            .map(|timestamp| HistoryEntry {
+                timestamp,
+                action: Action::Connect,
+                code: "de-fra-wg-001".to_string(),
+                duration: None,
+                error: None,
+            })
+            .collect();
+
+        app.record_history(Action::Connect, "de-fra-wg-002", None);
+
+        assert_eq!(app.history.len(), history::MAX_ENTRIES);
+        assert_eq!(app.history[0].timestamp, 1);
+        assert_eq!(app.history.last().unwrap().code, "de-fra-wg-002");
+    }
+
+    #[tokio::test]
+    async fn offline_server_is_refused_without_a_task() {
+        let mut app = sample_app();
+
+        app.connect_to_server("de-fra-wg-003");
+
+        assert!(app.task.is_none());
+        assert_eq!(
+            app.error.as_deref(),
+            Some("Failed to connect: de-fra-wg-003 is offline. Pick another server.")
+        );
+    }
+
+    #[tokio::test]
+    async fn actions_wait_for_the_running_task() {
+        let mut app = sample_app();
+        app.connect_to_server("de-fra-wg-002");
+
+        app.refresh_servers();
+        app.disconnect();
+
+        assert_eq!(app.error.as_deref(), Some("Busy: Connecting to de-fra-wg-002..."));
+        app.wait().await;
+        assert!(app.task.is_none());
+    }
+
+    #[tokio::test]
+    async fn cancelled_connect_stops_before_the_next_step() {
+        let runner = Arc::new(FakeRunner::new());
+        let mut app = sample_app();
+        app.runner = runner.clone();
+        app.settings.backend = Backend::WgQuick;
+
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let job = ConnectJob {
+            runner: app.runner.clone(),
+            code: "de-fra-wg-002".to_string(),
+            current: None,
+            backend: app.settings.backend,
+            firewall: app.settings.firewall.clone(),
+            dns: app.dns_backend,
+            resolver: app.settings.dns.resolver(),
+        };
+        let mut task = Task::spawn_blocking("Connecting to de-fra-wg-002...", move |progress| {
+            released.recv().unwrap();
+            Outcome::Connect(job.run(&progress))
+        });
+        task.cancel();
+        release.send(()).unwrap();
+        app.task = Some(task);
+        app.wait().await;
+
+        // Nothing was run, so there is nothing to roll back
+        assert!(runner.calls().is_empty());
+        assert!(matches!(app.failure(), Some(ConnectError::Cancelled)));
+        assert_eq!(app.error.as_deref(), Some("Cancelled connecting to de-fra-wg-002"));
+        assert_eq!(app.connection_status, ConnectionStatus::Disconnected);
+        let entry = app.history.last().unwrap();
+        assert_eq!(entry.error.as_deref(), Some("Cancelled"));
+    }
+
+    #[tokio::test]
+    async fn disconnect_runs_in_the_background() {
+        let _journal = crate::testing::lock_journal();
+        let runner = Arc::new(FakeRunner::new());
+        let mut app = sample_app();
+        app.runner = runner.clone();
+        app.settings.backend = Backend::WgQuick;
+        app.settings.firewall.kill_switch = KillSwitch::On;
+        app.connection_status = ConnectionStatus::Connected("de-fra-wg-002".to_string());
+
+        app.disconnect();
+        assert_eq!(app.task.as_ref().map(|t| t.status()), Some("Disconnecting from de-fra-wg-002..."));
+
+        app.wait().await;
+        // DNS rules, the tunnel, then the kill switch
+        assert_eq!(
+            runner.calls(),
+            ["nft -f -", "wg-quick down de-fra-wg-002", "nft -f -"]
+        );
+        assert_eq!(app.connection_status, ConnectionStatus::Disconnected);
+        assert_eq!(app.message.as_deref(), Some("Disconnected"));
+        let entry = app.history.last().unwrap();
+        assert_eq!((entry.action, entry.code.as_str()), (Action::Disconnect, "de-fra-wg-002"));
+    }
+
+    #[tokio::test]
+    async fn remedies_run_in_the_background() {
+        let _journal = crate::testing::lock_journal();
+        let runner = Arc::new(FakeRunner::new());
+        let mut app = sample_app();
+        app.runner = runner.clone();
+        app.settings.backend = Backend::WgQuick;
+
+        app.fail("Failed to connect".to_string(), ConnectError::ModuleMissing);
+        app.apply_remedy();
+        assert_eq!(app.task.as_ref().map(|t| t.status()), Some("Loading wireguard module..."));
+        app.wait().await;
+        assert_eq!(app.message.as_deref(), Some("Loaded wireguard module"));
+
+        app.fail(
+            "Failed to connect".to_string(),
+            ConnectError::InterfaceExists("de-fra-wg-001".to_string()),
+        );
+        app.apply_remedy();
+        assert_eq!(app.task.as_ref().map(|t| t.status()), Some("Removing interface de-fra-wg-001..."));
+        app.wait().await;
+        assert_eq!(app.message.as_deref(), Some("Removed interface de-fra-wg-001"));
+        assert_eq!(
+            runner.calls(),
+            ["modprobe wireguard", "nft -f -", "wg-quick down de-fra-wg-001", "wg show"]
+        );
+    }
+
+    #[tokio::test]
+    async fn status_is_checked_in_the_background() {
+        let runner = Arc::new(FakeRunner::new());
+        runner.respond("wg show", 0, "interface: de-fra-wg-002\n", "");
+        let mut app = sample_app();
+        app.runner = runner.clone();
+
+        app.update_status();
+        assert_eq!(app.connection_status, ConnectionStatus::Disconnected);
+
+        app.wait().await;
+        assert_eq!(app.connection_status, ConnectionStatus::Connected("de-fra-wg-002".to_string()));
+    }
+
+    #[tokio::test]
+    async fn blocklist_is_switched_in_the_background_while_disconnected() {
+        let mut app = sample_app();
+        app.show_dns();
+
+        app.toggle_blocklist();
+        assert_eq!(app.task.as_ref().map(|t| t.status()), Some("Switching resolver..."));
+
+        app.wait().await;
+        assert!(app.error.is_none());
+        assert!(app.message.as_deref().unwrap().starts_with("DNS: blocking"));
+    }
+
+    /// App connected to a tunnel whose last handshake was ten minutes ago
+    fn stale_app(runner: Arc<FakeRunner>) -> App {
+        let mut app = sample_app();
+        app.runner = runner;
+        app.settings.backend = Backend::WgQuick;
+        app.connection_status = ConnectionStatus::Connected("de-fra-wg-002".to_string());
+        app.tunnel.current = Some(TunnelStats {
+            endpoint: Some("185.209.196.72:51820".to_string()),
+            latest_handshake: Some(unix_time() - 600),
+            rx_bytes: 0,
+            tx_bytes: 0,
+            keepalive: None,
+        });
+        app
+    }
+
+    #[tokio::test]
+    async fn watchdog_moves_away_from_a_dead_relay_before_giving_up() {
+        let _journal = crate::testing::lock_journal();
+        let runner = Arc::new(FakeRunner::new());
+        runner.respond("wg-quick up", 1, "", "RTNETLINK answers: Operation not permitted\n");
+        let mut app = stale_app(runner);
+        let stale = app.stale_handshake("de-fra-wg-002");
+        assert!(stale.is_some_and(|secs| secs >= 600));
+
+        // Each reconnect fails, so the next check takes the next step
+        app.finish_probe("de-fra-wg-002".to_string(), Some(600), false);
+        let mut targets = Vec::new();
+        while let Some(task) = &app.task {
+            targets.push(task.status().to_string());
+            app.wait().await;
+            assert_eq!(app.connection_status, ConnectionStatus::Disconnected);
+            app.check_tunnel();
+        }
+
+        // Frankfurt has only one other online relay; Berlin is left in Germany
+        assert_eq!(
+            targets,
+            [
+                "Connecting to de-fra-wg-002...",
+                "Connecting to de-fra-wg-001...",
+                "Connecting to de-ber-wg-001...",
+            ]
+        );
+        let incident = app.watchdog.incident.as_ref().unwrap();
+        assert_eq!(incident.step, None);
+        assert_eq!(incident.tried, ["de-fra-wg-002", "de-fra-wg-001", "de-ber-wg-001"]);
+
+        let log: Vec<&str> = app.watchdog.log.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(log[0], "de-fra-wg-002 looks dead: no handshake for 600s and tunnel probe failed");
+        assert_eq!(log[1], "Reconnecting to de-fra-wg-002");
+        assert!(log[2].starts_with("Failed to connect: "));
+        assert_eq!(log[3], "Reconnecting to de-fra-wg-001");
+        assert_eq!(log[5], "Reconnecting to de-ber-wg-001");
+        assert_eq!(log[7], "Giving up on recovering de-fra-wg-002. Reconnect manually.");
+        assert_eq!(log.len(), 8);
+
+        // Given up, later checks leave the tunnel alone
+        app.check_tunnel();
+        assert!(app.task.is_none() && app.monitor.is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_tunnel_is_probed_and_reconnected_when_dead() {
+        let _journal = crate::testing::lock_journal();
+        let runner = Arc::new(FakeRunner::new());
+        // Without a reply the probe counts as lost
+        runner.respond("ping", 1, "1 packets transmitted, 0 received\n", "");
+        let mut app = stale_app(runner.clone());
+
+        app.check_tunnel();
+        assert!(app.task.is_none());
+        while app.monitor.is_some() {
+            tokio::task::yield_now().await;
+            app.poll_monitor();
+        }
+
+        assert_eq!(runner.calls()[0], format!("ping -n -c 1 -W 2 {}", watchdog::PROBE_ADDR));
+        assert_eq!(app.task.as_ref().map(|t| t.status()), Some("Connecting to de-fra-wg-002..."));
+        let log: Vec<&str> = app.watchdog.log.iter().map(|e| e.message.as_str()).collect();
+        assert!(log[0].starts_with("de-fra-wg-002 looks dead: no handshake for "));
+        assert_eq!(log[1], "Reconnecting to de-fra-wg-002");
+        app.wait().await;
+    }
+
+    #[tokio::test]
+    async fn measured_servers_are_sorted_by_latency() {
+        let runner = Arc::new(FakeRunner::new());
+        runner
+            .respond("ping -n -c 1 -W 2 185.209.196.71", 0, "64 bytes from 185.209.196.71: time=40.1 ms\n", "")
+            .respond("ping -n -c 1 -W 2 185.209.196.72", 0, "64 bytes from 185.209.196.72: time=9.7 ms\n", "");
+        let mut app = sample_app();
+        app.runner = runner;
+        app.settings.sort_by_latency = true;
+        app.select();
+        app.next();
+        app.select();
+        assert_eq!(app.selected_city.as_deref(), Some("Frankfurt"));
+
+        app.measure_latency();
+        app.wait().await;
+
+        let order: Vec<&str> = app.city_servers.iter().map(|s| s.code.as_str()).collect();
+        assert_eq!(order, ["de-fra-wg-002", "de-fra-wg-001", "de-fra-wg-003"]);
+        assert_eq!(app.latency_ms("de-fra-wg-002"), Some(9.7));
+        assert_eq!(app.message.as_deref(), Some("Measured 3 servers (2 reachable)"));
+    }
+
+    #[tokio::test]
+    async fn recovery_ends_once_the_tunnel_answers() {
+        let mut app = stale_app(Arc::new(FakeRunner::new()));
+        app.watchdog.incident = Some(Incident {
+            code: "de-fra-wg-002".to_string(),
+            country: "Germany".to_string(),
+            city: "Frankfurt".to_string(),
+            step: Some(Step::SameCity),
+            tried: vec!["de-fra-wg-002".to_string()],
+        });
+
+        app.finish_probe("de-fra-wg-002".to_string(), Some(600), true);
+
+        assert!(app.watchdog.incident.is_none());
+        assert!(app.task.is_none());
+        let entry = app.watchdog.log.back().unwrap();
+        assert_eq!(entry.message, "Connection restored via de-fra-wg-002");
+        assert_eq!(app.message.as_deref(), Some("Watchdog: Connection restored via de-fra-wg-002"));
+    }
+
+    #[tokio::test]
+    async fn kill_switch_mode_is_kept_only_once_applied() {
+        let _journal = crate::testing::lock_journal();
+        let runner = Arc::new(FakeRunner::new());
+        runner.respond("nft", 1, "", "Error: Could not process rule: Operation not permitted\n");
+        let mut app = sample_app();
+        app.runner = runner.clone();
+
+        app.cycle_kill_switch();
+        assert_eq!(app.settings.firewall.kill_switch, KillSwitch::Off);
+
+        app.wait().await;
+        assert_eq!(app.settings.firewall.kill_switch, KillSwitch::Off);
+        assert!(matches!(app.failure(), Some(ConnectError::Firewall(_))));
+    }
+
+    #[tokio::test]
+    async fn tunnel_stats_are_sampled_without_blocking_actions() {
+        let runner = Arc::new(FakeRunner::new());
+        runner.respond(
+            "wg show de-fra-wg-002 dump",
+            0,
+            "priv\tpub\t51820\t51820\n\
+             peer\t(none)\t185.209.196.72:51820\t0.0.0.0/0\t1700000000\t2048\t1024\t0\n",
+            "",
+        );
+        let mut app = sample_app();
+        app.runner = runner.clone();
+        app.connection_status = ConnectionStatus::Connected("de-fra-wg-002".to_string());
+
+        app.tick();
+        assert!(app.monitor.is_some());
+        assert!(app.task.is_none());
+
+        while app.monitor.is_some() {
+            tokio::task::yield_now().await;
+            app.poll_monitor();
+        }
+        let stats = app.tunnel.current.as_ref().unwrap();
+        assert_eq!((stats.rx_bytes, stats.tx_bytes), (2048, 1024));
+        assert_eq!(runner.calls(), ["wg show de-fra-wg-002 dump"]);
+    }
+
+    #[tokio::test]
+    async fn cancel_stops_setup_before_anything_is_written() {
+        let api = MockApi::start().await;
+        let runner = Arc::new(FakeRunner::new());
+        let mut app = setup_app(&api, runner, "1234567890123456");
+
+        app.submit_setup();
+        app.cancel_task();
+
+        assert!(app.task.is_none());
+        assert_eq!(app.error.as_deref(), Some("Cancelled"));
+        assert_eq!(app.private_key, None);
+        assert_eq!(app.view, View::Setup);
+    }
+}
@@ -0,0 +1,543 @@
+use std::pin::pin;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+
+use crate::app::{self, App};
+use crate::history::{Action, HistoryEntry};
+use crate::select::{Constraints, Strategy};
+use crate::server::Server;
+use crate::wireguard::{self, ConnectionStatus};
+
+/// Version of the JSON documents printed with `--json`. Bump on breaking changes.
+const JSON_VERSION: u32 = 1;
+
+/// Command line interface. Without a subcommand the TUI is started.
+#[derive(Debug, Parser)]
+#[command(name = "mvtui", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Connect to a server (e.g. se-mma-wg-001), or pick one with --auto
+    Connect {
+        #[arg(required_unless_present = "auto")]
+        code: Option<String>,
+        /// Pick the best server matching the options below
+        #[arg(long, conflicts_with = "code")]
+        auto: bool,
+        /// Country name or code (e.g. "ch")
+        #[arg(long, conflicts_with = "code")]
+        country: Option<String>,
+        /// City name or code (e.g. "zrh")
+        #[arg(long, conflicts_with = "code")]
+        city: Option<String>,
+        /// Only use Mullvad-owned servers
+        #[arg(long, conflicts_with = "code")]
+        owned: bool,
+        /// Never use servers from this provider (repeatable)
+        #[arg(long = "exclude-provider", conflicts_with = "code")]
+        exclude_providers: Vec<String>,
+        /// Only use servers measured at or below this latency (ms)
+        #[arg(long, conflicts_with = "code")]
+        max_latency: Option<f64>,
+        /// How to choose between matching servers [default: from settings]
+        #[arg(long, value_enum, conflicts_with = "code")]
+        strategy: Option<Strategy>,
+    },
+    /// Disconnect from the current server
+    Disconnect,
+    /// Reconnect to the most recently connected server
+    Reconnect,
+    /// Show connection history, newest first
+    History {
+        /// Print a JSON document instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show connection status
+    Status {
+        /// Print a JSON document instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// List cached servers
+    List {
+        /// Print a JSON document instead of text
+        #[arg(long)]
+        json: bool,
+        /// Only show servers in this country, by name or code (e.g. "ch")
+        #[arg(long)]
+        country: Option<String>,
+        /// Only show servers in this city, by name or code (e.g. "zrh")
+        #[arg(long)]
+        city: Option<String>,
+    },
+    /// Fetch the server list from the Mullvad API
+    Refresh,
+    /// List favorite servers, or add/remove one
+    Favorites {
+        #[command(subcommand)]
+        action: Option<FavoritesAction>,
+    },
+    /// Roll back state left behind by an interrupted connect or disconnect
+    Repair,
+    /// Enable autostart on boot for a server
+    Autostart {
+        code: Option<String>,
+        /// Disable autostart for the currently enabled server
+        #[arg(long, conflicts_with = "code")]
+        disable: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FavoritesAction {
+    /// Add a server to favorites
+    Add { code: String },
+    /// Remove a server from favorites
+    Remove { code: String },
+}
+
+/// Run a subcommand without starting the TUI
+pub async fn run(command: Command) -> Result<()> {
+    let mut app = App::new();
+
+    // Startup already reconciles, so repair runs on its own to report what it did
+    if let Command::Repair = command {
+        app.repair();
+        if app.message.is_none() && app.error.is_none() {
+            println!("Nothing to repair");
+        }
+        return finish(&mut app);
+    }
+
+    // Queries are polled by monitoring and cron, and must never change the
+    // system under a connect that another mvtui has in flight
+    let query = matches!(
+        command,
+        Command::Status { .. } | Command::List { .. } | Command::History { .. } | Command::Favorites { .. }
+    );
+    if query {
+        app.load()?;
+    } else {
+        app.init().await?;
+    }
+
+    match command {
+        Command::Connect {
+            code,
+            auto,
+            country,
+            city,
+            owned,
+            exclude_providers,
+            max_latency,
+            strategy,
+        } => {
+            if auto {
+                let constraints = Constraints {
+                    country,
+                    city,
+                    owned_only: owned,
+                    exclude_providers,
+                    max_latency_ms: max_latency,
+                    ..Default::default()
+                };
+                let strategy = strategy.unwrap_or(app.settings.strategy);
+                app.connect_best(&constraints, strategy);
+            } else if let Some(code) = code {
+                app.connect_to_server(&code);
+            }
+            wait(&mut app).await;
+            finish(&mut app)
+        }
+        Command::Disconnect => {
+            if app.connection_status == ConnectionStatus::Disconnected {
+                println!("Not connected");
+                return Ok(());
+            }
+            app.disconnect();
+            wait(&mut app).await;
+            finish(&mut app)
+        }
+        Command::Reconnect => {
+            app.reconnect_last();
+            wait(&mut app).await;
+            finish(&mut app)
+        }
+        Command::History { json } => {
+            if json {
+                return print_json(&HistoryDocument {
+                    version: JSON_VERSION,
+                    entries: app.history.iter().rev().map(HistoryEntryJson::from).collect(),
+                });
+            }
+
+            for entry in app.history.iter().rev() {
+                let action = match entry.action {
+                    Action::Connect => "connect",
+                    Action::Disconnect => "disconnect",
+                };
+                let outcome = match (&entry.error, entry.duration) {
+                    (Some(error), _) => error.clone(),
+                    (None, Some(duration)) => format!("after {}s", duration),
+                    (None, None) => "OK".to_string(),
+                };
+                println!("{} {:<11}{:<20} {}", entry.local_time(), action, entry.code, outcome);
+            }
+            Ok(())
+        }
+        Command::Status { json } => {
+            if json {
+                return print_json(&StatusDocument {
+                    version: JSON_VERSION,
+                    status: &app.connection_status,
+                    autostart: app.autostart_server.as_deref(),
+                });
+            }
+
+            match &app.connection_status {
+                ConnectionStatus::Connected(code) => {
+                    match app.servers.iter().find(|s| &s.code == code) {
+                        Some(server) => println!("Connected: {} ({})", code, server.location()),
+                        None => println!("Connected: {}", code),
+                    }
+                }
+                ConnectionStatus::Disconnected => println!("Disconnected"),
+            }
+            if let Some(code) = &app.autostart_server {
+                println!("Autostart: {}", code);
+            }
+            Ok(())
+        }
+        Command::List { json, country, city } => {
+            let cache = app::load_cache()?
+                .ok_or_else(|| anyhow!("No servers cached. Run 'mvtui refresh' first."))?;
+            let location = Constraints {
+                country,
+                city,
+                ..Default::default()
+            };
+            let servers: Vec<&Server> = cache
+                .servers
+                .iter()
+                .filter(|s| location.matches_location(s))
+                .collect();
+
+            if json {
+                return print_json(&ServerListDocument {
+                    version: JSON_VERSION,
+                    timestamp: cache.timestamp,
+                    servers: servers.into_iter().map(ServerJson::from).collect(),
+                });
+            }
+
+            for server in servers {
+                println!(
+                    "{:<20} {:<30} {:<15} {}{}{}",
+                    server.code,
+                    server.location(),
+                    server.ipv4_addr,
+                    server.provider,
+                    if server.owned { " (owned)" } else { "" },
+                    if server.active { "" } else { " [offline]" }
+                );
+            }
+            Ok(())
+        }
+        Command::Refresh => {
+            app.refresh_servers();
+            wait(&mut app).await;
+            finish(&mut app)
+        }
+        Command::Favorites { action } => match action {
+            Some(FavoritesAction::Add { code }) => {
+                if !app.servers.iter().any(|s| s.code == code) {
+                    return Err(anyhow!("Unknown server: {}", code));
+                }
+                app.set_favorite(&code, true);
+                finish(&mut app)
+            }
+            Some(FavoritesAction::Remove { code }) => {
+                app.set_favorite(&code, false);
+                finish(&mut app)
+            }
+            None => {
+                for server in app.favorite_servers() {
+                    let connected = matches!(&app.connection_status,
+                        ConnectionStatus::Connected(c) if c == &server.code);
+                    println!(
+                        "{:<20} {:<30}{}{}",
+                        server.code,
+                        server.location(),
+                        if connected { " [connected]" } else { "" },
+                        if app.autostart_server.as_ref() == Some(&server.code) {
+                            " [autostart]"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+                Ok(())
+            }
+        },
+        Command::Repair => unreachable!("handled before init"),
+        Command::Autostart { code, disable } => {
+            if disable {
+                match app.autostart_server.clone() {
+                    Some(current) => {
+                        wireguard::disable_autostart(app.runner.as_ref(), &current)?;
+                        println!("Disabled autostart for {}", current);
+                    }
+                    None => println!("Autostart is not enabled"),
+                }
+                return Ok(());
+            }
+
+            let code = code.ok_or_else(|| anyhow!("Specify a server code or --disable"))?;
+            if wireguard::is_enabled(app.runner.as_ref(), &code) {
+                println!("Autostart already enabled for {}", code);
+                return Ok(());
+            }
+            wireguard::enable_autostart(app.runner.as_ref(), &code)?;
+            println!("Enabled autostart for {}", code);
+            Ok(())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StatusDocument<'a> {
+    version: u32,
+    status: &'a ConnectionStatus,
+    autostart: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ServerListDocument<'a> {
+    version: u32,
+    timestamp: u64,
+    servers: Vec<ServerJson<'a>>,
+}
+
+/// A server as printed by `list --json`. Kept apart from `Server` so the
+/// document only changes together with `JSON_VERSION`.
+#[derive(Serialize)]
+struct ServerJson<'a> {
+    code: &'a str,
+    hostname: &'a str,
+    public_key: &'a str,
+    ipv4_addr: &'a str,
+    port: u16,
+    country: &'a str,
+    city: &'a str,
+}
+
+impl<'a> From<&'a Server> for ServerJson<'a> {
+    fn from(server: &'a Server) -> Self {
+        Self {
+            code: &server.code,
+            hostname: &server.hostname,
+            public_key: &server.public_key,
+            ipv4_addr: &server.ipv4_addr,
+            port: server.port,
+            country: &server.country,
+            city: &server.city,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HistoryDocument<'a> {
+    version: u32,
+    entries: Vec<HistoryEntryJson<'a>>,
+}
+
+/// A history entry as printed by `history --json`
+#[derive(Serialize)]
+struct HistoryEntryJson<'a> {
+    timestamp: u64,
+    action: Action,
+    code: &'a str,
+    duration: Option<u64>,
+    error: Option<&'a str>,
+}
+
+impl<'a> From<&'a HistoryEntry> for HistoryEntryJson<'a> {
+    fn from(entry: &'a HistoryEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp,
+            action: entry.action,
+            code: &entry.code,
+            duration: entry.duration,
+            error: entry.error.as_deref(),
+        }
+    }
+}
+
+fn print_json<T: Serialize>(document: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(document)?);
+    Ok(())
+}
+
+/// Wait for the task an action started. Ctrl-C cancels it, rolling back a connect.
+async fn wait(app: &mut App) {
+    let mut ctrl_c = pin!(tokio::signal::ctrl_c());
+    let mut cancelled = false;
+    while app.task.is_some() {
+        tokio::select! {
+            _ = &mut ctrl_c, if !cancelled => {
+                cancelled = true;
+                app.cancel_task();
+            }
+            _ = tokio::time::sleep(Duration::from_millis(100)) => app.poll_task(),
+        }
+    }
+}
+
+/// Report the outcome of an `App` action the same way the TUI message bar would.
+/// Connection failures keep their type so `main` can pick the exit code.
+fn finish(app: &mut App) -> Result<()> {
+    let failure = app.take_failure();
+    if let Some(error) = app.error.take() {
+        return Err(match failure {
+            Some(failure) => anyhow::Error::new(failure).context(error),
+            None => anyhow!(error),
+        });
+    }
+    if let Some(message) = app.message.take() {
+        println!("{}", message);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample_servers;
+
+    fn json<T: Serialize>(document: &T) -> String {
+        serde_json::to_string_pretty(document).unwrap()
+    }
+
+    #[test]
+    fn status_document_is_stable() {
+        let status = ConnectionStatus::Connected("se-mma-wg-001".to_string());
+        let document = StatusDocument {
+            version: JSON_VERSION,
+            status: &status,
+            autostart: Some("se-mma-wg-001"),
+        };
+
+        assert_eq!(
+            json(&document),
+            r#"{
+  "version": 1,
+  "status": {
+    "state": "connected",
+    "server": "se-mma-wg-001"
+  },
+  "autostart": "se-mma-wg-001"
+}"#
+        );
+
+        let document = StatusDocument {
+            version: JSON_VERSION,
+            status: &ConnectionStatus::Disconnected,
+            autostart: None,
+        };
+        assert_eq!(
+            json(&document),
+            r#"{
+  "version": 1,
+  "status": {
+    "state": "disconnected"
+  },
+  "autostart": null
+}"#
+        );
+    }
+
+    #[test]
+    fn server_list_document_is_stable() {
+        let servers = sample_servers();
+        let document = ServerListDocument {
+            version: JSON_VERSION,
+            timestamp: 1700000000,
+            servers: servers[..1].iter().map(ServerJson::from).collect(),
+        };
+
+        // Fields added to `Server` later (provider, ownership, IPv6, ...) stay out of v1
+        assert_eq!(
+            json(&document),
+            r#"{
+  "version": 1,
+  "timestamp": 1700000000,
+  "servers": [
+    {
+      "code": "de-fra-wg-001",
+      "hostname": "de-fra-wg-001",
+      "public_key": "5VRvAFF5vrELTzOD7ZZcVnQHv0O+dRKy2UDkVQDcEmw=",
+      "ipv4_addr": "185.209.196.71",
+      "port": 51820,
+      "country": "Germany",
+      "city": "Frankfurt"
+    }
+  ]
+}"#
+        );
+    }
+
+    #[test]
+    fn history_document_is_stable() {
+        let entries = [
+            HistoryEntry {
+                timestamp: 1700000000,
+                action: Action::Connect,
+                code: "se-mma-wg-001".to_string(),
+                duration: None,
+                error: None,
+            },
+            HistoryEntry {
+                timestamp: 1700003600,
+                action: Action::Disconnect,
+                code: "se-mma-wg-001".to_string(),
+                duration: Some(3600),
+                error: Some("wg-quick down failed".to_string()),
+            },
+        ];
+        let document = HistoryDocument {
+            version: JSON_VERSION,
+            entries: entries.iter().map(HistoryEntryJson::from).collect(),
+        };
+
+        assert_eq!(
+            json(&document),
+            r#"{
+  "version": 1,
+  "entries": [
+    {
+      "timestamp": 1700000000,
+      "action": "connect",
+      "code": "se-mma-wg-001",
+      "duration": null,
+      "error": null
+    },
+    {
+      "timestamp": 1700003600,
+      "action": "disconnect",
+      "code": "se-mma-wg-001",
+      "duration": 3600,
+      "error": "wg-quick down failed"
+    }
+  ]
+}"#
+        );
+    }
+}
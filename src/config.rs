@@ -1,16 +1,26 @@
 use anyhow::{Context, Result};
 use std::fs;
+use std::net::IpAddr;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 
 use crate::server::Server;
 
-const WIREGUARD_DIR: &str = "/etc/wireguard";
-const DNS_SERVER: &str = "10.64.0.1";
+/// Directory wg-quick reads configs from
+#[cfg(not(test))]
+fn wireguard_dir() -> PathBuf {
+    PathBuf::from("/etc/wireguard")
+}
+
+/// Tests write configs to a scratch directory
+#[cfg(test)]
+fn wireguard_dir() -> PathBuf {
+    crate::testing::scratch_dir().join("wireguard")
+}
 
 /// Get the path to a WireGuard config file for a server code
 pub fn config_path(code: &str) -> PathBuf {
-    Path::new(WIREGUARD_DIR).join(format!("{}.conf", code))
+    wireguard_dir().join(format!("{}.conf", code))
 }
 
 /// Check if a config file exists for the given server code
@@ -20,13 +30,13 @@ pub fn config_exists(code: &str) -> bool {
 
 /// List all existing Mullvad config files
 pub fn list_configs() -> Result<Vec<String>> {
-    let dir = Path::new(WIREGUARD_DIR);
+    let dir = wireguard_dir();
     if !dir.exists() {
         return Ok(Vec::new());
     }
 
     let mut configs = Vec::new();
-    for entry in fs::read_dir(dir)? {
+    for entry in fs::read_dir(&dir)? {
         let entry = entry?;
         let path = entry.path();
         if let Some(ext) = path.extension() {
@@ -68,6 +78,55 @@ pub fn extract_private_key(code: &str) -> Result<Option<String>> {
     Ok(None)
 }
 
+/// Settings from a WireGuard config file needed to bring up the tunnel natively
+#[derive(Debug, Clone)]
+pub struct WgConfig {
+    pub private_key: String,
+    /// Interface addresses in CIDR notation
+    pub addresses: Vec<String>,
+    pub peer_public_key: String,
+    pub endpoint: String,
+    pub allowed_ips: Vec<String>,
+}
+
+/// Parse the config file for a server code
+pub fn read_config(code: &str) -> Result<WgConfig> {
+    let path = config_path(code);
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut private_key = None;
+    let mut addresses = Vec::new();
+    let mut peer_public_key = None;
+    let mut endpoint = None;
+    let mut allowed_ips = Vec::new();
+
+    for line in content.lines() {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        let list = || value.split(',').map(|v| v.trim().to_string());
+        match key.trim().to_lowercase().as_str() {
+            "privatekey" => private_key = Some(value.to_string()),
+            "address" => addresses.extend(list()),
+            "publickey" => peer_public_key = Some(value.to_string()),
+            "endpoint" => endpoint = Some(value.to_string()),
+            "allowedips" => allowed_ips.extend(list()),
+            _ => {}
+        }
+    }
+
+    let missing = |field: &str| anyhow::anyhow!("{} missing from {}", field, path.display());
+    Ok(WgConfig {
+        private_key: private_key.ok_or_else(|| missing("PrivateKey"))?,
+        addresses,
+        peer_public_key: peer_public_key.ok_or_else(|| missing("PublicKey"))?,
+        endpoint: endpoint.ok_or_else(|| missing("Endpoint"))?,
+        allowed_ips,
+    })
+}
+
 /// Find any existing private key from Mullvad configs
 pub fn find_existing_private_key() -> Result<Option<String>> {
     for code in list_configs()? {
@@ -78,12 +137,20 @@ pub fn find_existing_private_key() -> Result<Option<String>> {
     Ok(None)
 }
 
-/// Generate a WireGuard config file for a server
+/// Generate a WireGuard config file for a server, reaching it over IPv6 if `ipv6` is set
 pub fn generate_config(
     server: &Server,
     private_key: &str,
     address: &str,
+    dns: IpAddr,
+    ipv6: bool,
 ) -> Result<()> {
+    // Relays without an IPv6 address are still reached over IPv4
+    let endpoint = ipv6
+        .then(|| server.endpoint_v6())
+        .flatten()
+        .unwrap_or_else(|| server.endpoint());
+
     let content = format!(
         "[Interface]\n\
          PrivateKey = {}\n\
@@ -96,16 +163,51 @@ pub fn generate_config(
          AllowedIPs = 0.0.0.0/0, ::/0\n",
         private_key,
         address,
-        DNS_SERVER,
+        dns,
         server.public_key,
-        server.endpoint()
+        endpoint
     );
 
-    let path = config_path(&server.code);
+    write_config(&config_path(&server.code), &content)
+}
+
+/// Point the DNS line of all existing Mullvad configs at `dns`.
+/// Returns how many configs were changed.
+pub fn set_dns(dns: IpAddr) -> Result<usize> {
+    let mut count = 0;
+    for code in list_configs()? {
+        let path = config_path(&code);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let mut changed = false;
+        let lines: Vec<String> = content
+            .lines()
+            .map(|line| match line.split_once('=') {
+                Some((key, value))
+                    if key.trim().eq_ignore_ascii_case("dns") && value.trim() != dns.to_string() =>
+                {
+                    changed = true;
+                    format!("DNS = {}", dns)
+                }
+                _ => line.to_string(),
+            })
+            .collect();
+
+        if changed {
+            write_config(&path, &(lines.join("\n") + "\n"))?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Write a config file in one step, readable only by root
+fn write_config(path: &Path, content: &str) -> Result<()> {
     let dir = path.parent().unwrap();
 
     // Ensure /etc/wireguard exists
-    fs::create_dir_all(dir).context("Failed to create /etc/wireguard")?;
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
 
     // Write with restrictive permissions (0600)
     let tmp_path = path.with_extension("conf.tmp");
@@ -115,33 +217,61 @@ pub fn generate_config(
         .truncate(true)
         .mode(0o600)
         .open(&tmp_path)
-        .and_then(|_| fs::write(&tmp_path, &content))
+        .and_then(|_| fs::write(&tmp_path, content))
         .context("Failed to write config file")?;
 
-    fs::rename(&tmp_path, &path).context("Failed to move config file")?;
+    fs::rename(&tmp_path, path).context("Failed to move config file")?;
 
     Ok(())
 }
 
-/// Delete a config file
-pub fn delete_config(code: &str) -> Result<()> {
-    let path = config_path(code);
-    if path.exists() {
-        fs::remove_file(&path).context("Failed to delete config file")?;
-    }
-    Ok(())
-}
-
 /// Generate configs for all servers
 pub fn generate_all_configs(
     servers: &[Server],
     private_key: &str,
     address: &str,
+    dns: IpAddr,
+    ipv6: bool,
 ) -> Result<usize> {
     let mut count = 0;
     for server in servers {
-        generate_config(server, private_key, address)?;
+        generate_config(server, private_key, address, dns, ipv6)?;
         count += 1;
     }
     Ok(count)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample_servers;
+
+    /// A relay no other test generates a config for
+    fn relay(code: &str, ipv6_addr: Option<&str>) -> Server {
+        Server {
+            code: code.to_string(),
+            ipv6_addr: ipv6_addr.map(str::to_string),
+            ..sample_servers().remove(0)
+        }
+    }
+
+    fn endpoint(server: &Server, ipv6: bool) -> String {
+        generate_config(server, "key", "10.68.12.34/32", "10.64.0.1".parse().unwrap(), ipv6).unwrap();
+        read_config(&server.code).unwrap().endpoint
+    }
+
+    #[test]
+    fn endpoint_follows_ipv6_setting() {
+        let server = relay("se-sto-wg-201", Some("2a03:1b20:3:f011::a01f"));
+
+        assert_eq!(endpoint(&server, false), "185.209.196.71:51820");
+        assert_eq!(endpoint(&server, true), "[2a03:1b20:3:f011::a01f]:51820");
+    }
+
+    #[test]
+    fn relay_without_ipv6_is_reached_over_ipv4() {
+        let server = relay("se-sto-wg-202", None);
+
+        assert_eq!(endpoint(&server, true), "185.209.196.71:51820");
+    }
+}
@@ -0,0 +1,402 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use crate::error::ConnectError;
+use crate::netlink;
+use crate::runner::CommandRunner;
+use crate::settings;
+
+type Result<T> = std::result::Result<T, ConnectError>;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Mullvad's resolver inside the tunnel, without content blocking
+const MULLVAD_DNS: Ipv4Addr = Ipv4Addr::new(10, 64, 0, 1);
+
+/// Mullvad's content blocking resolvers are 100.64.0.x, x being a bit per blocklist
+const BLOCKING_DNS_PREFIX: [u8; 3] = [100, 64, 0];
+
+/// D-Bus destination, object and interface of systemd-resolved
+const RESOLVED_DEST: &str = "org.freedesktop.resolve1";
+const RESOLVED_PATH: &str = "/org/freedesktop/resolve1";
+const RESOLVED_MANAGER: &str = "org.freedesktop.resolve1.Manager";
+
+/// How the system resolver is pointed at the tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DnsBackend {
+    /// Pick one of the others based on how the system is set up
+    #[default]
+    Auto,
+    /// Per-link DNS servers in systemd-resolved, set over D-Bus
+    SystemdResolved,
+    /// An interface record in openresolv or Debian's resolvconf
+    Resolvconf,
+    /// Overwrite /etc/resolv.conf and restore it from a backup afterwards
+    File,
+}
+
+/// Content Mullvad's in-tunnel resolvers can block
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Blocklist {
+    Ads,
+    Trackers,
+    Malware,
+    Adult,
+    Gambling,
+}
+
+impl Blocklist {
+    pub const ALL: [Blocklist; 5] = [
+        Blocklist::Ads,
+        Blocklist::Trackers,
+        Blocklist::Malware,
+        Blocklist::Adult,
+        Blocklist::Gambling,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Blocklist::Ads => "Ads",
+            Blocklist::Trackers => "Trackers",
+            Blocklist::Malware => "Malware",
+            Blocklist::Adult => "Adult content",
+            Blocklist::Gambling => "Gambling",
+        }
+    }
+
+    /// Bit of the last address octet that selects this blocklist
+    fn bit(self) -> u8 {
+        match self {
+            Blocklist::Ads => 1,
+            Blocklist::Trackers => 2,
+            Blocklist::Malware => 4,
+            Blocklist::Adult => 8,
+            Blocklist::Gambling => 16,
+        }
+    }
+}
+
+/// DNS configuration, part of the persistent settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DnsSettings {
+    pub backend: DnsBackend,
+    /// Content blocked by the resolver
+    pub blocking: Vec<Blocklist>,
+}
+
+impl DnsSettings {
+    /// Resolver that blocks the chosen content
+    pub fn resolver(&self) -> IpAddr {
+        let bits = self.blocking.iter().fold(0, |bits, list| bits | list.bit());
+        if bits == 0 {
+            return IpAddr::V4(MULLVAD_DNS);
+        }
+        let [a, b, c] = BLOCKING_DNS_PREFIX;
+        IpAddr::V4(Ipv4Addr::new(a, b, c, bits))
+    }
+
+    pub fn is_blocked(&self, list: Blocklist) -> bool {
+        self.blocking.contains(&list)
+    }
+
+    /// Turn blocking of a category on or off
+    pub fn toggle(&mut self, list: Blocklist) {
+        if self.is_blocked(list) {
+            self.blocking.retain(|l| *l != list);
+        } else {
+            self.blocking.push(list);
+        }
+    }
+
+    /// Blocked categories for display, e.g. "ads, malware" or "nothing"
+    pub fn describe(&self) -> String {
+        let lists: Vec<String> = Blocklist::ALL
+            .iter()
+            .filter(|list| self.is_blocked(**list))
+            .map(|list| list.label().to_lowercase())
+            .collect();
+        if lists.is_empty() {
+            "nothing".to_string()
+        } else {
+            lists.join(", ")
+        }
+    }
+}
+
+impl DnsBackend {
+    /// Resolve `Auto` to the backend that manages DNS on this system
+    pub fn detect(self) -> Self {
+        if self != DnsBackend::Auto {
+            return self;
+        }
+
+        if uses_resolved() {
+            DnsBackend::SystemdResolved
+        } else if is_installed("resolvconf") {
+            DnsBackend::Resolvconf
+        } else {
+            DnsBackend::File
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DnsBackend::Auto => "auto",
+            DnsBackend::SystemdResolved => "systemd-resolved",
+            DnsBackend::Resolvconf => "resolvconf",
+            DnsBackend::File => "resolv.conf",
+        }
+    }
+
+    /// Send all DNS queries to `servers` through the interface
+    pub fn apply(self, runner: &dyn CommandRunner, interface: &str, servers: &[IpAddr]) -> Result<()> {
+        match self.detect() {
+            DnsBackend::SystemdResolved => {
+                let index = interface_index(interface)?;
+                resolved_call(runner, "SetLinkDNS", &link_dns_args(index, servers))?;
+                // "~." routes all domains to this link
+                resolved_call(
+                    runner,
+                    "SetLinkDomains",
+                    &["ia(sb)", &index.to_string(), "1", ".", "true"].map(String::from),
+                )?;
+                resolved_call(runner, "FlushCaches", &[])
+            }
+            DnsBackend::Resolvconf => {
+                let content: String = servers
+                    .iter()
+                    .map(|server| format!("nameserver {}\n", server))
+                    .collect();
+                let record = resolvconf_record(interface);
+                // Exclusive mode with the highest priority, like wg-quick
+                run_with_input(runner, "resolvconf", &["-a", &record, "-m", "0", "-x"], &content)
+            }
+            DnsBackend::File => {
+                backup_resolv_conf()
+                    .map_err(|e| ConnectError::Dns(format!("Failed to back up {}: {}", RESOLV_CONF, e)))?;
+                let content: String = servers
+                    .iter()
+                    .map(|server| format!("nameserver {}\n", server))
+                    .collect();
+                write_resolv_conf(&format!("# Generated by mvtui for {}\n{}", interface, content))
+                    .map_err(|e| ConnectError::Dns(format!("Failed to write {}: {}", RESOLV_CONF, e)))
+            }
+            DnsBackend::Auto => unreachable!("detect never returns Auto"),
+        }
+    }
+
+    /// Undo `apply`; does nothing if there is nothing to undo
+    pub fn revert(self, runner: &dyn CommandRunner, interface: &str) -> Result<()> {
+        match self.detect() {
+            DnsBackend::SystemdResolved => {
+                // Link settings disappear together with the interface
+                if let Ok(index) = interface_index(interface) {
+                    resolved_call(runner, "RevertLink", &["i".to_string(), index.to_string()])?;
+                }
+                resolved_call(runner, "FlushCaches", &[])
+            }
+            DnsBackend::Resolvconf => {
+                let record = resolvconf_record(interface);
+                // -f: don't complain if the record is already gone
+                run_with_input(runner, "resolvconf", &["-d", &record, "-f"], "")
+            }
+            DnsBackend::File => restore_resolv_conf()
+                .map_err(|e| ConnectError::Dns(format!("Failed to restore {}: {}", RESOLV_CONF, e))),
+            DnsBackend::Auto => unreachable!("detect never returns Auto"),
+        }
+    }
+}
+
+/// /etc/resolv.conf points at the resolved stub when systemd-resolved manages DNS
+fn uses_resolved() -> bool {
+    let linked = fs::read_link(RESOLV_CONF)
+        .is_ok_and(|target| target.to_string_lossy().contains("systemd/resolve"));
+    linked
+        || fs::read_to_string(RESOLV_CONF)
+            .is_ok_and(|content| content.lines().any(|l| l.trim() == "nameserver 127.0.0.53"))
+}
+
+/// Whether a program can be found in PATH
+fn is_installed(program: &str) -> bool {
+    env::var_os("PATH").is_some_and(|path| {
+        env::split_paths(&path).any(|dir| dir.join(program).is_file())
+    })
+}
+
+fn interface_index(interface: &str) -> Result<u32> {
+    netlink::interface_index(interface)
+        .map_err(|e| ConnectError::Dns(format!("No interface {}: {}", interface, e)))
+}
+
+/// Arguments of SetLinkDNS: the link, then (address family, address bytes) per server
+fn link_dns_args(index: u32, servers: &[IpAddr]) -> Vec<String> {
+    let mut args = vec!["ia(iay)".to_string(), index.to_string(), servers.len().to_string()];
+    for server in servers {
+        let (family, octets) = match server {
+            IpAddr::V4(addr) => (libc::AF_INET, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (libc::AF_INET6, addr.octets().to_vec()),
+        };
+        args.push(family.to_string());
+        args.push(octets.len().to_string());
+        args.extend(octets.iter().map(u8::to_string));
+    }
+    args
+}
+
+/// Call a method of the systemd-resolved manager. `args` starts with the signature.
+fn resolved_call(runner: &dyn CommandRunner, method: &str, args: &[String]) -> Result<()> {
+    let mut call = vec!["call", RESOLVED_DEST, RESOLVED_PATH, RESOLVED_MANAGER, method];
+    call.extend(args.iter().map(String::as_str));
+    let output = runner
+        .output("busctl", &call)
+        .map_err(|e| ConnectError::spawn("busctl", e))?;
+
+    if !output.status.success() {
+        return Err(ConnectError::Dns(format!(
+            "{}: {}",
+            method,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Name of the resolvconf record for an interface. Like wg-quick, honor the
+/// interface-order of Debian's resolvconf, which only ranks known prefixes.
+fn resolvconf_record(interface: &str) -> String {
+    let prefix = fs::read_to_string("/etc/resolvconf/interface-order")
+        .ok()
+        .and_then(|order| {
+            order.lines().find_map(|line| {
+                let name = line.trim().strip_suffix('*')?;
+                let valid = !name.is_empty()
+                    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+                valid.then(|| format!("{}.", name))
+            })
+        })
+        .unwrap_or_default();
+    format!("{}{}", prefix, interface)
+}
+
+fn run_with_input(runner: &dyn CommandRunner, program: &str, args: &[&str], input: &str) -> Result<()> {
+    let output = runner
+        .run(program, args, Some(input))
+        .map_err(|e| ConnectError::spawn(program, e))?;
+
+    if !output.status.success() {
+        return Err(ConnectError::Dns(format!(
+            "{} {}: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+fn backup_path() -> PathBuf {
+    settings::state_dir().join("resolv.conf.backup")
+}
+
+/// Keep the original resolv.conf; a symlink is backed up as a symlink.
+/// An existing backup is left alone, it still holds the original.
+fn backup_resolv_conf() -> io::Result<()> {
+    let backup = backup_path();
+    if backup.symlink_metadata().is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::read_link(RESOLV_CONF) {
+        Ok(target) => symlink(target, &backup),
+        Err(_) => fs::copy(RESOLV_CONF, &backup).map(|_| ()),
+    }
+}
+
+/// Put the backed up resolv.conf back in place
+fn restore_resolv_conf() -> io::Result<()> {
+    let backup = backup_path();
+    if backup.symlink_metadata().is_err() {
+        return Ok(());
+    }
+
+    match fs::read_link(&backup) {
+        Ok(target) => {
+            let _ = fs::remove_file(RESOLV_CONF);
+            symlink(target, RESOLV_CONF)?;
+        }
+        Err(_) => write_resolv_conf(&fs::read_to_string(&backup)?)?,
+    }
+    fs::remove_file(&backup)
+}
+
+/// Replace resolv.conf (or the symlink in its place) in one step
+fn write_resolv_conf(content: &str) -> io::Result<()> {
+    let tmp_path = Path::new(RESOLV_CONF).with_extension("conf.mvtui");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, RESOLV_CONF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver(blocking: &[Blocklist]) -> IpAddr {
+        DnsSettings {
+            blocking: blocking.to_vec(),
+            ..Default::default()
+        }
+        .resolver()
+    }
+
+    #[test]
+    fn no_blocking_uses_plain_resolver() {
+        assert_eq!(resolver(&[]), IpAddr::V4(Ipv4Addr::new(10, 64, 0, 1)));
+    }
+
+    #[test]
+    fn each_blocklist_selects_its_bit() {
+        let cases = [
+            (Blocklist::Ads, 1),
+            (Blocklist::Trackers, 2),
+            (Blocklist::Malware, 4),
+            (Blocklist::Adult, 8),
+            (Blocklist::Gambling, 16),
+        ];
+
+        for (list, octet) in cases {
+            assert_eq!(resolver(&[list]), IpAddr::V4(Ipv4Addr::new(100, 64, 0, octet)), "{:?}", list);
+        }
+    }
+
+    #[test]
+    fn combined_blocklists_add_up() {
+        assert_eq!(
+            resolver(&[Blocklist::Malware, Blocklist::Ads]),
+            IpAddr::V4(Ipv4Addr::new(100, 64, 0, 5))
+        );
+        assert_eq!(resolver(&Blocklist::ALL), IpAddr::V4(Ipv4Addr::new(100, 64, 0, 31)));
+    }
+
+    #[test]
+    fn toggling_twice_restores_plain_resolver() {
+        let mut settings = DnsSettings::default();
+
+        settings.toggle(Blocklist::Trackers);
+        assert_eq!(settings.resolver(), IpAddr::V4(Ipv4Addr::new(100, 64, 0, 2)));
+        settings.toggle(Blocklist::Trackers);
+        assert_eq!(settings.resolver(), IpAddr::V4(MULLVAD_DNS));
+    }
+}
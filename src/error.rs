@@ -0,0 +1,126 @@
+use std::fmt;
+use std::io;
+
+/// Why connecting, disconnecting or setting up failed
+#[derive(Debug)]
+pub enum ConnectError {
+    /// No WireGuard config file for the server
+    MissingConfig(String),
+    /// The wireguard kernel module is not loaded
+    ModuleMissing,
+    /// An interface with this name already exists
+    InterfaceExists(String),
+    /// Pointing the system resolver at the tunnel failed
+    Dns(String),
+    /// Not running with enough privileges
+    Permission(String),
+    /// Talking to the Mullvad API failed
+    Api(String),
+    /// Installing or removing firewall rules failed
+    Firewall(String),
+    /// Stopped by the user and rolled back
+    Cancelled,
+    /// Anything else (unexpected wg-quick output, netlink errors, ...)
+    Other(String),
+}
+
+/// Action the UI can offer to fix a failure
+#[derive(Debug, Clone, PartialEq)]
+pub enum Remedy {
+    /// Open the setup screen to generate configs
+    Setup,
+    /// Run `modprobe wireguard`
+    LoadModule,
+    /// Tear down the leftover interface
+    RemoveInterface(String),
+}
+
+impl Remedy {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Remedy::Setup => "run setup",
+            Remedy::LoadModule => "load wireguard module",
+            Remedy::RemoveInterface(_) => "remove stale interface",
+        }
+    }
+}
+
+impl ConnectError {
+    pub fn remedy(&self) -> Option<Remedy> {
+        match self {
+            ConnectError::MissingConfig(_) => Some(Remedy::Setup),
+            ConnectError::ModuleMissing => Some(Remedy::LoadModule),
+            ConnectError::InterfaceExists(name) => Some(Remedy::RemoveInterface(name.clone())),
+            _ => None,
+        }
+    }
+
+    /// Exit code used by the CLI, so scripts can tell failures apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ConnectError::Other(_) => 1,
+            ConnectError::MissingConfig(_) => 2,
+            ConnectError::ModuleMissing => 3,
+            ConnectError::InterfaceExists(_) => 4,
+            ConnectError::Dns(_) => 5,
+            ConnectError::Permission(_) => 6,
+            ConnectError::Api(_) => 7,
+            ConnectError::Firewall(_) => 8,
+            // Like a shell reports a command ended with Ctrl-C
+            ConnectError::Cancelled => 130,
+        }
+    }
+
+    /// Classify a failure to run an external program
+    pub fn spawn(program: &str, error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => ConnectError::Other(format!("{} not found. Is it installed?", program)),
+            io::ErrorKind::PermissionDenied => {
+                ConnectError::Permission(format!("Not allowed to run {}", program))
+            }
+            _ => ConnectError::Other(format!("Failed to execute {}: {}", program, error)),
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::MissingConfig(code) => {
+                write!(f, "No config for {}. Press 'i' to set up.", code)
+            }
+            ConnectError::ModuleMissing => {
+                write!(f, "WireGuard module not loaded. Run: sudo modprobe wireguard")
+            }
+            ConnectError::InterfaceExists(name) => write!(
+                f,
+                "Interface {} already exists. Try disconnecting first (press 'd')",
+                name
+            ),
+            ConnectError::Dns(msg) => write!(f, "DNS configuration failed: {}", msg),
+            ConnectError::Permission(msg) => write!(f, "Permission denied: {}", msg),
+            ConnectError::Api(msg) => write!(f, "Mullvad API error: {}", msg),
+            ConnectError::Firewall(msg) => write!(f, "Firewall configuration failed: {}", msg),
+            ConnectError::Cancelled => write!(f, "Cancelled"),
+            ConnectError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+impl From<anyhow::Error> for ConnectError {
+    fn from(error: anyhow::Error) -> Self {
+        // Keep typed errors that were passed through anyhow
+        match error.downcast::<ConnectError>() {
+            Ok(error) => error,
+            Err(error) => ConnectError::Other(format!("{:#}", error)),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ConnectError {
+    fn from(error: reqwest::Error) -> Self {
+        ConnectError::Api(error.to_string())
+    }
+}
@@ -0,0 +1,33 @@
+use anyhow::Result;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::settings;
+
+fn favorites_path() -> PathBuf {
+    settings::data_dir().join("favorites.json")
+}
+
+/// Load the list of favorite server codes
+pub fn load() -> Result<Vec<String>> {
+    let path = favorites_path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Save the list of favorite server codes
+pub fn save(codes: &[String]) -> Result<()> {
+    let path = favorites_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let content = serde_json::to_string_pretty(codes)?;
+    fs::write(&path, content)?;
+
+    Ok(())
+}
@@ -0,0 +1,385 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::process::Output;
+
+use crate::error::ConnectError;
+use crate::journal::{record, Applied};
+use crate::runner::CommandRunner;
+
+/// nftables table holding the kill switch rules
+const KILL_SWITCH_TABLE: &str = "mvtui_killswitch";
+
+/// nftables table holding the DNS leak prevention rules
+const DNS_TABLE: &str = "mvtui_dns";
+
+/// When non-tunnel traffic is blocked
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KillSwitch {
+    #[default]
+    Off,
+    /// Block while connected and across reconnects, lifted on disconnect
+    On,
+    /// Block all the time, even while disconnected
+    Lockdown,
+}
+
+impl KillSwitch {
+    /// Cycle through the modes: off -> on -> lockdown
+    pub fn next(self) -> Self {
+        match self {
+            KillSwitch::Off => KillSwitch::On,
+            KillSwitch::On => KillSwitch::Lockdown,
+            KillSwitch::Lockdown => KillSwitch::Off,
+        }
+    }
+}
+
+/// Firewall configuration, part of the persistent settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FirewallSettings {
+    pub kill_switch: KillSwitch,
+    /// Let traffic to `lan_networks` bypass the kill switch
+    pub allow_lan: bool,
+    pub lan_networks: Vec<String>,
+}
+
+impl Default for FirewallSettings {
+    fn default() -> Self {
+        Self {
+            kill_switch: KillSwitch::Off,
+            allow_lan: true,
+            lan_networks: [
+                "10.0.0.0/8",
+                "172.16.0.0/12",
+                "192.168.0.0/16",
+                "169.254.0.0/16",
+                "fe80::/10",
+                "fc00::/7",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+/// The tunnel the kill switch lets traffic through
+#[derive(Debug, Clone)]
+pub struct Tunnel {
+    pub interface: String,
+    pub endpoint: SocketAddr,
+}
+
+/// Install or replace the kill switch. Without a tunnel only loopback,
+/// DHCP and (if allowed) the LAN can be reached.
+pub fn apply_kill_switch(
+    runner: &dyn CommandRunner,
+    tunnel: Option<&Tunnel>,
+    settings: &FirewallSettings,
+) -> Result<(), ConnectError> {
+    record(|j| {
+        j.applied.retain(|a| !matches!(a, Applied::KillSwitch { .. }));
+        j.applied.push(Applied::KillSwitch {
+            mode: settings.kill_switch,
+        });
+    })?;
+    run_nft(runner, &kill_switch_ruleset(tunnel, settings))
+}
+
+/// Remove the kill switch; does nothing if it is not installed
+pub fn remove_kill_switch(runner: &dyn CommandRunner) -> Result<(), ConnectError> {
+    delete_table(runner, KILL_SWITCH_TABLE)?;
+    record(|j| j.applied.retain(|a| !matches!(a, Applied::KillSwitch { .. })))
+}
+
+/// Reject DNS queries that don't go through the tunnel. Replaces any
+/// rules from an earlier connection, so reconnecting never piles them up.
+pub fn apply_dns_rules(runner: &dyn CommandRunner, interface: &str) -> Result<(), ConnectError> {
+    run_nft(runner, &dns_ruleset(interface))
+}
+
+/// Remove the DNS leak prevention rules; does nothing if they are not installed
+pub fn remove_dns_rules(runner: &dyn CommandRunner) -> Result<(), ConnectError> {
+    delete_table(runner, DNS_TABLE)
+}
+
+/// Local resolvers like the systemd-resolved stub at 127.0.0.53 forward
+/// queries themselves, so only DNS leaving on other interfaces is rejected
+fn dns_ruleset(interface: &str) -> String {
+    format!(
+        "table inet {table}\n\
+         delete table inet {table}\n\
+         table inet {table} {{\n\
+         \x20   chain output {{\n\
+         \x20       type filter hook output priority 0; policy accept;\n\
+         \x20       oif \"lo\" accept\n\
+         \x20       oifname != \"{interface}\" udp dport 53 reject\n\
+         \x20       oifname != \"{interface}\" tcp dport 53 reject with tcp reset\n\
+         \x20   }}\n\
+         }}\n",
+        table = DNS_TABLE,
+        interface = interface
+    )
+}
+
+fn kill_switch_ruleset(tunnel: Option<&Tunnel>, settings: &FirewallSettings) -> String {
+    let (lan4, lan6): (Vec<&str>, Vec<&str>) = settings
+        .lan_networks
+        .iter()
+        .map(String::as_str)
+        .partition(|net| !net.contains(':'));
+
+    let mut output = String::new();
+    let mut input = String::new();
+
+    // DHCP only from the client to the server port and back, DHCPv6 queries
+    // only to the link-scoped servers multicast group
+    let dhcp_out = [
+        "udp sport 68 udp dport 67",
+        "ip6 daddr ff02::1:2 udp sport 546 udp dport 547",
+    ];
+    let dhcp_in = ["udp sport 67 udp dport 68", "udp sport 547 udp dport 546"];
+
+    for (chain, dir, port_dir, addr_dir, dhcp) in [
+        (&mut output, "oif", "dport", "daddr", dhcp_out),
+        (&mut input, "iif", "sport", "saddr", dhcp_in),
+    ] {
+        let _ = writeln!(chain, "        {} \"lo\" accept", dir);
+        if let Some(tunnel) = tunnel {
+            let family = if tunnel.endpoint.is_ipv4() { "ip" } else { "ip6" };
+            let _ = writeln!(chain, "        {}name \"{}\" accept", dir, tunnel.interface);
+            let _ = writeln!(
+                chain,
+                "        {} {} {} udp {} {} accept",
+                family,
+                addr_dir,
+                tunnel.endpoint.ip(),
+                port_dir,
+                tunnel.endpoint.port()
+            );
+        }
+        if settings.allow_lan {
+            if !lan4.is_empty() {
+                let _ = writeln!(chain, "        ip {} {{ {} }} accept", addr_dir, lan4.join(", "));
+            }
+            if !lan6.is_empty() {
+                let _ = writeln!(chain, "        ip6 {} {{ {} }} accept", addr_dir, lan6.join(", "));
+            }
+        }
+        // Keep the link itself working so the tunnel can come back
+        for rule in dhcp {
+            let _ = writeln!(chain, "        {} accept", rule);
+        }
+        let _ = writeln!(
+            chain,
+            "        icmpv6 type {{ nd-router-solicit, nd-router-advert, nd-neighbor-solicit, nd-neighbor-advert }} accept"
+        );
+    }
+
+    format!(
+        "table inet {table}\n\
+         delete table inet {table}\n\
+         table inet {table} {{\n\
+         \x20   chain output {{\n\
+         \x20       type filter hook output priority 0; policy drop;\n\
+         {output}\
+         \x20   }}\n\
+         \x20   chain input {{\n\
+         \x20       type filter hook input priority 0; policy drop;\n\
+         {input}\
+         \x20   }}\n\
+         }}\n",
+        table = KILL_SWITCH_TABLE,
+        output = output,
+        input = input
+    )
+}
+
+/// Delete one of our tables. Declaring it first makes the delete succeed
+/// if it does not exist; without nft there is nothing to delete either.
+fn delete_table(runner: &dyn CommandRunner, table: &str) -> Result<(), ConnectError> {
+    match nft(runner, &format!("table inet {table}\ndelete table inet {table}\n", table = table)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => check_nft(result),
+    }
+}
+
+fn run_nft(runner: &dyn CommandRunner, ruleset: &str) -> Result<(), ConnectError> {
+    check_nft(nft(runner, ruleset))
+}
+
+fn check_nft(result: io::Result<Output>) -> Result<(), ConnectError> {
+    let output = result.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => ConnectError::Firewall("nft not found. Is nftables installed?".to_string()),
+        _ => ConnectError::Firewall(format!("Failed to run nft: {}", e)),
+    })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(ConnectError::Firewall(stderr.trim().to_string()));
+    }
+
+    Ok(())
+}
+
+/// Load a ruleset with `nft -f -`; the whole file is applied atomically
+fn nft(runner: &dyn CommandRunner, ruleset: &str) -> io::Result<Output> {
+    runner.run("nft", &["-f", "-"], Some(ruleset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal;
+    use crate::runner::FakeRunner;
+    use crate::testing::lock_journal;
+
+    fn tunnel(endpoint: &str) -> Tunnel {
+        Tunnel {
+            interface: "se-mma-wg-001".to_string(),
+            endpoint: endpoint.parse().unwrap(),
+        }
+    }
+
+    fn lan(networks: &[&str]) -> FirewallSettings {
+        FirewallSettings {
+            lan_networks: networks.iter().map(|n| n.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dns_outside_the_tunnel_is_rejected() {
+        // Loopback comes first so the systemd-resolved stub keeps working
+        assert_eq!(
+            dns_ruleset("se-mma-wg-001"),
+            "table inet mvtui_dns\n\
+             delete table inet mvtui_dns\n\
+             table inet mvtui_dns {\n\
+             \x20   chain output {\n\
+             \x20       type filter hook output priority 0; policy accept;\n\
+             \x20       oif \"lo\" accept\n\
+             \x20       oifname != \"se-mma-wg-001\" udp dport 53 reject\n\
+             \x20       oifname != \"se-mma-wg-001\" tcp dport 53 reject with tcp reset\n\
+             \x20   }\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn without_tunnel_only_local_traffic_passes() {
+        let settings = lan(&["192.168.0.0/16", "fe80::/10"]);
+
+        assert_eq!(
+            kill_switch_ruleset(None, &settings),
+            "table inet mvtui_killswitch\n\
+             delete table inet mvtui_killswitch\n\
+             table inet mvtui_killswitch {\n\
+             \x20   chain output {\n\
+             \x20       type filter hook output priority 0; policy drop;\n\
+             \x20       oif \"lo\" accept\n\
+             \x20       ip daddr { 192.168.0.0/16 } accept\n\
+             \x20       ip6 daddr { fe80::/10 } accept\n\
+             \x20       udp sport 68 udp dport 67 accept\n\
+             \x20       ip6 daddr ff02::1:2 udp sport 546 udp dport 547 accept\n\
+             \x20       icmpv6 type { nd-router-solicit, nd-router-advert, nd-neighbor-solicit, nd-neighbor-advert } accept\n\
+             \x20   }\n\
+             \x20   chain input {\n\
+             \x20       type filter hook input priority 0; policy drop;\n\
+             \x20       iif \"lo\" accept\n\
+             \x20       ip saddr { 192.168.0.0/16 } accept\n\
+             \x20       ip6 saddr { fe80::/10 } accept\n\
+             \x20       udp sport 67 udp dport 68 accept\n\
+             \x20       udp sport 547 udp dport 546 accept\n\
+             \x20       icmpv6 type { nd-router-solicit, nd-router-advert, nd-neighbor-solicit, nd-neighbor-advert } accept\n\
+             \x20   }\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn ipv4_endpoint_is_let_through() {
+        let ruleset = kill_switch_ruleset(Some(&tunnel("185.65.134.2:51820")), &lan(&[]));
+
+        assert!(ruleset.contains("        oifname \"se-mma-wg-001\" accept\n"));
+        assert!(ruleset.contains("        iifname \"se-mma-wg-001\" accept\n"));
+        assert!(ruleset.contains("        ip daddr 185.65.134.2 udp dport 51820 accept\n"));
+        assert!(ruleset.contains("        ip saddr 185.65.134.2 udp sport 51820 accept\n"));
+    }
+
+    #[test]
+    fn ipv6_endpoint_is_let_through() {
+        let ruleset = kill_switch_ruleset(Some(&tunnel("[2a03:1b20:3:f011::a02f]:51820")), &lan(&[]));
+
+        assert!(ruleset.contains("        ip6 daddr 2a03:1b20:3:f011::a02f udp dport 51820 accept\n"));
+        assert!(ruleset.contains("        ip6 saddr 2a03:1b20:3:f011::a02f udp sport 51820 accept\n"));
+        assert!(!ruleset.contains("ip daddr"));
+    }
+
+    #[test]
+    fn lan_is_blocked_unless_allowed() {
+        let settings = FirewallSettings {
+            allow_lan: false,
+            ..Default::default()
+        };
+
+        let ruleset = kill_switch_ruleset(Some(&tunnel("185.65.134.2:51820")), &settings);
+
+        assert!(!ruleset.contains("192.168.0.0/16"));
+        assert!(!ruleset.contains("fe80::/10"));
+        // DHCP and neighbor discovery keep working
+        assert!(ruleset.contains("        udp sport 68 udp dport 67 accept\n"));
+        assert!(ruleset.contains("        ip6 daddr ff02::1:2 udp sport 546 udp dport 547 accept\n"));
+        assert!(ruleset.contains("        udp sport 67 udp dport 68 accept\n"));
+    }
+
+    #[test]
+    fn empty_lan_family_is_skipped() {
+        let ruleset = kill_switch_ruleset(None, &lan(&["10.0.0.0/8", "172.16.0.0/12"]));
+        assert!(ruleset.contains("        ip daddr { 10.0.0.0/8, 172.16.0.0/12 } accept\n"));
+        assert!(!ruleset.contains("ip6 daddr {"));
+        assert!(!ruleset.contains("ip6 saddr {"));
+
+        let ruleset = kill_switch_ruleset(None, &lan(&["fc00::/7"]));
+        assert!(ruleset.contains("        ip6 saddr { fc00::/7 } accept\n"));
+        assert!(!ruleset.contains("ip daddr"));
+        assert!(!ruleset.contains("ip saddr"));
+    }
+
+    #[test]
+    fn kill_switch_is_recorded_until_removed() {
+        let _journal = lock_journal();
+        let runner = FakeRunner::new();
+        let mut settings = lan(&[]);
+
+        settings.kill_switch = KillSwitch::Lockdown;
+        apply_kill_switch(&runner, None, &settings).unwrap();
+        settings.kill_switch = KillSwitch::On;
+        apply_kill_switch(&runner, Some(&tunnel("185.213.154.68:51820")), &settings).unwrap();
+
+        // Re-applying replaces the entry
+        let journal = journal::load().unwrap();
+        assert_eq!(journal.applied, [Applied::KillSwitch { mode: KillSwitch::On }]);
+
+        remove_kill_switch(&runner).unwrap();
+        assert!(journal::load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failed_removal_stays_recorded() {
+        let _journal = lock_journal();
+        let runner = FakeRunner::new();
+        let settings = FirewallSettings {
+            kill_switch: KillSwitch::Lockdown,
+            ..Default::default()
+        };
+        apply_kill_switch(&runner, None, &settings).unwrap();
+        runner.respond("nft", 1, "", "Error: Operation not permitted\n");
+
+        assert!(remove_kill_switch(&runner).is_err());
+        assert_eq!(journal::load().unwrap().kill_switch(), Some(KillSwitch::Lockdown));
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use chrono::TimeZone;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::settings;
+
+/// Oldest entries are dropped beyond this many
+pub const MAX_ENTRIES: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Connect,
+    Disconnect,
+}
+
+/// A single connect or disconnect attempt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub action: Action,
+    pub code: String,
+    /// How long the connection lasted, in seconds (disconnects only)
+    pub duration: Option<u64>,
+    /// Error message if the attempt failed
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Local time of the entry, e.g. "2024-05-01 18:30:12"
+    pub fn local_time(&self) -> String {
+        format_time(self.timestamp, "%Y-%m-%d %H:%M:%S")
+    }
+}
+
+/// Format a unix timestamp in local time
+#[cfg(not(test))]
+pub fn format_time(timestamp: u64, format: &str) -> String {
+    format_in(chrono::Local, timestamp, format)
+}
+
+/// Tests render times in UTC, so they don't depend on the machine's time zone
+#[cfg(test)]
+pub fn format_time(timestamp: u64, format: &str) -> String {
+    format_in(chrono::Utc, timestamp, format)
+}
+
+fn format_in<Tz: TimeZone>(tz: Tz, timestamp: u64, format: &str) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    tz.timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map(|t| t.format(format).to_string())
+        .unwrap_or_default()
+}
+
+fn history_path() -> PathBuf {
+    settings::data_dir().join("history.json")
+}
+
+/// Load the connection history, oldest entry first
+pub fn load() -> Result<Vec<HistoryEntry>> {
+    let path = history_path();
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Save the connection history, keeping only the most recent entries
+pub fn save(entries: &[HistoryEntry]) -> Result<()> {
+    let path = history_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let start = entries.len().saturating_sub(MAX_ENTRIES);
+    let content = serde_json::to_string_pretty(&entries[start..])?;
+    fs::write(&path, content)?;
+
+    Ok(())
+}
+
+/// Server of the most recent successful connection
+pub fn last_connected(entries: &[HistoryEntry]) -> Option<&str> {
+    entries
+        .iter()
+        .rev()
+        .find(|e| e.action == Action::Connect && e.succeeded())
+        .map(|e| e.code.as_str())
+}
+
+/// When the most recent successful connection to `code` was made
+pub fn connected_since(entries: &[HistoryEntry], code: &str) -> Option<u64> {
+    entries
+        .iter()
+        .rev()
+        .find(|e| e.action == Action::Connect && e.succeeded() && e.code == code)
+        .map(|e| e.timestamp)
+}
@@ -0,0 +1,170 @@
+use anyhow::Result;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+
+use crate::dns::DnsBackend;
+use crate::error::ConnectError;
+use crate::firewall::KillSwitch;
+use crate::settings;
+use crate::wireguard::Backend;
+
+/// Operation that was running when the journal was last written
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Connect,
+    Disconnect,
+}
+
+/// A piece of system state mvtui changed and has to undo
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Applied {
+    /// Tunnel interface, with the backend that brought it up
+    Interface { code: String, backend: Backend },
+    /// Resolver settings pointing at the tunnel, with the backend that made them
+    Resolver { interface: String, dns: DnsBackend },
+    /// The DNS leak prevention nftables table
+    DnsRules,
+    /// The kill switch nftables table, with the mode it was applied in
+    KillSwitch { mode: KillSwitch },
+}
+
+/// What was applied to the system, written before each change is made so
+/// that state left behind by a crash can be found and rolled back
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Journal {
+    /// Set while connecting or disconnecting; still set at startup means mvtui died midway
+    pub in_progress: Option<Operation>,
+    pub applied: Vec<Applied>,
+}
+
+impl Journal {
+    /// Tunnel interface recorded in the journal, if any
+    pub fn interface(&self) -> Option<(&str, Backend)> {
+        self.applied.iter().find_map(|applied| match applied {
+            Applied::Interface { code, backend } => Some((code.as_str(), *backend)),
+            _ => None,
+        })
+    }
+
+    /// DNS backend that configured the resolver for an interface, if it did
+    pub fn resolver(&self, interface: &str) -> Option<DnsBackend> {
+        self.applied.iter().find_map(|applied| match applied {
+            Applied::Resolver { interface: i, dns } if i == interface => Some(*dns),
+            _ => None,
+        })
+    }
+
+    /// Mode of the kill switch recorded in the journal, if it is applied
+    pub fn kill_switch(&self) -> Option<KillSwitch> {
+        self.applied.iter().find_map(|applied| match applied {
+            Applied::KillSwitch { mode } => Some(*mode),
+            _ => None,
+        })
+    }
+
+    /// Forget the tunnel and everything that came with it. The kill switch
+    /// is not tied to a tunnel and stays recorded.
+    pub fn clear_tunnel(&mut self) {
+        self.in_progress = None;
+        self.applied.retain(|applied| matches!(applied, Applied::KillSwitch { .. }));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_progress.is_none() && self.applied.is_empty()
+    }
+}
+
+fn journal_path() -> PathBuf {
+    settings::state_dir().join("state.json")
+}
+
+fn lock_path() -> PathBuf {
+    settings::state_dir().join("lock")
+}
+
+/// Held while a connect, disconnect or kill switch change is in flight. The
+/// kernel releases it when the holder dies, so a journal found in progress while
+/// the lock is free was left behind by a crash rather than written by a live mvtui.
+pub struct Lock {
+    _flock: Flock<File>,
+}
+
+fn flock(arg: FlockArg) -> io::Result<Option<Lock>> {
+    let path = lock_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+
+    match Flock::lock(file, arg) {
+        Ok(flock) => Ok(Some(Lock { _flock: flock })),
+        Err((_, Errno::EWOULDBLOCK)) => Ok(None),
+        Err((_, errno)) => Err(errno.into()),
+    }
+}
+
+fn lock_failed(e: io::Error) -> ConnectError {
+    ConnectError::Other(format!("Failed to lock state journal: {}", e))
+}
+
+/// Take the lock, waiting for another mvtui to finish first
+pub fn lock() -> std::result::Result<Lock, ConnectError> {
+    match flock(FlockArg::LockExclusive) {
+        Ok(Some(lock)) => Ok(lock),
+        Ok(None) => unreachable!("a blocking flock waits instead"),
+        Err(e) => Err(lock_failed(e)),
+    }
+}
+
+/// Take the lock if no other mvtui holds it
+pub fn try_lock() -> std::result::Result<Option<Lock>, ConnectError> {
+    flock(FlockArg::LockExclusiveNonblock).map_err(lock_failed)
+}
+
+pub fn load() -> Result<Journal> {
+    let path = journal_path();
+    if !path.exists() {
+        return Ok(Journal::default());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub fn save(journal: &Journal) -> Result<()> {
+    let path = journal_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Write and rename so a crash never leaves a truncated journal
+    let content = serde_json::to_string_pretty(journal)?;
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, &path)?;
+
+    Ok(())
+}
+
+/// Load, change and save the journal in one step
+pub fn update(change: impl FnOnce(&mut Journal)) -> Result<()> {
+    let mut journal = load()?;
+    change(&mut journal);
+    save(&journal)
+}
+
+/// Write a change to the journal while changing the system
+pub fn record(change: impl FnOnce(&mut Journal)) -> std::result::Result<(), ConnectError> {
+    update(change).map_err(|e| ConnectError::Other(format!("Failed to write state journal: {}", e)))
+}
@@ -0,0 +1,149 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::runner::CommandRunner;
+use crate::server::Server;
+use crate::settings;
+
+/// Maximum number of pings in flight at once
+const MAX_CONCURRENT_PROBES: usize = 32;
+
+/// Seconds to wait for an echo reply
+const PROBE_TIMEOUT_SECS: &str = "2";
+
+/// Result of the most recent probe of a relay
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Latency {
+    /// Round trip time in milliseconds, `None` if the relay did not answer
+    pub ms: Option<f64>,
+    pub measured_at: u64,
+}
+
+/// Latest latency per server code
+pub type LatencyCache = HashMap<String, Latency>;
+
+fn latency_path() -> PathBuf {
+    settings::data_dir().join("latency.json")
+}
+
+pub fn load() -> Result<LatencyCache> {
+    let path = latency_path();
+    if !path.exists() {
+        return Ok(LatencyCache::new());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+pub fn save(cache: &LatencyCache) -> Result<()> {
+    let path = latency_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let content = serde_json::to_string_pretty(cache)?;
+    fs::write(&path, content)?;
+
+    Ok(())
+}
+
+/// Ping every server concurrently, with at most `MAX_CONCURRENT_PROBES` in flight.
+/// Returns the round trip time in milliseconds per server code.
+pub async fn probe_all(runner: Arc<dyn CommandRunner>, servers: &[Server]) -> HashMap<String, Option<f64>> {
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_PROBES));
+    let mut tasks = JoinSet::new();
+
+    for server in servers {
+        let semaphore = semaphore.clone();
+        let runner = runner.clone();
+        let code = server.code.clone();
+        let addr = server.ipv4_addr.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.ok()?;
+            Some((code, probe(runner, &addr).await))
+        });
+    }
+
+    let mut results = HashMap::new();
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok(Some((code, ms))) = joined {
+            results.insert(code, ms);
+        }
+    }
+    results
+}
+
+/// Send a single ICMP echo request and return the round trip time in milliseconds
+pub async fn probe(runner: Arc<dyn CommandRunner>, addr: &str) -> Option<f64> {
+    // ping blocks for up to the timeout, so keep it off the async workers
+    let addr = addr.to_string();
+    let output = tokio::task::spawn_blocking(move || {
+        runner.output("ping", &["-n", "-c", "1", "-W", PROBE_TIMEOUT_SECS, &addr])
+    })
+    .await
+    .ok()?
+    .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    // Format: "64 bytes from 1.2.3.4: icmp_seq=1 ttl=57 time=23.4 ms"
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("time="))
+        .and_then(|ms| ms.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::FakeRunner;
+    use crate::testing::sample_servers;
+
+    fn reply(ms: &str) -> String {
+        format!(
+            "PING 10.64.0.1 (10.64.0.1) 56(84) bytes of data.\n\
+             64 bytes from 10.64.0.1: icmp_seq=1 ttl=64 time={} ms\n",
+            ms
+        )
+    }
+
+    #[tokio::test]
+    async fn probe_reads_the_round_trip_time() {
+        let runner = Arc::new(FakeRunner::new());
+        runner
+            .respond("ping", 0, &reply("23.4"), "")
+            .respond("ping", 1, "1 packets transmitted, 0 received\n", "");
+
+        assert_eq!(probe(runner.clone(), "10.64.0.1").await, Some(23.4));
+        assert_eq!(probe(runner.clone(), "10.64.0.1").await, None);
+        assert_eq!(runner.calls()[0], "ping -n -c 1 -W 2 10.64.0.1");
+    }
+
+    #[tokio::test]
+    async fn probe_all_measures_every_server() {
+        let runner = Arc::new(FakeRunner::new());
+        runner
+            .respond("ping -n -c 1 -W 2 185.209.196.71", 0, &reply("31.5"), "")
+            .respond("ping -n -c 1 -W 2 193.32.249.69", 0, &reply("12"), "")
+            .fail_spawn("ping -n -c 1 -W 2 193.32.248.66", std::io::ErrorKind::NotFound);
+
+        let results = probe_all(runner.clone(), &sample_servers()).await;
+
+        assert_eq!(results.len(), 5);
+        assert_eq!(results["de-fra-wg-001"], Some(31.5));
+        assert_eq!(results["nl-ams-wg-101"], Some(12.0));
+        assert_eq!(results["de-ber-wg-001"], None);
+        assert_eq!(results["de-fra-wg-002"], None);
+        assert_eq!(runner.calls().len(), 5);
+    }
+}
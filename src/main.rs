@@ -1,31 +1,70 @@
 mod api;
 mod app;
+mod cli;
 mod config;
+mod dns;
+mod error;
+mod favorites;
+mod firewall;
+mod history;
+mod journal;
+mod latency;
+mod netlink;
+mod runner;
+mod search;
+mod select;
 mod server;
+mod settings;
+mod stats;
+mod task;
+#[cfg(test)]
+mod testing;
 mod ui;
+mod watchdog;
 mod wireguard;
 
 use std::io;
 use std::time::Duration;
 
 use anyhow::Result;
+use clap::Parser;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+        KeyModifiers,
+    },
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{backend::CrosstermBackend, Terminal};
 
 use app::{App, InputMode, View};
+use cli::Cli;
+use error::ConnectError;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     // Check if running as root
     if !nix::unistd::Uid::effective().is_root() {
         eprintln!("This program must be run as root (use sudo)");
         std::process::exit(1);
     }
 
+    // Run a subcommand without the TUI if one was given
+    if let Some(command) = cli.command {
+        if let Err(err) = cli::run(command).await {
+            eprintln!("Error: {}", err);
+            let code = err
+                .downcast_ref::<ConnectError>()
+                .map(ConnectError::exit_code)
+                .unwrap_or(1);
+            std::process::exit(code);
+        }
+        return Ok(());
+    }
+
     // Setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -69,76 +108,297 @@ async fn run_app<B: ratatui::backend::Backend>(
         // Draw UI
         terminal.draw(|f| ui::draw(f, app))?;
 
-        // Handle events with timeout for periodic status updates
-        if event::poll(Duration::from_millis(250))? {
+        // Handle events with timeout for periodic status updates,
+        // more often while a task runs so the spinner turns
+        let timeout = if app.task.is_some() { 100 } else { 250 };
+        if event::poll(Duration::from_millis(timeout))? {
             if let Event::Key(key) = event::read()? {
                 // Only handle key press events, not release
-                if key.kind != KeyEventKind::Press {
-                    continue;
-                }
-
-                match app.input_mode {
-                    InputMode::Normal => match key.code {
-                        KeyCode::Char('q') => {
-                            app.should_quit = true;
-                        }
-                        KeyCode::Char('j') | KeyCode::Down => {
-                            app.next();
-                        }
-                        KeyCode::Char('k') | KeyCode::Up => {
-                            app.previous();
-                        }
-                        KeyCode::Enter => {
-                            app.select();
-                        }
-                        KeyCode::Esc | KeyCode::Char('h') | KeyCode::Left => {
-                            app.back();
-                        }
-                        KeyCode::Char('l') | KeyCode::Right => {
-                            app.select();
-                        }
-                        KeyCode::Char('r') => {
-                            app.refresh_servers().await?;
-                        }
-                        KeyCode::Char('d') => {
-                            app.disconnect();
-                        }
-                        KeyCode::Char('i') => {
-                            app.enter_setup();
-                        }
-                        KeyCode::Char('e') => {
-                            app.toggle_autostart();
-                        }
-                        KeyCode::Char('s') => {
-                            app.update_status();
-                        }
-                        _ => {}
-                    },
-                    InputMode::AccountInput => match key.code {
-                        KeyCode::Enter => {
-                            if let Err(e) = app.submit_setup().await {
-                                app.error = Some(format!("Setup failed: {}", e));
-                            }
-                        }
-                        KeyCode::Char(c) => {
-                            app.input_buffer.push(c);
-                        }
-                        KeyCode::Backspace => {
-                            app.input_buffer.pop();
-                        }
-                        KeyCode::Esc => {
-                            app.input_mode = InputMode::Normal;
-                            app.view = View::Countries;
-                            app.input_buffer.clear();
-                        }
-                        _ => {}
-                    },
+                if key.kind == KeyEventKind::Press {
+                    handle_key(app, key);
                 }
             }
         }
 
+        app.tick();
+
         if app.should_quit {
+            // Don't leave a half-finished connect behind
+            app.cancel_task();
+            app.wait().await;
             return Ok(());
         }
     }
 }
+
+/// Act on a key press. Long operations only start a task, so this returns right away.
+fn handle_key(app: &mut App, key: KeyEvent) {
+    // Esc and Ctrl-C stop a running task before they do anything else
+    let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+    if app.task.is_some() && (ctrl_c || key.code == KeyCode::Esc) {
+        app.cancel_task();
+        return;
+    }
+    if ctrl_c {
+        app.should_quit = true;
+        return;
+    }
+
+    match app.input_mode {
+        InputMode::Normal => match key.code {
+            KeyCode::Char('q') => {
+                app.should_quit = true;
+            }
+            KeyCode::Char('j') | KeyCode::Down => {
+                app.next();
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                app.previous();
+            }
+            KeyCode::Enter => {
+                app.select();
+            }
+            KeyCode::Esc | KeyCode::Char('h') | KeyCode::Left => {
+                app.back();
+            }
+            KeyCode::Char('l') | KeyCode::Right => {
+                app.select();
+            }
+            KeyCode::Char('r') => {
+                app.refresh_servers();
+            }
+            KeyCode::Char('d') => {
+                app.disconnect();
+            }
+            KeyCode::Char('i') => {
+                app.enter_setup();
+            }
+            KeyCode::Char('e') => {
+                app.toggle_autostart();
+            }
+            KeyCode::Char('s') => {
+                app.update_status();
+            }
+            KeyCode::Char('o') => {
+                app.cycle_ownership_filter();
+            }
+            KeyCode::Char('v') => {
+                app.cycle_provider_filter();
+            }
+            KeyCode::Char('/') => {
+                app.enter_search();
+            }
+            KeyCode::Char('f') => {
+                app.toggle_favorite();
+            }
+            KeyCode::Char('F') => {
+                app.show_favorites();
+            }
+            KeyCode::Char('H') => {
+                app.show_history();
+            }
+            KeyCode::Char('c') => {
+                app.reconnect_last();
+            }
+            KeyCode::Char('p') => {
+                app.measure_latency();
+            }
+            KeyCode::Char('S') => {
+                app.toggle_latency_sort();
+            }
+            KeyCode::Char('b') => {
+                app.connect_best_here();
+            }
+            KeyCode::Char('K') => {
+                app.cycle_kill_switch();
+            }
+            KeyCode::Char('g') => {
+                app.show_stats();
+            }
+            KeyCode::Char('D') => {
+                app.show_dns();
+            }
+            KeyCode::Char('x') => {
+                app.apply_remedy();
+            }
+            _ => {}
+        },
+        InputMode::AccountInput => match key.code {
+            KeyCode::Enter => {
+                app.submit_setup();
+            }
+            KeyCode::Char(c) => {
+                app.input_buffer.push(c);
+            }
+            KeyCode::Backspace => {
+                app.input_buffer.pop();
+            }
+            KeyCode::Esc => {
+                app.input_mode = InputMode::Normal;
+                app.view = View::Countries;
+                app.input_buffer.clear();
+            }
+            _ => {}
+        },
+        InputMode::Search => match key.code {
+            KeyCode::Enter => {
+                // Keep the results on screen so the new status is visible
+                app.input_mode = InputMode::Normal;
+                app.select();
+            }
+            KeyCode::Char(c) => {
+                app.search_query.push(c);
+                app.update_search();
+            }
+            KeyCode::Backspace => {
+                app.search_query.pop();
+                app.update_search();
+            }
+            KeyCode::Down => {
+                app.next();
+            }
+            KeyCode::Up => {
+                app.previous();
+            }
+            KeyCode::Esc => {
+                app.exit_search();
+            }
+            _ => {}
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(app: &mut App, keys: &[KeyCode]) {
+        for &code in keys {
+            handle_key(app, KeyEvent::new(code, KeyModifiers::NONE));
+        }
+    }
+
+    fn type_text(app: &mut App, text: &str) {
+        for c in text.chars() {
+            press(app, &[KeyCode::Char(c)]);
+        }
+    }
+
+    #[test]
+    fn navigates_down_to_servers_and_back() {
+        let mut app = testing::sample_app();
+
+        press(&mut app, &[KeyCode::Char('j'), KeyCode::Char('k'), KeyCode::Enter]);
+        assert_eq!(app.view, View::Cities);
+        assert_eq!(app.selected_country.as_deref(), Some("Germany"));
+
+        press(&mut app, &[KeyCode::Down, KeyCode::Char('l')]);
+        assert_eq!(app.view, View::Servers);
+        assert_eq!(app.selected_city.as_deref(), Some("Frankfurt"));
+        assert_eq!(app.city_servers.len(), 3);
+
+        press(&mut app, &[KeyCode::Esc, KeyCode::Char('h')]);
+        assert_eq!(app.view, View::Countries);
+        assert_eq!(app.selected_country, None);
+    }
+
+    #[test]
+    fn search_filters_as_you_type_and_esc_returns() {
+        let mut app = testing::sample_app();
+        press(&mut app, &[KeyCode::Enter, KeyCode::Char('/')]);
+        assert_eq!(app.input_mode, InputMode::Search);
+
+        type_text(&mut app, "amsx");
+        assert!(app.search_results.is_empty());
+
+        press(&mut app, &[KeyCode::Backspace]);
+        assert_eq!(app.search_query, "ams");
+        assert_eq!(app.search_results.len(), 1);
+
+        press(&mut app, &[KeyCode::Esc]);
+        assert_eq!(app.view, View::Cities);
+        assert_eq!(app.input_mode, InputMode::Normal);
+        assert!(app.search_query.is_empty());
+    }
+
+    #[test]
+    fn letters_go_to_the_query_not_the_commands() {
+        let mut app = testing::sample_app();
+        press(&mut app, &[KeyCode::Char('/')]);
+        type_text(&mut app, "qd");
+
+        assert!(!app.should_quit);
+        assert_eq!(app.search_query, "qd");
+        assert_eq!(app.view, View::Search);
+    }
+
+    #[test]
+    fn setup_input_is_cleared_on_esc() {
+        let mut app = testing::sample_app();
+        press(&mut app, &[KeyCode::Char('i')]);
+        assert_eq!(app.view, View::Setup);
+
+        type_text(&mut app, "12345");
+        press(&mut app, &[KeyCode::Backspace]);
+        assert_eq!(app.input_buffer, "1234");
+
+        press(&mut app, &[KeyCode::Esc]);
+        assert_eq!(app.view, View::Countries);
+        assert_eq!(app.input_mode, InputMode::Normal);
+        assert!(app.input_buffer.is_empty());
+    }
+
+    #[test]
+    fn empty_account_is_rejected() {
+        let mut app = testing::sample_app();
+        press(&mut app, &[KeyCode::Char('i'), KeyCode::Enter]);
+
+        assert_eq!(app.view, View::Setup);
+        assert_eq!(app.error.as_deref(), Some("Account number cannot be empty"));
+    }
+
+    #[test]
+    fn view_keys_switch_views() {
+        let mut app = testing::sample_app();
+        for (key, view) in [
+            ('F', View::Favorites),
+            ('H', View::History),
+            ('g', View::Stats),
+            ('D', View::Dns),
+        ] {
+            press(&mut app, &[KeyCode::Char(key)]);
+            assert_eq!(app.view, view);
+            press(&mut app, &[KeyCode::Esc]);
+            assert_eq!(app.view, View::Countries);
+        }
+    }
+
+    #[test]
+    fn q_quits() {
+        let mut app = testing::sample_app();
+        press(&mut app, &[KeyCode::Char('q')]);
+        assert!(app.should_quit);
+    }
+
+    #[test]
+    fn ctrl_c_quits_instead_of_reconnecting() {
+        let mut app = testing::sample_app();
+        handle_key(&mut app, KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL));
+
+        assert!(app.should_quit);
+        assert!(app.task.is_none());
+    }
+
+    #[tokio::test]
+    async fn esc_cancels_refresh_and_stays_in_view() {
+        let mut app = testing::sample_app();
+        app.settings.api_url = Some("http://127.0.0.1:9".to_string());
+        press(&mut app, &[KeyCode::Enter, KeyCode::Char('r')]);
+        assert!(app.task.is_some());
+
+        press(&mut app, &[KeyCode::Esc]);
+        assert!(app.task.is_none());
+        assert_eq!(app.view, View::Cities);
+        assert_eq!(app.error.as_deref(), Some("Cancelled"));
+    }
+}
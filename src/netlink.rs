@@ -0,0 +1,937 @@
+//! Minimal rtnetlink and WireGuard generic netlink client.
+//!
+//! Only the handful of requests needed to bring a WireGuard tunnel up and
+//! down are implemented; messages are built by hand from the kernel UAPI
+//! layouts (linux/rtnetlink.h, linux/fib_rules.h, linux/wireguard.h).
+
+use nix::errno::Errno;
+use nix::sys::socket::SockProtocol;
+#[cfg(not(test))]
+use nix::sys::socket::{
+    bind, recv, send, socket, AddressFamily, MsgFlags, NetlinkAddr, SockFlag, SockType,
+};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(not(test))]
+use std::os::fd::{AsRawFd, OwnedFd};
+
+// linux/netlink.h
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLA_F_NESTED: u16 = 0x8000;
+const NLMSG_HDRLEN: usize = 16;
+
+// linux/rtnetlink.h
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_OIF: u16 = 4;
+const RTA_TABLE: u16 = 15;
+const RTPROT_BOOT: u8 = 3;
+const RTN_UNICAST: u8 = 1;
+const RT_TABLE_MAIN: u32 = 254;
+const IFF_UP: u32 = 0x1;
+
+// linux/fib_rules.h
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 0x2;
+
+// linux/genetlink.h
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+// linux/wireguard.h
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_SET_DEVICE: u8 = 1;
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_FLAGS: u16 = 5;
+const WGDEVICE_A_FWMARK: u16 = 7;
+const WGDEVICE_A_PEERS: u16 = 8;
+const WGDEVICE_F_REPLACE_PEERS: u32 = 1;
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 2;
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+
+const AF_INET: u8 = libc::AF_INET as u8;
+const AF_INET6: u8 = libc::AF_INET6 as u8;
+
+/// Errors returned by netlink requests
+#[derive(Debug)]
+pub enum NetlinkError {
+    /// Socket-level failure talking to the kernel
+    Io(Errno),
+    /// The kernel rejected the request
+    Kernel(Errno),
+    /// A generic netlink family (e.g. the WireGuard module) is not available
+    FamilyNotFound(&'static str),
+}
+
+impl fmt::Display for NetlinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetlinkError::Io(errno) => write!(f, "netlink socket error: {}", errno.desc()),
+            NetlinkError::Kernel(errno) => write!(f, "{}", errno.desc()),
+            NetlinkError::FamilyNotFound(name) => {
+                write!(f, "generic netlink family '{}' not found", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetlinkError {}
+
+pub type Result<T> = std::result::Result<T, NetlinkError>;
+
+/// A peer to configure on a WireGuard device
+pub struct Peer {
+    pub public_key: [u8; 32],
+    pub endpoint: SocketAddr,
+    /// Allowed IPs as (address, prefix length)
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+}
+
+/// A netlink message under construction
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(ty: u16, flags: u16) -> Self {
+        let mut buf = vec![0u8; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&ty.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        Self { buf }
+    }
+
+    /// Append a fixed-size family header (ifinfomsg, rtmsg, genlmsghdr, ...)
+    fn header(mut self, bytes: &[u8]) -> Self {
+        self.buf.extend_from_slice(bytes);
+        self.align();
+        self
+    }
+
+    fn attr(&mut self, ty: u16, data: &[u8]) {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.align();
+    }
+
+    fn attr_u16(&mut self, ty: u16, value: u16) {
+        self.attr(ty, &value.to_ne_bytes());
+    }
+
+    fn attr_u32(&mut self, ty: u16, value: u32) {
+        self.attr(ty, &value.to_ne_bytes());
+    }
+
+    fn attr_str(&mut self, ty: u16, value: &str) {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(ty, &data);
+    }
+
+    /// Start a nested attribute; returns its offset for `end_nested`
+    fn begin_nested(&mut self, ty: u16) -> usize {
+        let start = self.buf.len();
+        self.buf.extend_from_slice(&0u16.to_ne_bytes());
+        self.buf.extend_from_slice(&(ty | NLA_F_NESTED).to_ne_bytes());
+        start
+    }
+
+    fn end_nested(&mut self, start: usize) {
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn align(&mut self) {
+        self.buf.resize(self.buf.len().next_multiple_of(4), 0);
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+struct Socket {
+    #[cfg(not(test))]
+    fd: OwnedFd,
+    /// Replies of the kernel stand-in not received yet
+    #[cfg(test)]
+    pending: Vec<u8>,
+    seq: u32,
+}
+
+impl Socket {
+    #[cfg(not(test))]
+    fn open(protocol: SockProtocol) -> Result<Self> {
+        let fd = socket(
+            AddressFamily::Netlink,
+            SockType::Raw,
+            SockFlag::SOCK_CLOEXEC,
+            protocol,
+        )
+        .map_err(NetlinkError::Io)?;
+        bind(fd.as_raw_fd(), &NetlinkAddr::new(0, 0)).map_err(NetlinkError::Io)?;
+        Ok(Self { fd, seq: 0 })
+    }
+
+    #[cfg(not(test))]
+    fn send(&mut self, request: &[u8]) -> Result<()> {
+        send(self.fd.as_raw_fd(), request, MsgFlags::empty())
+            .map(|_| ())
+            .map_err(NetlinkError::Io)
+    }
+
+    #[cfg(not(test))]
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        recv(self.fd.as_raw_fd(), buf, MsgFlags::empty()).map_err(NetlinkError::Io)
+    }
+
+    /// Tests talk to `kernel` instead of the real thing
+    #[cfg(test)]
+    fn open(_protocol: SockProtocol) -> Result<Self> {
+        Ok(Self {
+            pending: Vec::new(),
+            seq: 0,
+        })
+    }
+
+    #[cfg(test)]
+    fn send(&mut self, request: &[u8]) -> Result<()> {
+        self.pending.extend(kernel::handle(request));
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.pending.len().min(buf.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+
+    /// Send a request and wait for its acknowledgement.
+    /// Returns the payloads of any data messages received before the ack.
+    fn request(&mut self, message: Message) -> Result<Vec<Vec<u8>>> {
+        self.seq += 1;
+        let request = message.finish(self.seq);
+        self.send(&request)?;
+
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; 32768];
+        loop {
+            let len = self.recv(&mut buf)?;
+
+            let mut offset = 0;
+            while offset + NLMSG_HDRLEN <= len {
+                let msg_len = read_u32(&buf, offset) as usize;
+                let ty = read_u16(&buf, offset + 4);
+                let seq = read_u32(&buf, offset + 8);
+                if msg_len < NLMSG_HDRLEN || offset + msg_len > len {
+                    return Err(NetlinkError::Io(Errno::EBADMSG));
+                }
+
+                if seq == self.seq {
+                    let payload = &buf[offset + NLMSG_HDRLEN..offset + msg_len];
+                    match ty {
+                        NLMSG_ERROR => {
+                            let code = i32::from_ne_bytes(payload[0..4].try_into().unwrap());
+                            return if code == 0 {
+                                Ok(replies)
+                            } else {
+                                Err(NetlinkError::Kernel(Errno::from_raw(-code)))
+                            };
+                        }
+                        NLMSG_DONE => return Ok(replies),
+                        _ => replies.push(payload.to_vec()),
+                    }
+                }
+
+                offset += (msg_len + 3) & !3;
+            }
+        }
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Iterate over (type, data) of the attributes in a buffer
+fn attributes(buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        if offset + 4 > buf.len() {
+            return None;
+        }
+        let len = read_u16(buf, offset) as usize;
+        let ty = read_u16(buf, offset + 2) & !NLA_F_NESTED;
+        if len < 4 || offset + len > buf.len() {
+            return None;
+        }
+        let data = &buf[offset + 4..offset + len];
+        offset += (len + 3) & !3;
+        Some((ty, data))
+    })
+}
+
+/// struct ifinfomsg
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> Vec<u8> {
+    let mut header = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+    header.extend_from_slice(&index.to_ne_bytes());
+    header.extend_from_slice(&flags.to_ne_bytes());
+    header.extend_from_slice(&change.to_ne_bytes());
+    header
+}
+
+fn family(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => AF_INET,
+        IpAddr::V6(_) => AF_INET6,
+    }
+}
+
+fn addr_bytes(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    }
+}
+
+/// Kernel sockaddr_in / sockaddr_in6 layout for the peer endpoint
+fn sockaddr_bytes(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    match addr {
+        SocketAddr::V4(a) => {
+            bytes.extend_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+            bytes.extend_from_slice(&a.port().to_be_bytes());
+            bytes.extend_from_slice(&a.ip().octets());
+            bytes.extend_from_slice(&[0; 8]);
+        }
+        SocketAddr::V6(a) => {
+            bytes.extend_from_slice(&(libc::AF_INET6 as u16).to_ne_bytes());
+            bytes.extend_from_slice(&a.port().to_be_bytes());
+            bytes.extend_from_slice(&a.flowinfo().to_be_bytes());
+            bytes.extend_from_slice(&a.ip().octets());
+            bytes.extend_from_slice(&a.scope_id().to_ne_bytes());
+        }
+    }
+    bytes
+}
+
+/// Index of an interface, fails with ENODEV if there is none by that name
+#[cfg(not(test))]
+pub fn interface_index(name: &str) -> Result<u32> {
+    nix::net::if_::if_nametoindex(name).map_err(NetlinkError::Kernel)
+}
+
+#[cfg(test)]
+pub fn interface_index(name: &str) -> Result<u32> {
+    kernel::index(name).ok_or(NetlinkError::Kernel(Errno::ENODEV))
+}
+
+/// Create a WireGuard interface
+pub fn create_interface(name: &str, mtu: u32) -> Result<()> {
+    let mut socket = Socket::open(SockProtocol::NetlinkRoute)?;
+    let mut msg = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL).header(&ifinfomsg(0, 0, 0));
+    msg.attr_str(IFLA_IFNAME, name);
+    msg.attr_u32(IFLA_MTU, mtu);
+    let linkinfo = msg.begin_nested(IFLA_LINKINFO);
+    msg.attr_str(IFLA_INFO_KIND, "wireguard");
+    msg.end_nested(linkinfo);
+    socket.request(msg).map(|_| ())
+}
+
+/// Delete an interface
+pub fn delete_interface(name: &str) -> Result<()> {
+    let mut socket = Socket::open(SockProtocol::NetlinkRoute)?;
+    let mut msg = Message::new(RTM_DELLINK, 0).header(&ifinfomsg(0, 0, 0));
+    msg.attr_str(IFLA_IFNAME, name);
+    socket.request(msg).map(|_| ())
+}
+
+/// Set an interface administratively up
+pub fn set_up(index: u32) -> Result<()> {
+    let mut socket = Socket::open(SockProtocol::NetlinkRoute)?;
+    let msg = Message::new(RTM_NEWLINK, 0).header(&ifinfomsg(index, IFF_UP, IFF_UP));
+    socket.request(msg).map(|_| ())
+}
+
+/// Add an address to an interface
+pub fn add_address(index: u32, addr: IpAddr, prefix_len: u8) -> Result<()> {
+    let mut socket = Socket::open(SockProtocol::NetlinkRoute)?;
+    // struct ifaddrmsg
+    let mut header = vec![family(&addr), prefix_len, 0, 0];
+    header.extend_from_slice(&index.to_ne_bytes());
+    let mut msg = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE).header(&header);
+    msg.attr(IFA_LOCAL, &addr_bytes(&addr));
+    msg.attr(IFA_ADDRESS, &addr_bytes(&addr));
+    socket.request(msg).map(|_| ())
+}
+
+/// Add a default route through an interface in a routing table
+pub fn add_default_route(index: u32, ipv6: bool, table: u32) -> Result<()> {
+    let mut socket = Socket::open(SockProtocol::NetlinkRoute)?;
+    let family = if ipv6 { AF_INET6 } else { AF_INET };
+    // struct rtmsg: family, dst_len, src_len, tos, table, protocol, scope, type, flags
+    let mut header = vec![family, 0, 0, 0, 0, RTPROT_BOOT, 0, RTN_UNICAST];
+    header.extend_from_slice(&0u32.to_ne_bytes());
+    let mut msg = Message::new(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE).header(&header);
+    msg.attr_u32(RTA_OIF, index);
+    msg.attr_u32(RTA_TABLE, table);
+    socket.request(msg).map(|_| ())
+}
+
+/// Policy rules used by wg-quick for a full tunnel: `not fwmark <table> table <table>`,
+/// or with `suppress` set, `table main suppress_prefixlength 0`
+fn tunnel_rule(ipv6: bool, table: u32, suppress: bool, ty: u16, flags: u16) -> Message {
+    let family = if ipv6 { AF_INET6 } else { AF_INET };
+    let rule_flags = if suppress { 0 } else { FIB_RULE_INVERT };
+    // struct fib_rule_hdr: family, dst_len, src_len, tos, table, res1, res2, action, flags
+    let mut header = vec![family, 0, 0, 0, 0, 0, 0, FR_ACT_TO_TBL];
+    header.extend_from_slice(&rule_flags.to_ne_bytes());
+
+    let mut msg = Message::new(ty, flags).header(&header);
+    if suppress {
+        msg.attr_u32(FRA_TABLE, RT_TABLE_MAIN);
+        msg.attr_u32(FRA_SUPPRESS_PREFIXLEN, 0);
+    } else {
+        msg.attr_u32(FRA_FWMARK, table);
+        msg.attr_u32(FRA_TABLE, table);
+    }
+    msg
+}
+
+/// Route everything not marked with `table` as fwmark through `table`
+pub fn add_tunnel_rules(ipv6: bool, table: u32) -> Result<()> {
+    let mut socket = Socket::open(SockProtocol::NetlinkRoute)?;
+    for suppress in [false, true] {
+        let msg = tunnel_rule(ipv6, table, suppress, RTM_NEWRULE, NLM_F_CREATE | NLM_F_EXCL);
+        match socket.request(msg) {
+            Ok(_) | Err(NetlinkError::Kernel(Errno::EEXIST)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Remove the rules added by `add_tunnel_rules`, including any duplicates
+pub fn delete_tunnel_rules(ipv6: bool, table: u32) -> Result<()> {
+    let mut socket = Socket::open(SockProtocol::NetlinkRoute)?;
+    for suppress in [false, true] {
+        loop {
+            match socket.request(tunnel_rule(ipv6, table, suppress, RTM_DELRULE, 0)) {
+                Ok(_) => {}
+                Err(NetlinkError::Kernel(Errno::ENOENT)) => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Look up the id of a generic netlink family
+fn resolve_family(socket: &mut Socket, name: &'static str) -> Result<u16> {
+    // struct genlmsghdr: cmd, version, reserved
+    let mut msg = Message::new(GENL_ID_CTRL, 0).header(&[CTRL_CMD_GETFAMILY, 1, 0, 0]);
+    msg.attr_str(CTRL_ATTR_FAMILY_NAME, name);
+
+    let replies = match socket.request(msg) {
+        Ok(replies) => replies,
+        Err(NetlinkError::Kernel(Errno::ENOENT)) => return Err(NetlinkError::FamilyNotFound(name)),
+        Err(e) => return Err(e),
+    };
+
+    // Replies start with a genlmsghdr followed by the attributes
+    let id = replies
+        .iter()
+        .filter(|reply| reply.len() > 4)
+        .flat_map(|reply| attributes(&reply[4..]))
+        .find(|(ty, data)| *ty == CTRL_ATTR_FAMILY_ID && data.len() >= 2)
+        .map(|(_, data)| u16::from_ne_bytes([data[0], data[1]]));
+    id.ok_or(NetlinkError::FamilyNotFound(name))
+}
+
+/// Set the private key, fwmark and (single) peer of a WireGuard device
+pub fn configure_device(
+    name: &str,
+    private_key: &[u8; 32],
+    fwmark: u32,
+    peer: &Peer,
+) -> Result<()> {
+    let mut socket = Socket::open(SockProtocol::NetlinkGeneric)?;
+    let family_id = resolve_family(&mut socket, WG_GENL_NAME)?;
+
+    let mut msg = Message::new(family_id, 0).header(&[WG_CMD_SET_DEVICE, WG_GENL_VERSION, 0, 0]);
+    msg.attr_str(WGDEVICE_A_IFNAME, name);
+    msg.attr(WGDEVICE_A_PRIVATE_KEY, private_key);
+    msg.attr_u32(WGDEVICE_A_FWMARK, fwmark);
+    msg.attr_u32(WGDEVICE_A_FLAGS, WGDEVICE_F_REPLACE_PEERS);
+
+    let peers = msg.begin_nested(WGDEVICE_A_PEERS);
+    let entry = msg.begin_nested(0);
+    msg.attr(WGPEER_A_PUBLIC_KEY, &peer.public_key);
+    msg.attr_u32(WGPEER_A_FLAGS, WGPEER_F_REPLACE_ALLOWEDIPS);
+    msg.attr(WGPEER_A_ENDPOINT, &sockaddr_bytes(&peer.endpoint));
+    let allowed_ips = msg.begin_nested(WGPEER_A_ALLOWEDIPS);
+    for (i, (addr, cidr)) in peer.allowed_ips.iter().enumerate() {
+        let allowed_ip = msg.begin_nested(i as u16);
+        msg.attr_u16(WGALLOWEDIP_A_FAMILY, family(addr) as u16);
+        msg.attr(WGALLOWEDIP_A_IPADDR, &addr_bytes(addr));
+        msg.attr(WGALLOWEDIP_A_CIDR_MASK, &[*cidr]);
+        msg.end_nested(allowed_ip);
+    }
+    msg.end_nested(allowed_ips);
+    msg.end_nested(entry);
+    msg.end_nested(peers);
+
+    socket.request(msg).map(|_| ())
+}
+
+/// In-memory stand-in for the kernel side of netlink, so that tunnels can be
+/// brought up and down in tests. It keeps track of links and policy rules and
+/// acknowledges everything else. State is per thread, like the tests.
+#[cfg(test)]
+pub mod kernel {
+    use super::*;
+    use std::cell::RefCell;
+
+    pub const RTM_NEWROUTE: u16 = super::RTM_NEWROUTE;
+
+    /// Id the WireGuard generic netlink family gets
+    const WG_FAMILY_ID: u16 = 0x1c;
+    const CTRL_CMD_NEWFAMILY: u8 = 1;
+
+    #[derive(Default)]
+    struct State {
+        /// Interface names with their index
+        links: Vec<(String, u32)>,
+        last_index: u32,
+        /// Rules as their fib_rule_hdr and attributes
+        rules: Vec<Vec<u8>>,
+        /// Request types to fail the next time they come, with the error
+        failures: Vec<(u16, Errno)>,
+        /// Every request received, as sent
+        requests: Vec<Vec<u8>>,
+    }
+
+    impl State {
+        fn index(&self, name: &str) -> Option<u32> {
+            self.links.iter().find(|(n, _)| n == name).map(|(_, index)| *index)
+        }
+
+        fn add_link(&mut self, name: &str) {
+            self.last_index += 1;
+            self.links.push((name.to_string(), self.last_index));
+        }
+    }
+
+    thread_local! {
+        static STATE: RefCell<State> = RefCell::default();
+    }
+
+    /// Forget all links, rules and failures
+    pub fn reset() {
+        STATE.with(|state| *state.borrow_mut() = State::default());
+    }
+
+    /// Add a link, like wg-quick would
+    pub fn add_link(name: &str) {
+        STATE.with(|state| state.borrow_mut().add_link(name));
+    }
+
+    pub fn links() -> Vec<String> {
+        STATE.with(|state| state.borrow().links.iter().map(|(name, _)| name.clone()).collect())
+    }
+
+    pub fn rule_count() -> usize {
+        STATE.with(|state| state.borrow().rules.len())
+    }
+
+    /// Reject the next request of type `ty` with `errno`
+    pub fn fail(ty: u16, errno: Errno) {
+        STATE.with(|state| state.borrow_mut().failures.push((ty, errno)));
+    }
+
+    pub(super) fn index(name: &str) -> Option<u32> {
+        STATE.with(|state| state.borrow().index(name))
+    }
+
+    pub(super) fn requests() -> Vec<Vec<u8>> {
+        STATE.with(|state| state.borrow().requests.clone())
+    }
+
+    /// Answer a request with the messages the kernel would send back
+    pub(super) fn handle(request: &[u8]) -> Vec<u8> {
+        let ty = read_u16(request, 4);
+        let flags = read_u16(request, 6);
+        let seq = read_u32(request, 8);
+        let payload = &request[NLMSG_HDRLEN..];
+
+        let mut replies = Vec::new();
+        let result = STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.requests.push(request.to_vec());
+            if let Some(pos) = state.failures.iter().position(|(t, _)| *t == ty) {
+                return Err(state.failures.remove(pos).1);
+            }
+
+            match ty {
+                RTM_NEWLINK if flags & NLM_F_CREATE != 0 => {
+                    let name = name_attr(&payload[16..], IFLA_IFNAME);
+                    if state.index(&name).is_some() {
+                        return Err(Errno::EEXIST);
+                    }
+                    state.add_link(&name);
+                }
+                RTM_DELLINK => {
+                    let name = name_attr(&payload[16..], IFLA_IFNAME);
+                    let pos = state.links.iter().position(|(n, _)| *n == name);
+                    state.links.remove(pos.ok_or(Errno::ENODEV)?);
+                }
+                RTM_NEWRULE => {
+                    if state.rules.iter().any(|rule| rule == payload) {
+                        return Err(Errno::EEXIST);
+                    }
+                    state.rules.push(payload.to_vec());
+                }
+                RTM_DELRULE => {
+                    let pos = state.rules.iter().position(|rule| rule == payload);
+                    state.rules.remove(pos.ok_or(Errno::ENOENT)?);
+                }
+                GENL_ID_CTRL => {
+                    let mut reply = Message::new(GENL_ID_CTRL, 0).header(&[CTRL_CMD_NEWFAMILY, 2, 0, 0]);
+                    reply.attr_u16(CTRL_ATTR_FAMILY_ID, WG_FAMILY_ID);
+                    reply.attr_str(CTRL_ATTR_FAMILY_NAME, WG_GENL_NAME);
+                    replies.extend(reply.finish(seq));
+                }
+                WG_FAMILY_ID => {
+                    let name = name_attr(&payload[4..], WGDEVICE_A_IFNAME);
+                    if state.index(&name).is_none() {
+                        return Err(Errno::ENODEV);
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        });
+
+        // struct nlmsgerr: the error code, then the header of the request
+        let code = match result {
+            Ok(()) => 0,
+            Err(errno) => -(errno as i32),
+        };
+        let ack = Message::new(NLMSG_ERROR, 0)
+            .header(&code.to_ne_bytes())
+            .header(&request[..NLMSG_HDRLEN]);
+        replies.extend(ack.finish(seq));
+        replies
+    }
+
+    /// Interface name attribute of a request
+    fn name_attr(attrs: &[u8], ty: u16) -> String {
+        attributes(attrs)
+            .find(|(t, _)| *t == ty)
+            .map(|(_, data)| String::from_utf8_lossy(data).trim_end_matches('\0').to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// struct nlmsghdr of a request; the port id is left to the kernel
+    fn nlmsghdr(len: u32, ty: u16, flags: u16, seq: u32) -> Vec<u8> {
+        [&len.to_ne_bytes()[..], &ty.to_ne_bytes(), &flags.to_ne_bytes(), &seq.to_ne_bytes(), &[0; 4]].concat()
+    }
+
+    /// Attribute header: length without padding, then type
+    fn nla(len: u16, ty: u16) -> Vec<u8> {
+        [len.to_ne_bytes(), ty.to_ne_bytes()].concat()
+    }
+
+    fn sent() -> Vec<Vec<u8>> {
+        kernel::requests()
+    }
+
+    const CREATE: u16 = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL;
+    const REPLACE: u16 = NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE;
+
+    #[test]
+    fn create_interface_encodes_newlink() {
+        kernel::reset();
+
+        create_interface("wg-se1", 1380).unwrap();
+
+        let expected = [
+            nlmsghdr(72, RTM_NEWLINK, CREATE, 1),
+            // struct ifinfomsg: family, pad, type, index, flags, change
+            vec![0, 0, 0, 0],
+            vec![0; 12],
+            // 7 bytes of name, padded to 8
+            nla(11, IFLA_IFNAME),
+            b"wg-se1\0\0".to_vec(),
+            nla(8, IFLA_MTU),
+            1380u32.to_ne_bytes().to_vec(),
+            nla(20, IFLA_LINKINFO | NLA_F_NESTED),
+            nla(14, IFLA_INFO_KIND),
+            b"wireguard\0\0\0".to_vec(),
+        ]
+        .concat();
+        assert_eq!(sent(), [expected]);
+    }
+
+    #[test]
+    fn set_up_encodes_link_flags() {
+        kernel::reset();
+
+        set_up(3).unwrap();
+
+        let expected = [
+            nlmsghdr(32, RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK, 1),
+            vec![0, 0, 0, 0],
+            3u32.to_ne_bytes().to_vec(),
+            IFF_UP.to_ne_bytes().to_vec(),
+            IFF_UP.to_ne_bytes().to_vec(),
+        ]
+        .concat();
+        assert_eq!(sent(), [expected]);
+    }
+
+    #[test]
+    fn add_address_encodes_newaddr() {
+        kernel::reset();
+
+        add_address(3, IpAddr::V4(Ipv4Addr::new(10, 64, 0, 2)), 32).unwrap();
+        add_address(3, IpAddr::V6("fc00:bbbb::2".parse().unwrap()), 128).unwrap();
+
+        let v6: Ipv6Addr = "fc00:bbbb::2".parse().unwrap();
+        let expected = [
+            [
+                nlmsghdr(40, RTM_NEWADDR, REPLACE, 1),
+                // struct ifaddrmsg: family, prefix length, flags, scope, index
+                vec![AF_INET, 32, 0, 0],
+                3u32.to_ne_bytes().to_vec(),
+                nla(8, IFA_LOCAL),
+                vec![10, 64, 0, 2],
+                nla(8, IFA_ADDRESS),
+                vec![10, 64, 0, 2],
+            ]
+            .concat(),
+            [
+                nlmsghdr(64, RTM_NEWADDR, REPLACE, 1),
+                vec![AF_INET6, 128, 0, 0],
+                3u32.to_ne_bytes().to_vec(),
+                nla(20, IFA_LOCAL),
+                v6.octets().to_vec(),
+                nla(20, IFA_ADDRESS),
+                v6.octets().to_vec(),
+            ]
+            .concat(),
+        ];
+        assert_eq!(sent(), expected);
+    }
+
+    #[test]
+    fn add_default_route_encodes_newroute() {
+        kernel::reset();
+
+        add_default_route(3, true, 51820).unwrap();
+
+        let expected = [
+            nlmsghdr(44, RTM_NEWROUTE, REPLACE, 1),
+            // struct rtmsg: the table goes in RTA_TABLE, it does not fit in a byte
+            vec![AF_INET6, 0, 0, 0, 0, RTPROT_BOOT, 0, RTN_UNICAST],
+            vec![0; 4],
+            nla(8, RTA_OIF),
+            3u32.to_ne_bytes().to_vec(),
+            nla(8, RTA_TABLE),
+            51820u32.to_ne_bytes().to_vec(),
+        ]
+        .concat();
+        assert_eq!(sent(), [expected]);
+    }
+
+    #[test]
+    fn tunnel_rules_encode_fwmark_and_suppress_rules() {
+        kernel::reset();
+
+        add_tunnel_rules(false, 51820).unwrap();
+        delete_tunnel_rules(false, 51820).unwrap();
+
+        let fwmark_rule = |ty, flags, seq| {
+            [
+                nlmsghdr(44, ty, flags, seq),
+                // struct fib_rule_hdr, inverted: not fwmark
+                vec![AF_INET, 0, 0, 0, 0, 0, 0, FR_ACT_TO_TBL],
+                FIB_RULE_INVERT.to_ne_bytes().to_vec(),
+                nla(8, FRA_FWMARK),
+                51820u32.to_ne_bytes().to_vec(),
+                nla(8, FRA_TABLE),
+                51820u32.to_ne_bytes().to_vec(),
+            ]
+            .concat()
+        };
+        let suppress_rule = |ty, flags, seq| {
+            [
+                nlmsghdr(44, ty, flags, seq),
+                vec![AF_INET, 0, 0, 0, 0, 0, 0, FR_ACT_TO_TBL],
+                vec![0; 4],
+                nla(8, FRA_TABLE),
+                RT_TABLE_MAIN.to_ne_bytes().to_vec(),
+                nla(8, FRA_SUPPRESS_PREFIXLEN),
+                vec![0; 4],
+            ]
+            .concat()
+        };
+        let delete = NLM_F_REQUEST | NLM_F_ACK;
+        // Deleting repeats until the kernel has no such rule left
+        assert_eq!(
+            sent(),
+            [
+                fwmark_rule(RTM_NEWRULE, CREATE, 1),
+                suppress_rule(RTM_NEWRULE, CREATE, 2),
+                fwmark_rule(RTM_DELRULE, delete, 1),
+                fwmark_rule(RTM_DELRULE, delete, 2),
+                suppress_rule(RTM_DELRULE, delete, 3),
+                suppress_rule(RTM_DELRULE, delete, 4),
+            ]
+        );
+        assert_eq!(kernel::rule_count(), 0);
+    }
+
+    #[test]
+    fn configure_device_encodes_peer_and_allowed_ips() {
+        kernel::reset();
+        kernel::add_link("wg-se1");
+        let peer = Peer {
+            public_key: [0xbb; 32],
+            endpoint: "185.213.154.68:51820".parse().unwrap(),
+            allowed_ips: vec![
+                (IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+                (IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+            ],
+        };
+
+        configure_device("wg-se1", &[0xaa; 32], 51820, &peer).unwrap();
+
+        let sent = sent();
+        let family = [
+            nlmsghdr(36, GENL_ID_CTRL, NLM_F_REQUEST | NLM_F_ACK, 1),
+            // struct genlmsghdr: command, version, reserved
+            vec![CTRL_CMD_GETFAMILY, 1, 0, 0],
+            nla(14, CTRL_ATTR_FAMILY_NAME),
+            b"wireguard\0\0\0".to_vec(),
+        ]
+        .concat();
+        assert_eq!(sent[0], family);
+
+        let device = [
+            // Sent to the family id the kernel answered with
+            nlmsghdr(228, 0x1c, NLM_F_REQUEST | NLM_F_ACK, 2),
+            vec![WG_CMD_SET_DEVICE, WG_GENL_VERSION, 0, 0],
+            nla(11, WGDEVICE_A_IFNAME),
+            b"wg-se1\0\0".to_vec(),
+            nla(36, WGDEVICE_A_PRIVATE_KEY),
+            vec![0xaa; 32],
+            nla(8, WGDEVICE_A_FWMARK),
+            51820u32.to_ne_bytes().to_vec(),
+            nla(8, WGDEVICE_A_FLAGS),
+            WGDEVICE_F_REPLACE_PEERS.to_ne_bytes().to_vec(),
+            nla(144, WGDEVICE_A_PEERS | NLA_F_NESTED),
+            nla(140, NLA_F_NESTED),
+            nla(36, WGPEER_A_PUBLIC_KEY),
+            vec![0xbb; 32],
+            nla(8, WGPEER_A_FLAGS),
+            WGPEER_F_REPLACE_ALLOWEDIPS.to_ne_bytes().to_vec(),
+            // struct sockaddr_in: family, port in network order, address, zero padding
+            nla(20, WGPEER_A_ENDPOINT),
+            (libc::AF_INET as u16).to_ne_bytes().to_vec(),
+            vec![0xca, 0x6c, 185, 213, 154, 68],
+            vec![0; 8],
+            nla(72, WGPEER_A_ALLOWEDIPS | NLA_F_NESTED),
+            // Allowed IPs are numbered from 0, each padded to four bytes
+            nla(28, NLA_F_NESTED),
+            nla(6, WGALLOWEDIP_A_FAMILY),
+            vec![AF_INET, 0, 0, 0],
+            nla(8, WGALLOWEDIP_A_IPADDR),
+            vec![0; 4],
+            nla(5, WGALLOWEDIP_A_CIDR_MASK),
+            vec![0, 0, 0, 0],
+            nla(40, 1 | NLA_F_NESTED),
+            nla(6, WGALLOWEDIP_A_FAMILY),
+            vec![AF_INET6, 0, 0, 0],
+            nla(20, WGALLOWEDIP_A_IPADDR),
+            vec![0; 16],
+            nla(5, WGALLOWEDIP_A_CIDR_MASK),
+            vec![0, 0, 0, 0],
+        ]
+        .concat();
+        assert_eq!(sent[1], device);
+    }
+
+    #[test]
+    fn ipv6_endpoint_is_sockaddr_in6() {
+        let endpoint = SocketAddr::V6("[2a03:1b20:1:f011::a01f]:51820".parse().unwrap());
+        let ip: Ipv6Addr = "2a03:1b20:1:f011::a01f".parse().unwrap();
+
+        // family, port, flowinfo, address, scope id
+        let expected = [
+            (libc::AF_INET6 as u16).to_ne_bytes().to_vec(),
+            vec![0xca, 0x6c],
+            vec![0; 4],
+            ip.octets().to_vec(),
+            vec![0; 4],
+        ]
+        .concat();
+        assert_eq!(sockaddr_bytes(&endpoint), expected);
+        assert_eq!(expected.len(), 28);
+    }
+
+    #[test]
+    fn kernel_error_is_returned() {
+        kernel::reset();
+        kernel::fail(RTM_NEWADDR, Errno::EPERM);
+
+        let error = add_address(3, IpAddr::V4(Ipv4Addr::new(10, 64, 0, 2)), 32).unwrap_err();
+
+        assert!(matches!(error, NetlinkError::Kernel(Errno::EPERM)));
+    }
+}
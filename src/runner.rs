@@ -0,0 +1,111 @@
+use std::io::{self, Write};
+use std::process::{Command, Output, Stdio};
+
+/// Runs external programs. Injected into the code that manages the tunnel so
+/// that it can be exercised with scripted outputs instead of root and a kernel.
+pub trait CommandRunner: Send + Sync {
+    /// Run a program to completion, feeding `input` to its stdin
+    fn run(&self, program: &str, args: &[&str], input: Option<&str>) -> io::Result<Output>;
+
+    /// Run a program to completion without input
+    fn output(&self, program: &str, args: &[&str]) -> io::Result<Output> {
+        self.run(program, args, None)
+    }
+}
+
+/// Runs programs for real
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, program: &str, args: &[&str], input: Option<&str>) -> io::Result<Output> {
+        let Some(input) = input else {
+            return Command::new(program).args(args).output();
+        };
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input.as_bytes())?;
+        }
+
+        child.wait_with_output()
+    }
+}
+
+#[cfg(test)]
+pub use fake::FakeRunner;
+
+#[cfg(test)]
+mod fake {
+    use std::collections::VecDeque;
+    use std::io;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+    use std::sync::Mutex;
+
+    use super::CommandRunner;
+
+    /// Records the command lines it is asked to run and answers with scripted
+    /// outputs. Commands without a script succeed with no output.
+    #[derive(Default)]
+    pub struct FakeRunner {
+        calls: Mutex<Vec<String>>,
+        /// Command line prefix and the result to give, used once each in order
+        script: Mutex<VecDeque<(String, Result<Output, io::ErrorKind>)>>,
+    }
+
+    impl FakeRunner {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Answer the next command starting with `prefix` with this exit code and output
+        pub fn respond(&self, prefix: &str, code: i32, stdout: &str, stderr: &str) -> &Self {
+            let output = Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: stderr.as_bytes().to_vec(),
+            };
+            self.script.lock().unwrap().push_back((prefix.to_string(), Ok(output)));
+            self
+        }
+
+        /// Fail to start the next command starting with `prefix`
+        pub fn fail_spawn(&self, prefix: &str, kind: io::ErrorKind) -> &Self {
+            self.script.lock().unwrap().push_back((prefix.to_string(), Err(kind)));
+            self
+        }
+
+        /// Command lines run so far, program and arguments joined by spaces
+        pub fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn run(&self, program: &str, args: &[&str], _input: Option<&str>) -> io::Result<Output> {
+            let line = std::iter::once(program)
+                .chain(args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.calls.lock().unwrap().push(line.clone());
+
+            let mut script = self.script.lock().unwrap();
+            let scripted = script.iter().position(|(prefix, _)| line.starts_with(prefix.as_str()));
+            match scripted.and_then(|idx| script.remove(idx)) {
+                Some((_, Ok(output))) => Ok(output),
+                Some((_, Err(kind))) => Err(kind.into()),
+                None => Ok(Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                }),
+            }
+        }
+    }
+}
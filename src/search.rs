@@ -0,0 +1,152 @@
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+
+use crate::server::Server;
+
+/// Which server field a search query matched
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchField {
+    Code,
+    Hostname,
+    City,
+    Country,
+}
+
+/// A server matching a search query
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub server: Server,
+    pub field: MatchField,
+    /// Character indices of the matched field to highlight
+    pub indices: Vec<usize>,
+    score: i64,
+}
+
+/// Fuzzy-match a query against server code, hostname, city and country.
+/// Results are ordered best match first.
+pub fn search<'a>(servers: impl IntoIterator<Item = &'a Server>, query: &str) -> Vec<SearchResult> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Vec::new();
+    }
+
+    let matcher = SkimMatcherV2::default().ignore_case();
+    let mut results: Vec<SearchResult> = servers
+        .into_iter()
+        .filter_map(|server| {
+            [
+                (MatchField::Code, server.code.as_str()),
+                (MatchField::Hostname, server.hostname.as_str()),
+                (MatchField::City, server.city.as_str()),
+                (MatchField::Country, server.country.as_str()),
+            ]
+            .into_iter()
+            .filter_map(|(field, text)| {
+                matcher
+                    .fuzzy_indices(text, query)
+                    .map(|(score, indices)| (score, field, indices))
+            })
+            // Keep the first field on ties so code beats hostname
+            .reduce(|best, candidate| if candidate.0 > best.0 { candidate } else { best })
+            .map(|(score, field, indices)| SearchResult {
+                server: server.clone(),
+                field,
+                indices,
+                score,
+            })
+        })
+        .collect();
+
+    results.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then_with(|| a.server.code.cmp(&b.server.code))
+    });
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::sample_servers;
+
+    /// Codes and matched fields of the results, best first
+    fn ranking(servers: &[Server], query: &str) -> Vec<(String, MatchField)> {
+        search(servers, query)
+            .into_iter()
+            .map(|r| (r.server.code, r.field))
+            .collect()
+    }
+
+    fn result(code: &str, field: MatchField) -> (String, MatchField) {
+        (code.to_string(), field)
+    }
+
+    #[test]
+    fn matches_each_field() {
+        let mut servers = sample_servers();
+        servers[4].hostname = "amsterdam-relay.example.net".to_string();
+
+        let cases = [
+            // Hostnames equal to the code count as a code match
+            ("ber-wg", vec![result("de-ber-wg-001", MatchField::Code)]),
+            ("relay", vec![result("nl-ams-wg-101", MatchField::Hostname)]),
+            ("Berlin", vec![result("de-ber-wg-001", MatchField::City)]),
+            ("netherlands", vec![result("nl-ams-wg-101", MatchField::Country)]),
+        ];
+
+        for (query, expected) in cases {
+            assert_eq!(ranking(&servers, query), expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn close_matches_rank_first() {
+        let servers = sample_servers();
+
+        let results = ranking(&servers, "wg1");
+
+        // "wg-1" is closer to the query than "wg-001"
+        assert_eq!(
+            results,
+            [
+                result("nl-ams-wg-101", MatchField::Code),
+                result("de-ber-wg-001", MatchField::Code),
+                result("de-fra-wg-001", MatchField::Code),
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_are_ordered_by_code() {
+        let servers = sample_servers();
+
+        let codes: Vec<String> = ranking(&servers, "frankfurt").into_iter().map(|(code, _)| code).collect();
+
+        assert_eq!(codes, ["de-fra-wg-001", "de-fra-wg-002", "de-fra-wg-003"]);
+    }
+
+    #[test]
+    fn highlights_matched_characters() {
+        let servers = sample_servers();
+
+        let results = search(&servers, "ams");
+
+        // The city starts with the query, which beats the middle of the code
+        assert_eq!(results[0].server.code, "nl-ams-wg-101");
+        assert_eq!(results[0].field, MatchField::City);
+        assert_eq!(results[0].indices, [0, 1, 2]);
+
+        let results = search(&servers, "wg-101");
+        assert_eq!(results[0].field, MatchField::Code);
+        assert_eq!(results[0].indices, [7, 8, 9, 10, 11, 12]);
+    }
+
+    #[test]
+    fn blank_or_unmatched_query_finds_nothing() {
+        let servers = sample_servers();
+
+        assert!(search(&servers, "  ").is_empty());
+        assert!(search(&servers, "zzz").is_empty());
+    }
+}
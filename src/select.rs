@@ -0,0 +1,322 @@
+use clap::ValueEnum;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::history::{Action, HistoryEntry};
+use crate::latency::LatencyCache;
+use crate::server::{Server, ServerTree};
+
+/// Restrictions on which relays may be picked
+#[derive(Debug, Clone, Default)]
+pub struct Constraints {
+    /// Country name or code (e.g. "Switzerland" or "ch")
+    pub country: Option<String>,
+    /// City name or code (e.g. "Zurich" or "zrh")
+    pub city: Option<String>,
+    pub owned_only: bool,
+    pub exclude_providers: Vec<String>,
+    /// Only consider relays measured at or below this latency
+    pub max_latency_ms: Option<f64>,
+    /// Server codes that must not be picked
+    pub exclude_servers: Vec<String>,
+}
+
+impl Constraints {
+    /// Whether the relay is in the country and city, by name or code
+    pub fn matches_location(&self, server: &Server) -> bool {
+        let matches_place = |filter: &Option<String>, name: &str, code: &str| {
+            filter
+                .as_ref()
+                .is_none_or(|f| f.eq_ignore_ascii_case(name) || f.eq_ignore_ascii_case(code))
+        };
+
+        matches_place(&self.country, &server.country, &server.country_code)
+            && matches_place(&self.city, &server.city, &server.city_code)
+    }
+
+    fn matches(&self, server: &Server, latencies: &LatencyCache) -> bool {
+        server.active
+            && !self.exclude_servers.contains(&server.code)
+            && self.matches_location(server)
+            && (!self.owned_only || server.owned)
+            && !self
+                .exclude_providers
+                .iter()
+                .any(|p| p.eq_ignore_ascii_case(&server.provider))
+            && self.max_latency_ms.is_none_or(|max| {
+                latencies
+                    .get(&server.code)
+                    .and_then(|l| l.ms)
+                    .is_some_and(|ms| ms <= max)
+            })
+    }
+}
+
+/// How to choose among the relays that satisfy the constraints
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Lowest measured latency, falling back to weighted random if nothing was measured
+    #[default]
+    #[value(name = "latency")]
+    LowestLatency,
+    /// Random, weighted by the relay weight from the API
+    Random,
+    /// The relay connected to least recently (never used relays first)
+    #[value(name = "lru")]
+    LeastRecentlyUsed,
+}
+
+/// Pick the best relay from the tree for the given constraints and strategy
+pub fn select_server<'a>(
+    tree: &'a ServerTree,
+    constraints: &Constraints,
+    strategy: Strategy,
+    latencies: &LatencyCache,
+    history: &[HistoryEntry],
+) -> Option<&'a Server> {
+    let candidates: Vec<&Server> = tree
+        .values()
+        .flat_map(|cities| cities.values().flatten())
+        .filter(|s| constraints.matches(s, latencies))
+        .collect();
+
+    match strategy {
+        Strategy::LowestLatency => candidates
+            .iter()
+            .filter_map(|s| latencies.get(&s.code).and_then(|l| l.ms).map(|ms| (*s, ms)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(s, _)| s)
+            .or_else(|| weighted_random(&candidates)),
+        Strategy::Random => weighted_random(&candidates),
+        Strategy::LeastRecentlyUsed => candidates.into_iter().min_by_key(|s| {
+            let last_used = history
+                .iter()
+                .rev()
+                .find(|e| e.action == Action::Connect && e.succeeded() && e.code == s.code)
+                .map(|e| e.timestamp)
+                .unwrap_or(0);
+            (last_used, s.code.clone())
+        }),
+    }
+}
+
+fn weighted_random<'a>(candidates: &[&'a Server]) -> Option<&'a Server> {
+    // Relays without a weight still get a chance
+    let weight = |s: &Server| s.weight.max(1);
+    let total: u64 = candidates.iter().map(|s| weight(s)).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let mut pick = rand::thread_rng().gen_range(0..total);
+    for server in candidates {
+        if pick < weight(server) {
+            return Some(server);
+        }
+        pick -= weight(server);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::latency::Latency;
+    use crate::server::group_servers;
+    use crate::testing::sample_servers;
+
+    fn latencies(measured: &[(&str, Option<f64>)]) -> LatencyCache {
+        measured
+            .iter()
+            .map(|(code, ms)| (code.to_string(), Latency { ms: *ms, measured_at: 0 }))
+            .collect()
+    }
+
+    fn connected(code: &str, timestamp: u64) -> HistoryEntry {
+        HistoryEntry {
+            timestamp,
+            action: Action::Connect,
+            code: code.to_string(),
+            duration: None,
+            error: None,
+        }
+    }
+
+    /// Codes of the servers matching the constraints, in tree order
+    fn candidates(constraints: &Constraints, latencies: &LatencyCache) -> Vec<String> {
+        sample_servers()
+            .iter()
+            .filter(|s| constraints.matches(s, latencies))
+            .map(|s| s.code.clone())
+            .collect()
+    }
+
+    #[test]
+    fn lowest_latency_wins() {
+        let tree = group_servers(&sample_servers());
+        let latencies = latencies(&[
+            ("de-fra-wg-001", Some(40.0)),
+            ("de-ber-wg-001", Some(12.5)),
+            ("nl-ams-wg-101", None),
+            // Offline relays are never picked, however fast they were
+            ("de-fra-wg-003", Some(1.0)),
+        ]);
+
+        let best = select_server(&tree, &Constraints::default(), Strategy::LowestLatency, &latencies, &[]);
+
+        assert_eq!(best.map(|s| s.code.as_str()), Some("de-ber-wg-001"));
+    }
+
+    #[test]
+    fn lowest_latency_without_measurements_picks_any_candidate() {
+        let tree = group_servers(&sample_servers());
+        let constraints = Constraints {
+            country: Some("nl".to_string()),
+            ..Default::default()
+        };
+
+        let best = select_server(&tree, &constraints, Strategy::LowestLatency, &LatencyCache::new(), &[]);
+
+        assert_eq!(best.map(|s| s.code.as_str()), Some("nl-ams-wg-101"));
+    }
+
+    #[test]
+    fn random_only_picks_candidates() {
+        let tree = group_servers(&sample_servers());
+        let constraints = Constraints {
+            city: Some("Frankfurt".to_string()),
+            ..Default::default()
+        };
+
+        for _ in 0..50 {
+            let code = select_server(&tree, &constraints, Strategy::Random, &LatencyCache::new(), &[])
+                .map(|s| s.code.as_str());
+            assert!(matches!(code, Some("de-fra-wg-001" | "de-fra-wg-002")), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn least_recently_used_prefers_unused_then_oldest() {
+        let tree = group_servers(&sample_servers());
+        let constraints = Constraints {
+            country: Some("Germany".to_string()),
+            ..Default::default()
+        };
+        let mut failed = connected("de-ber-wg-001", 300);
+        failed.error = Some("wg-quick up failed".to_string());
+        let mut history = vec![
+            connected("de-fra-wg-001", 100),
+            connected("de-fra-wg-002", 200),
+            // Failed attempts don't count as use
+            failed,
+        ];
+        let pick = |history: &[HistoryEntry]| {
+            select_server(&tree, &constraints, Strategy::LeastRecentlyUsed, &LatencyCache::new(), history)
+                .map(|s| s.code.clone())
+        };
+
+        assert_eq!(pick(&history).as_deref(), Some("de-ber-wg-001"));
+
+        history.push(connected("de-ber-wg-001", 400));
+        assert_eq!(pick(&history).as_deref(), Some("de-fra-wg-001"));
+    }
+
+    #[test]
+    fn constraints_narrow_the_candidates() {
+        let none = LatencyCache::new();
+        let cases = [
+            // Names and codes, in any case; offline relays never match
+            (
+                Constraints {
+                    country: Some("DE".to_string()),
+                    ..Default::default()
+                },
+                vec!["de-fra-wg-001", "de-fra-wg-002", "de-ber-wg-001"],
+            ),
+            (
+                Constraints {
+                    city: Some("fra".to_string()),
+                    ..Default::default()
+                },
+                vec!["de-fra-wg-001", "de-fra-wg-002"],
+            ),
+            (
+                Constraints {
+                    owned_only: true,
+                    ..Default::default()
+                },
+                vec!["de-fra-wg-001", "nl-ams-wg-101"],
+            ),
+            (
+                Constraints {
+                    exclude_providers: vec!["XTOM".to_string(), "m247".to_string()],
+                    ..Default::default()
+                },
+                vec!["de-fra-wg-001", "nl-ams-wg-101"],
+            ),
+            (
+                Constraints {
+                    country: Some("Germany".to_string()),
+                    exclude_servers: vec!["de-fra-wg-001".to_string(), "de-ber-wg-001".to_string()],
+                    ..Default::default()
+                },
+                vec!["de-fra-wg-002"],
+            ),
+        ];
+
+        for (constraints, expected) in cases {
+            assert_eq!(candidates(&constraints, &none), expected, "{:?}", constraints);
+        }
+    }
+
+    #[test]
+    fn location_matches_names_and_codes_of_any_relay() {
+        let location = |country: &str, city: &str| Constraints {
+            country: Some(country.to_string()),
+            city: Some(city.to_string()),
+            ..Default::default()
+        };
+        let matching = |constraints: Constraints| -> Vec<String> {
+            sample_servers()
+                .into_iter()
+                .filter(|s| constraints.matches_location(s))
+                .map(|s| s.code)
+                .collect()
+        };
+
+        // Offline relays are still in Frankfurt
+        let frankfurt = ["de-fra-wg-001", "de-fra-wg-002", "de-fra-wg-003"];
+        assert_eq!(matching(location("de", "fra")), frankfurt);
+        assert_eq!(matching(location("GERMANY", "Frankfurt")), frankfurt);
+        assert!(matching(location("nl", "fra")).is_empty());
+    }
+
+    #[test]
+    fn max_latency_skips_slow_and_unmeasured_relays() {
+        let latencies = latencies(&[
+            ("de-fra-wg-001", Some(30.0)),
+            ("de-fra-wg-002", Some(80.0)),
+            ("de-ber-wg-001", None),
+        ]);
+        let constraints = Constraints {
+            max_latency_ms: Some(50.0),
+            ..Default::default()
+        };
+
+        assert_eq!(candidates(&constraints, &latencies), ["de-fra-wg-001"]);
+    }
+
+    #[test]
+    fn nothing_matching_selects_nothing() {
+        let tree = group_servers(&sample_servers());
+        let constraints = Constraints {
+            country: Some("Sweden".to_string()),
+            ..Default::default()
+        };
+
+        for strategy in [Strategy::LowestLatency, Strategy::Random, Strategy::LeastRecentlyUsed] {
+            assert!(select_server(&tree, &constraints, strategy, &LatencyCache::new(), &[]).is_none());
+        }
+    }
+}
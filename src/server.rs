@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
+// Fields after `city` were added later; defaults keep older caches loadable
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Server {
     pub code: String,
@@ -10,6 +11,30 @@ pub struct Server {
     pub port: u16,
     pub country: String,
     pub city: String,
+    #[serde(default)]
+    pub ipv6_addr: Option<String>,
+    /// Port the entry relay of a multihop connection forwards to. Generated
+    /// configs are single hop, so it is only kept for reference.
+    #[serde(default)]
+    pub multihop_port: Option<u16>,
+    #[serde(default)]
+    pub country_code: String,
+    #[serde(default)]
+    pub city_code: String,
+    #[serde(default = "default_active")]
+    pub active: bool,
+    #[serde(default)]
+    pub owned: bool,
+    #[serde(default)]
+    pub provider: String,
+    #[serde(default)]
+    pub socks_name: Option<String>,
+    #[serde(default)]
+    pub weight: u64,
+}
+
+fn default_active() -> bool {
+    true
 }
 
 impl Server {
@@ -17,6 +42,13 @@ impl Server {
         format!("{}:{}", self.ipv4_addr, self.port)
     }
 
+    /// Endpoint over IPv6, if the relay has an IPv6 address
+    pub fn endpoint_v6(&self) -> Option<String> {
+        self.ipv6_addr
+            .as_ref()
+            .map(|addr| format!("[{}]:{}", addr, self.port))
+    }
+
     pub fn location(&self) -> String {
         format!("{}, {}", self.city, self.country)
     }
@@ -28,6 +60,72 @@ pub struct ServerCache {
     pub timestamp: u64,
 }
 
+/// Which relays to show based on who owns the hardware
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ownership {
+    #[default]
+    Any,
+    Owned,
+    Rented,
+}
+
+impl Ownership {
+    pub fn next(self) -> Self {
+        match self {
+            Ownership::Any => Ownership::Owned,
+            Ownership::Owned => Ownership::Rented,
+            Ownership::Rented => Ownership::Any,
+        }
+    }
+}
+
+/// Filter applied to the server list before it is grouped
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerFilter {
+    pub ownership: Ownership,
+    pub provider: Option<String>,
+}
+
+impl ServerFilter {
+    pub fn matches(&self, server: &Server) -> bool {
+        let provider_ok = self
+            .provider
+            .as_ref()
+            .is_none_or(|p| server.provider.eq_ignore_ascii_case(p));
+
+        self.matches_ownership(server) && provider_ok
+    }
+
+    /// Whether the ownership part of the filter lets a server through
+    pub fn matches_ownership(&self, server: &Server) -> bool {
+        match self.ownership {
+            Ownership::Any => true,
+            Ownership::Owned => server.owned,
+            Ownership::Rented => !server.owned,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Short description for the status bar (e.g. "owned, M247")
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        match self.ownership {
+            Ownership::Any => {}
+            Ownership::Owned => parts.push("owned".to_string()),
+            Ownership::Rented => parts.push("rented".to_string()),
+        }
+        if let Some(provider) = &self.provider {
+            parts.push(provider.clone());
+        }
+        parts.join(", ")
+    }
+}
+
 /// Grouped servers by Country -> City -> Vec<Server>
 pub type ServerTree = BTreeMap<String, BTreeMap<String, Vec<Server>>>;
 
@@ -53,6 +151,18 @@ pub fn group_servers(servers: &[Server]) -> ServerTree {
     tree
 }
 
+/// Get the sorted list of hosting providers
+pub fn get_providers<'a>(servers: impl IntoIterator<Item = &'a Server>) -> Vec<String> {
+    let mut providers: Vec<String> = servers
+        .into_iter()
+        .filter(|s| !s.provider.is_empty())
+        .map(|s| s.provider.clone())
+        .collect();
+    providers.sort();
+    providers.dedup();
+    providers
+}
+
 /// Get list of countries from server tree
 pub fn get_countries(tree: &ServerTree) -> Vec<String> {
     tree.keys().cloned().collect()
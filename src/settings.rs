@@ -0,0 +1,89 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::dns::DnsSettings;
+use crate::firewall::FirewallSettings;
+use crate::select::Strategy;
+use crate::server::ServerFilter;
+use crate::watchdog::WatchdogSettings;
+use crate::wireguard::Backend;
+
+/// Persistent user settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub filter: ServerFilter,
+    /// Sort the Servers view by measured latency instead of by code
+    pub sort_by_latency: bool,
+    /// How "connect to best server" picks a relay
+    pub strategy: Strategy,
+    /// How tunnels are brought up: natively over netlink or with wg-quick
+    pub backend: Backend,
+    /// Detection and recovery of dead tunnels
+    pub watchdog: WatchdogSettings,
+    /// Kill switch mode and the networks it leaves reachable
+    pub firewall: FirewallSettings,
+    /// How the system resolver is pointed at the tunnel
+    pub dns: DnsSettings,
+    /// Reach relays over IPv6 in generated configs, where they have an address
+    pub ipv6_endpoints: bool,
+    /// Address of the Mullvad API, if not the default (`MVTUI_API_URL` overrides it)
+    pub api_url: Option<String>,
+}
+
+/// Directory holding the server cache and other persistent state
+#[cfg(not(test))]
+pub fn data_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(|| PathBuf::from("/tmp"))
+        .join("mullvadtui")
+}
+
+/// Tests keep their state in a scratch directory
+#[cfg(test)]
+pub fn data_dir() -> PathBuf {
+    crate::testing::scratch_dir().join("data")
+}
+
+/// Directory holding what mvtui changed on the system, which has to survive
+/// the cache being cleared as long as the changes are in place
+#[cfg(not(test))]
+pub fn state_dir() -> PathBuf {
+    PathBuf::from("/var/lib/mullvadtui")
+}
+
+#[cfg(test)]
+pub fn state_dir() -> PathBuf {
+    crate::testing::scratch_dir().join("state")
+}
+
+fn settings_path() -> PathBuf {
+    data_dir().join("settings.json")
+}
+
+impl Settings {
+    /// Load settings, falling back to defaults if none were saved yet
+    pub fn load() -> Result<Self> {
+        let path = settings_path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(&path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = settings_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(&path, content)?;
+
+        Ok(())
+    }
+}
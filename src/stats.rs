@@ -0,0 +1,201 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::wireguard::TunnelStats;
+
+/// Minimum time between two samples, matching the UI poll interval
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Spacing of the points in the rate history
+pub const HISTORY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of points kept in the rate history (five minutes)
+pub const HISTORY_LEN: usize = 300;
+
+/// Average throughput over one `HISTORY_INTERVAL`, in bytes per second
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RatePoint {
+    pub rx: u64,
+    pub tx: u64,
+}
+
+/// Latest tunnel statistics and the throughput derived from consecutive samples
+#[derive(Debug, Default)]
+pub struct TunnelMonitor {
+    pub current: Option<TunnelStats>,
+    /// Receive rate in bytes per second
+    pub rx_rate: f64,
+    /// Transmit rate in bytes per second
+    pub tx_rate: f64,
+    /// Rate history of the current session, oldest first
+    pub history: VecDeque<RatePoint>,
+    sampled_at: Option<Instant>,
+    /// Counters at the start of the history point being accumulated
+    history_base: Option<(Instant, u64, u64)>,
+}
+
+impl TunnelMonitor {
+    /// Whether enough time passed since the last sample to take a new one
+    pub fn due(&self) -> bool {
+        self.sampled_at
+            .is_none_or(|at| at.elapsed() >= SAMPLE_INTERVAL)
+    }
+
+    /// Record a new sample, `None` if the tunnel is gone
+    pub fn update(&mut self, sample: Option<TunnelStats>) {
+        self.update_at(Instant::now(), sample);
+    }
+
+    fn update_at(&mut self, now: Instant, sample: Option<TunnelStats>) {
+        match (&self.current, &sample, self.sampled_at) {
+            (Some(previous), Some(sample), Some(at)) => {
+                let secs = now.duration_since(at).as_secs_f64();
+                if secs > 0.0 {
+                    // Counters restart when the interface is recreated
+                    self.rx_rate = sample.rx_bytes.saturating_sub(previous.rx_bytes) as f64 / secs;
+                    self.tx_rate = sample.tx_bytes.saturating_sub(previous.tx_bytes) as f64 / secs;
+                }
+            }
+            _ => {
+                self.rx_rate = 0.0;
+                self.tx_rate = 0.0;
+            }
+        }
+
+        if let Some(sample) = &sample {
+            self.record_history(now, sample);
+        }
+
+        self.current = sample;
+        self.sampled_at = Some(now);
+    }
+
+    fn record_history(&mut self, now: Instant, sample: &TunnelStats) {
+        let Some((at, rx, tx)) = self.history_base else {
+            self.history_base = Some((now, sample.rx_bytes, sample.tx_bytes));
+            return;
+        };
+
+        let elapsed = now.duration_since(at);
+        if elapsed < HISTORY_INTERVAL {
+            return;
+        }
+
+        let secs = elapsed.as_secs_f64();
+        self.history.push_back(RatePoint {
+            rx: (sample.rx_bytes.saturating_sub(rx) as f64 / secs) as u64,
+            tx: (sample.tx_bytes.saturating_sub(tx) as f64 / secs) as u64,
+        });
+        if self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history_base = Some((now, sample.rx_bytes, sample.tx_bytes));
+    }
+
+    /// Highest receive and transmit rate in the history
+    pub fn peak(&self) -> RatePoint {
+        self.history.iter().fold(RatePoint::default(), |peak, point| RatePoint {
+            rx: peak.rx.max(point.rx),
+            tx: peak.tx.max(point.tx),
+        })
+    }
+
+    /// Seconds since the tunnel last carried traffic in either direction,
+    /// `None` if there is no history yet or the latest point had traffic
+    pub fn idle_secs(&self) -> Option<u64> {
+        let idle = self
+            .history
+            .iter()
+            .rev()
+            .take_while(|p| p.rx == 0 && p.tx == 0)
+            .count();
+        (idle > 0).then(|| idle as u64 * HISTORY_INTERVAL.as_secs())
+    }
+
+    /// Forget everything, e.g. after disconnecting
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(rx_bytes: u64, tx_bytes: u64) -> Option<TunnelStats> {
+        Some(TunnelStats {
+            endpoint: None,
+            latest_handshake: None,
+            rx_bytes,
+            tx_bytes,
+            keepalive: None,
+        })
+    }
+
+    #[test]
+    fn rates_come_from_consecutive_samples() {
+        let start = Instant::now();
+        let mut monitor = TunnelMonitor::default();
+
+        monitor.update_at(start, sample(1000, 500));
+        assert_eq!((monitor.rx_rate, monitor.tx_rate), (0.0, 0.0));
+
+        monitor.update_at(start + Duration::from_millis(500), sample(3000, 1500));
+        assert_eq!((monitor.rx_rate, monitor.tx_rate), (4000.0, 2000.0));
+
+        // A recreated interface starts counting from zero again
+        monitor.update_at(start + Duration::from_secs(1), sample(100, 0));
+        assert_eq!((monitor.rx_rate, monitor.tx_rate), (0.0, 0.0));
+
+        monitor.update_at(start + Duration::from_millis(1250), None);
+        assert_eq!((monitor.rx_rate, monitor.tx_rate), (0.0, 0.0));
+        assert!(monitor.current.is_none());
+    }
+
+    #[test]
+    fn history_holds_one_point_per_second() {
+        let start = Instant::now();
+        let mut monitor = TunnelMonitor::default();
+
+        // Four samples a second, with 4000 bytes received in the first second
+        for i in 0..=4u64 {
+            monitor.update_at(start + SAMPLE_INTERVAL * i as u32, sample(i * 1000, i * 250));
+        }
+        assert_eq!(monitor.history.len(), 1);
+        assert_eq!((monitor.history[0].rx, monitor.history[0].tx), (4000, 1000));
+
+        // Idle seconds after the traffic
+        for i in 2..=4u64 {
+            monitor.update_at(start + HISTORY_INTERVAL * i as u32, sample(4000, 1000));
+        }
+        assert_eq!(monitor.history.len(), 4);
+        assert_eq!(monitor.idle_secs(), Some(3));
+        let peak = monitor.peak();
+        assert_eq!((peak.rx, peak.tx), (4000, 1000));
+    }
+
+    #[test]
+    fn history_keeps_the_latest_points() {
+        let start = Instant::now();
+        let mut monitor = TunnelMonitor::default();
+
+        for i in 0..=(HISTORY_LEN + 10) as u64 {
+            monitor.update_at(start + HISTORY_INTERVAL * i as u32, sample(i * i, 0));
+        }
+
+        assert_eq!(monitor.history.len(), HISTORY_LEN);
+        // The oldest kept point covers second 10 to 11
+        assert_eq!(monitor.history[0].rx, 21);
+        assert_eq!(monitor.idle_secs(), None);
+    }
+
+    #[test]
+    fn no_idle_time_without_history() {
+        let mut monitor = TunnelMonitor::default();
+        assert_eq!(monitor.idle_secs(), None);
+
+        monitor.update_at(Instant::now(), sample(0, 0));
+        assert_eq!(monitor.idle_secs(), None);
+        assert_eq!(monitor.peak().rx, 0);
+    }
+}
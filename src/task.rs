@@ -0,0 +1,244 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::mpsc::{self, error::TryRecvError, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+/// Frames of the spinner shown while a task runs
+const SPINNER: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+
+/// Message from a running task to the event loop
+enum Update<T> {
+    /// What the task is doing now
+    Progress(String),
+    /// The task finished with this result
+    Done(T),
+}
+
+/// Handed to a task to report what it is doing and learn whether it was cancelled
+pub struct Progress<T> {
+    updates: UnboundedSender<Update<T>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl<T> Progress<T> {
+    pub fn report(&self, status: impl Into<String>) {
+        // The receiver is gone once the app stopped caring about the task
+        let _ = self.updates.send(Update::Progress(status.into()));
+    }
+
+    /// Whether the user asked to stop. Blocking jobs check this between steps.
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// A long operation running off the event loop, so the UI keeps drawing and
+/// reading keys. Progress and the result arrive over a channel.
+pub struct Task<T> {
+    status: String,
+    updates: UnboundedReceiver<Update<T>>,
+    handle: JoinHandle<()>,
+    cancelled: Arc<AtomicBool>,
+    /// Async jobs can be dropped at any await; blocking ones have to wind down themselves
+    abortable: bool,
+    frame: usize,
+}
+
+impl<T: Send + 'static> Task<T> {
+    /// Run an async job, e.g. talking to the API
+    pub fn spawn<F, Fut>(status: impl Into<String>, job: F) -> Self
+    where
+        F: FnOnce(Progress<T>) -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+    {
+        let (tx, updates) = mpsc::unbounded_channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let future = job(Progress {
+            updates: tx.clone(),
+            cancelled: cancelled.clone(),
+        });
+        let handle = tokio::spawn(async move {
+            let _ = tx.send(Update::Done(future.await));
+        });
+        Self::new(status.into(), updates, handle, cancelled, true)
+    }
+
+    /// Run a job that blocks on external programs, e.g. bringing up the tunnel
+    pub fn spawn_blocking<F>(status: impl Into<String>, job: F) -> Self
+    where
+        F: FnOnce(Progress<T>) -> T + Send + 'static,
+    {
+        let (tx, updates) = mpsc::unbounded_channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let progress = Progress {
+            updates: tx.clone(),
+            cancelled: cancelled.clone(),
+        };
+        let handle = tokio::task::spawn_blocking(move || {
+            let _ = tx.send(Update::Done(job(progress)));
+        });
+        Self::new(status.into(), updates, handle, cancelled, false)
+    }
+
+    fn new(
+        status: String,
+        updates: UnboundedReceiver<Update<T>>,
+        handle: JoinHandle<()>,
+        cancelled: Arc<AtomicBool>,
+        abortable: bool,
+    ) -> Self {
+        Self {
+            status,
+            updates,
+            handle,
+            cancelled,
+            abortable,
+            frame: 0,
+        }
+    }
+
+    /// Ask the task to stop. Returns true if it stopped right away; a blocking
+    /// job first undoes what it did and then still delivers its result.
+    pub fn cancel(&mut self) -> bool {
+        self.cancelled.store(true, Ordering::Relaxed);
+        if self.abortable {
+            self.handle.abort();
+            return true;
+        }
+        self.status = "Cancelling...".to_string();
+        false
+    }
+
+    /// Take the progress reported so far and advance the spinner.
+    /// Returns the result once the task is done.
+    pub fn poll(&mut self) -> Option<Result<T>> {
+        self.frame = self.frame.wrapping_add(1);
+        loop {
+            match self.updates.try_recv() {
+                Ok(Update::Progress(status)) => self.status = status,
+                Ok(Update::Done(result)) => return Some(Ok(result)),
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => return Some(Err(self.died())),
+            }
+        }
+    }
+
+    /// Wait for the result, ignoring progress
+    pub async fn wait(mut self) -> Result<T> {
+        while let Some(update) = self.updates.recv().await {
+            if let Update::Done(result) = update {
+                return Ok(result);
+            }
+        }
+        Err(self.died())
+    }
+
+    /// The sender was dropped without a result, so the job panicked
+    fn died(&self) -> anyhow::Error {
+        anyhow!("{} stopped unexpectedly", self.status.trim_end_matches("..."))
+    }
+
+    /// What the task is doing now
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+
+    /// Current spinner frame
+    pub fn spinner(&self) -> &'static str {
+        SPINNER[self.frame % SPINNER.len()]
+    }
+}
+
+impl<T> Drop for Task<T> {
+    /// Nobody is waiting for the result any more. Blocking jobs run to completion regardless.
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_progress_then_result() {
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let mut task = Task::spawn("Starting...", |progress| async move {
+            progress.report("Halfway...");
+            released.await.unwrap();
+            42
+        });
+        assert_eq!(task.status(), "Starting...");
+
+        tokio::task::yield_now().await;
+        assert!(task.poll().is_none());
+        assert_eq!(task.status(), "Halfway...");
+
+        release.send(()).unwrap();
+        assert_eq!(task.wait().await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn blocking_job_result_is_polled() {
+        let mut task = Task::spawn_blocking("Working...", |progress| {
+            progress.report("Almost...");
+            "done"
+        });
+
+        let result = loop {
+            if let Some(result) = task.poll() {
+                break result;
+            }
+            tokio::task::yield_now().await;
+        };
+        assert_eq!(result.unwrap(), "done");
+    }
+
+    #[tokio::test]
+    async fn panicking_job_is_reported() {
+        let task: Task<()> = Task::spawn_blocking("Connecting to se-got-wg-001...", |_| panic!("boom"));
+
+        let error = task.wait().await.unwrap_err();
+        assert_eq!(error.to_string(), "Connecting to se-got-wg-001 stopped unexpectedly");
+    }
+
+    #[tokio::test]
+    async fn cancel_drops_async_job() {
+        let (_release, released) = tokio::sync::oneshot::channel::<()>();
+        let (finished, finish) = tokio::sync::oneshot::channel::<()>();
+        let mut task: Task<()> = Task::spawn("Fetching servers...", |_| async move {
+            let _ = released.await;
+            let _ = finished.send(());
+        });
+
+        assert!(task.cancel());
+        // Dropping the job drops its sender without sending
+        assert!(finish.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cancel_lets_blocking_job_wind_down() {
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let mut task = Task::spawn_blocking("Connecting...", move |progress| {
+            released.recv().unwrap();
+            progress.cancelled()
+        });
+
+        assert!(!task.cancel());
+        assert_eq!(task.status(), "Cancelling...");
+        release.send(()).unwrap();
+        assert!(task.wait().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn spinner_advances_on_poll() {
+        let mut task: Task<()> = Task::spawn("Waiting...", |_| std::future::pending());
+
+        let first = task.spinner();
+        task.poll();
+        assert_ne!(task.spinner(), first);
+    }
+}
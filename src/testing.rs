@@ -0,0 +1,253 @@
+//! Helpers shared by the unit tests: a scratch directory standing in for the
+//! cache and /etc/wireguard, an in-process stand-in for the Mullvad API and
+//! sample app states.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::app::App;
+use crate::journal::{self, Journal};
+use crate::runner::FakeRunner;
+use crate::server::Server;
+
+/// Relay list recorded from `/public/relays/wireguard/v1/`, trimmed to a few relays
+pub const RELAYS_FIXTURE: &str = include_str!("../tests/fixtures/relays.json");
+
+/// Directory the data and config paths point to in tests, emptied once per run
+pub fn scratch_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("mvtui-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("create scratch directory");
+        dir
+    })
+}
+
+/// Tests using the state journal take turns, each starting with an empty one.
+/// The lock can be held across awaits, and taken with or without a runtime.
+pub fn lock_journal() -> tokio::sync::MutexGuard<'static, ()> {
+    static JOURNAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let guard = loop {
+        match JOURNAL.try_lock() {
+            Ok(guard) => break guard,
+            Err(_) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    journal::save(&Journal::default()).expect("reset journal");
+    guard
+}
+
+/// A request the stand-in received
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+#[derive(Default)]
+struct State {
+    relays: String,
+    /// Answers to `POST /wg`, used once each in order
+    wg: VecDeque<Response>,
+    requests: Vec<Request>,
+}
+
+/// Serves the relay list fixture and scripted `/wg` answers on a local port
+pub struct MockApi {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockApi {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind mock API");
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State {
+            relays: RELAYS_FIXTURE.to_string(),
+            ..Default::default()
+        }));
+
+        let shared = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, shared.clone()));
+            }
+        });
+
+        Self { addr, state }
+    }
+
+    /// Base URL to hand to the API functions
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Answer the next key registration with plain text
+    pub fn respond_wg(&self, status: u16, body: &str) -> &Self {
+        self.push_wg(status, "text/plain", body)
+    }
+
+    /// Answer the next key registration with an HTML page, like a proxy or an outage would
+    pub fn respond_wg_html(&self, status: u16, body: &str) -> &Self {
+        self.push_wg(status, "text/html", body)
+    }
+
+    fn push_wg(&self, status: u16, content_type: &'static str, body: &str) -> &Self {
+        self.state.lock().unwrap().wg.push_back(Response {
+            status,
+            content_type,
+            body: body.to_string(),
+        });
+        self
+    }
+
+    /// Requests received so far
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+/// Answer one request and close the connection
+async fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let Some(request) = read_request(&mut stream).await else {
+        return;
+    };
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/public/relays/wireguard/v1/") => Response {
+                status: 200,
+                content_type: "application/json",
+                body: state.relays.clone(),
+            },
+            ("POST", "/wg") => state.wg.pop_front().unwrap_or(Response {
+                status: 500,
+                content_type: "text/plain",
+                body: "no scripted response".to_string(),
+            }),
+            _ => Response {
+                status: 404,
+                content_type: "text/plain",
+                body: "not found".to_string(),
+            },
+        }
+    };
+
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        502 => "Bad Gateway",
+        _ => "Status",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+    let _ = stream.write_all(head.as_bytes()).await;
+    let _ = stream.write_all(response.body.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+
+    // Read up to the end of the headers
+    let header_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0);
+
+    while buf.len() < header_end + content_length {
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    Some(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&buf[header_end..]).to_string(),
+    })
+}
+
+/// Servers for UI tests. Their codes are not in the relay fixture, so no
+/// other test generates configs for them and they always show [NO CONFIG].
+pub fn sample_servers() -> Vec<Server> {
+    let server = |code: &str, country: &str, city: &str, ipv4: &str, owned: bool, provider: &str| Server {
+        code: code.to_string(),
+        hostname: code.to_string(),
+        public_key: "5VRvAFF5vrELTzOD7ZZcVnQHv0O+dRKy2UDkVQDcEmw=".to_string(),
+        ipv4_addr: ipv4.to_string(),
+        port: 51820,
+        country: country.to_string(),
+        city: city.to_string(),
+        ipv6_addr: None,
+        multihop_port: None,
+        country_code: code[..2].to_string(),
+        city_code: code[3..6].to_string(),
+        active: true,
+        owned,
+        provider: provider.to_string(),
+        socks_name: None,
+        weight: 100,
+    };
+
+    let mut offline = server("de-fra-wg-003", "Germany", "Frankfurt", "185.209.196.73", false, "xtom");
+    offline.active = false;
+    vec![
+        server("de-fra-wg-001", "Germany", "Frankfurt", "185.209.196.71", true, "31173"),
+        server("de-fra-wg-002", "Germany", "Frankfurt", "185.209.196.72", false, "xtom"),
+        offline,
+        server("de-ber-wg-001", "Germany", "Berlin", "193.32.248.66", false, "M247"),
+        server("nl-ams-wg-101", "Netherlands", "Amsterdam", "193.32.249.69", true, "31173"),
+    ]
+}
+
+/// App showing the sample servers, with a fake runner so nothing touches the system
+pub fn sample_app() -> App {
+    let mut app = App::new();
+    app.runner = Arc::new(FakeRunner::new());
+    app.set_servers(sample_servers());
+    app
+}
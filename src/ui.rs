@@ -2,29 +2,40 @@ use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Sparkline},
     Frame,
 };
 
-use crate::app::{App, InputMode, View};
+use crate::app::{unix_time, App, InputMode, View};
 use crate::config;
+use crate::dns::Blocklist;
+use crate::firewall::KillSwitch;
+use crate::history::{self, Action};
+use crate::search::MatchField;
+use crate::server::Server;
+use crate::stats::HISTORY_INTERVAL;
 use crate::wireguard::ConnectionStatus;
 
 pub fn draw(frame: &mut Frame, app: &App) {
+    // The stats panel is only shown while a tunnel is up
+    let stats_height = if app.tunnel.current.is_some() { 3 } else { 0 };
+
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
-            Constraint::Length(3), // Title/status bar
-            Constraint::Min(0),    // Main content
-            Constraint::Length(3), // Help bar
-            Constraint::Length(3), // Message bar
+            Constraint::Length(3),            // Title/status bar
+            Constraint::Min(0),               // Main content
+            Constraint::Length(stats_height), // Tunnel statistics
+            Constraint::Length(3),            // Help bar
+            Constraint::Length(3),            // Message bar
         ])
         .split(frame.area());
 
     draw_status_bar(frame, app, chunks[0]);
     draw_main_content(frame, app, chunks[1]);
-    draw_help_bar(frame, app, chunks[2]);
-    draw_message_bar(frame, app, chunks[3]);
+    draw_stats_panel(frame, app, chunks[2]);
+    draw_help_bar(frame, app, chunks[3]);
+    draw_message_bar(frame, app, chunks[4]);
 }
 
 fn draw_status_bar(frame: &mut Frame, app: &App, area: Rect) {
@@ -40,7 +51,18 @@ fn draw_status_bar(frame: &mut Frame, app: &App, area: Rect) {
         ConnectionStatus::Disconnected => Color::Red,
     };
 
-    let title = format!(" Mullvad TUI | {} ", status_text);
+    let mut title = format!(" Mullvad TUI | {} ", status_text);
+    match app.settings.firewall.kill_switch {
+        KillSwitch::Off => {}
+        KillSwitch::On => title.push_str("| KILL SWITCH "),
+        KillSwitch::Lockdown => title.push_str("| LOCKDOWN "),
+    }
+    if app.settings.filter.is_active() {
+        title.push_str(&format!("| FILTER: {} ", app.settings.filter.describe()));
+    }
+    if let Some(task) = &app.task {
+        title.push_str(&format!("| {} {} ", task.spinner(), task.status()));
+    }
 
     let block = Block::default()
         .borders(Borders::ALL)
@@ -52,9 +74,165 @@ fn draw_status_bar(frame: &mut Frame, app: &App, area: Rect) {
     frame.render_widget(block, area);
 }
 
+fn draw_stats_panel(frame: &mut Frame, app: &App, area: Rect) {
+    let (Some(stats), ConnectionStatus::Connected(code)) =
+        (&app.tunnel.current, &app.connection_status)
+    else {
+        return;
+    };
+
+    let now = unix_time();
+    let uptime = history::connected_since(&app.history, code)
+        .map(|since| format_duration(now.saturating_sub(since)))
+        .unwrap_or_else(|| "-".to_string());
+    let handshake = stats
+        .latest_handshake
+        .map(|t| format!("{} ago", format_duration(now.saturating_sub(t))))
+        .unwrap_or_else(|| "none".to_string());
+    let keepalive = stats
+        .keepalive
+        .map(|k| format!("{}s", k))
+        .unwrap_or_else(|| "off".to_string());
+
+    let label = Style::default().fg(Color::DarkGray);
+    let line = Line::from(vec![
+        Span::styled("↓ ", label),
+        Span::styled(format_rate(app.tunnel.rx_rate), Style::default().fg(Color::Green)),
+        Span::styled(format!(" ({})  ", format_bytes(stats.rx_bytes)), label),
+        Span::styled("↑ ", label),
+        Span::styled(format_rate(app.tunnel.tx_rate), Style::default().fg(Color::Cyan)),
+        Span::styled(format!(" ({})  ", format_bytes(stats.tx_bytes)), label),
+        Span::styled("Uptime ", label),
+        Span::raw(format!("{}  ", uptime)),
+        Span::styled("Handshake ", label),
+        Span::raw(format!("{}  ", handshake)),
+        Span::styled("Keepalive ", label),
+        Span::raw(format!("{}  ", keepalive)),
+        Span::styled("Endpoint ", label),
+        Span::raw(format!("{}  ", stats.endpoint.as_deref().unwrap_or("-"))),
+        Span::styled("DNS ", label),
+        Span::raw(app.dns_backend.label()),
+    ]);
+
+    let panel = Paragraph::new(line).block(Block::default().borders(Borders::ALL).title(" Tunnel "));
+    frame.render_widget(panel, area);
+}
+
+fn draw_stats_view(frame: &mut Frame, app: &App, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),      // Summary
+            Constraint::Percentage(35), // Download graph
+            Constraint::Percentage(35), // Upload graph
+            Constraint::Min(3),         // Watchdog log
+        ])
+        .split(area);
+
+    let monitor = &app.tunnel;
+    let peak = monitor.peak();
+
+    let summary = if monitor.current.is_none() {
+        Line::from(Span::styled(
+            "Not connected. Graphs start when a tunnel is up.",
+            Style::default().fg(Color::DarkGray),
+        ))
+    } else {
+        let (status, color) = match monitor.idle_secs() {
+            Some(secs) => (format!("no traffic for {}", format_duration(secs)), Color::Yellow),
+            None if monitor.history.is_empty() => ("collecting...".to_string(), Color::DarkGray),
+            None => ("carrying traffic".to_string(), Color::Green),
+        };
+        Line::from(vec![
+            Span::styled(status, Style::default().fg(color)),
+            Span::styled(
+                format!(
+                    "  | last {} | peak ↓ {} ↑ {}",
+                    format_duration(monitor.history.len() as u64 * HISTORY_INTERVAL.as_secs()),
+                    format_rate(peak.rx as f64),
+                    format_rate(peak.tx as f64)
+                ),
+                Style::default().fg(Color::DarkGray),
+            ),
+        ])
+    };
+    let summary = Paragraph::new(summary)
+        .block(Block::default().borders(Borders::ALL).title(" Session "));
+    frame.render_widget(summary, chunks[0]);
+
+    // Show as many of the most recent points as fit inside the borders
+    let width = chunks[1].width.saturating_sub(2) as usize;
+    let skip = monitor.history.len().saturating_sub(width);
+    let rx: Vec<u64> = monitor.history.iter().skip(skip).map(|p| p.rx).collect();
+    let tx: Vec<u64> = monitor.history.iter().skip(skip).map(|p| p.tx).collect();
+
+    let rx_graph = Sparkline::default()
+        .block(Block::default().borders(Borders::ALL).title(format!(
+            " Download {} ",
+            format_rate(monitor.rx_rate)
+        )))
+        .data(&rx)
+        .style(Style::default().fg(Color::Green));
+    frame.render_widget(rx_graph, chunks[1]);
+
+    let tx_graph = Sparkline::default()
+        .block(Block::default().borders(Borders::ALL).title(format!(
+            " Upload {} ",
+            format_rate(monitor.tx_rate)
+        )))
+        .data(&tx)
+        .style(Style::default().fg(Color::Cyan));
+    frame.render_widget(tx_graph, chunks[2]);
+
+    // Newest log lines last, keeping the latest visible
+    let visible = chunks[3].height.saturating_sub(2) as usize;
+    let skip = app.watchdog.log.len().saturating_sub(visible);
+    let lines: Vec<Line> = app
+        .watchdog
+        .log
+        .iter()
+        .skip(skip)
+        .map(|entry| {
+            Line::from(vec![
+                Span::styled(format!("{} ", entry.local_time()), Style::default().fg(Color::DarkGray)),
+                Span::raw(entry.message.clone()),
+            ])
+        })
+        .collect();
+    let title = if app.settings.watchdog.enabled {
+        " Watchdog "
+    } else {
+        " Watchdog (disabled) "
+    };
+    let log = Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title));
+    frame.render_widget(log, chunks[3]);
+}
+
+/// Human readable byte count, e.g. "12.3 MiB"
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_rate(bytes_per_sec: f64) -> String {
+    format!("{}/s", format_bytes(bytes_per_sec as u64))
+}
+
 fn draw_main_content(frame: &mut Frame, app: &App, area: Rect) {
     match app.view {
         View::Setup => draw_setup_view(frame, app, area),
+        View::Stats => draw_stats_view(frame, app, area),
+        View::Search => draw_search_view(frame, app, area),
         _ => draw_list_view(frame, app, area),
     }
 }
@@ -116,49 +294,281 @@ fn draw_list_view(frame: &mut Frame, app: &App, area: Rect) {
         View::Servers => {
             let city = app.selected_city.as_deref().unwrap_or("Unknown");
             let country = app.selected_country.as_deref().unwrap_or("Unknown");
-            let title = format!(" {}, {} - Select Server ", city, country);
+            let sort = if app.settings.sort_by_latency { "latency" } else { "name" };
+            let title = format!(" {}, {} - Select Server (by {}) ", city, country, sort);
             let items: Vec<ListItem> = app
                 .city_servers
                 .iter()
+                .map(|server| ListItem::new(Line::from(server_row(app, server))))
+                .collect();
+            (title, items)
+        }
+        View::Favorites => {
+            let title = " Favorites ".to_string();
+            let items: Vec<ListItem> = app
+                .favorite_servers()
+                .into_iter()
                 .map(|server| {
-                    let has_config = config::config_exists(&server.code);
-                    let connected = matches!(&app.connection_status,
-                        ConnectionStatus::Connected(c) if c == &server.code);
-                    let is_autostart = app.autostart_server.as_ref() == Some(&server.code);
-
-                    let status_indicator = if connected {
-                        Span::styled(" [CONNECTED] ", Style::default().fg(Color::Green))
-                    } else if has_config {
-                        Span::styled(" [OK] ", Style::default().fg(Color::Blue))
-                    } else {
-                        Span::styled(" [NO CONFIG] ", Style::default().fg(Color::Yellow))
+                    let mut spans = server_row(app, server);
+                    spans.push(Span::styled(
+                        format!("  {}", server.location()),
+                        Style::default().fg(Color::DarkGray),
+                    ));
+                    ListItem::new(Line::from(spans))
+                })
+                .collect();
+            (title, items)
+        }
+        View::History => {
+            let title = " Connection History ".to_string();
+            let items: Vec<ListItem> = (0..app.history.len())
+                .filter_map(|idx| app.history_entry(idx))
+                .map(|entry| {
+                    let action = match entry.action {
+                        Action::Connect => "connect",
+                        Action::Disconnect => "disconnect",
+                    };
+                    let outcome = match (&entry.error, entry.duration) {
+                        (Some(error), _) => {
+                            Span::styled(format!(" {}", error), Style::default().fg(Color::Red))
+                        }
+                        (None, Some(duration)) => Span::styled(
+                            format!(" after {}", format_duration(duration)),
+                            Style::default().fg(Color::DarkGray),
+                        ),
+                        (None, None) => Span::styled(" OK", Style::default().fg(Color::Green)),
                     };
 
-                    let autostart_indicator = if is_autostart {
-                        Span::styled("[AUTOSTART] ", Style::default().fg(Color::Magenta))
+                    ListItem::new(Line::from(vec![
+                        Span::styled(format!("{} ", entry.local_time()), Style::default().fg(Color::DarkGray)),
+                        Span::styled(format!("{:<11}", action), Style::default().fg(Color::White)),
+                        Span::styled(format!("{:<20}", entry.code), Style::default().fg(Color::White)),
+                        outcome,
+                    ]))
+                })
+                .collect();
+            (title, items)
+        }
+        View::Dns => {
+            let title = format!(" DNS Content Blocking - resolver {} ", app.settings.dns.resolver());
+            let items: Vec<ListItem> = Blocklist::ALL
+                .iter()
+                .map(|list| {
+                    let (mark, color) = if app.settings.dns.is_blocked(*list) {
+                        ("[x] ", Color::Green)
                     } else {
-                        Span::raw("")
+                        ("[ ] ", Color::DarkGray)
                     };
-
                     ListItem::new(Line::from(vec![
-                        Span::styled(
-                            format!("{:<20}", server.code),
-                            Style::default().fg(Color::White),
-                        ),
-                        status_indicator,
-                        autostart_indicator,
-                        Span::styled(
-                            format!(" {}", server.ipv4_addr),
-                            Style::default().fg(Color::DarkGray),
-                        ),
+                        Span::styled(mark, Style::default().fg(color)),
+                        Span::styled(list.label(), Style::default().fg(Color::White)),
                     ]))
                 })
                 .collect();
             (title, items)
         }
-        View::Setup => unreachable!(),
+        View::Setup | View::Search | View::Stats => unreachable!(),
     };
 
+    render_list(frame, app, area, title, items);
+}
+
+/// Search input box with the flat list of matching servers below it
+fn draw_search_view(frame: &mut Frame, app: &App, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(0)])
+        .split(area);
+
+    let input_style = match app.input_mode {
+        InputMode::Search => Style::default().fg(Color::Yellow),
+        _ => Style::default(),
+    };
+    let input = Paragraph::new(app.search_query.as_str())
+        .style(input_style)
+        .block(Block::default().borders(Borders::ALL).title(" Search "));
+    frame.render_widget(input, chunks[0]);
+
+    if app.input_mode == InputMode::Search {
+        frame.set_cursor_position((
+            chunks[0].x + app.search_query.chars().count() as u16 + 1,
+            chunks[0].y + 1,
+        ));
+    }
+
+    let highlight = Style::default()
+        .fg(Color::Yellow)
+        .add_modifier(Modifier::BOLD);
+    let items: Vec<ListItem> = app
+        .search_results
+        .iter()
+        .map(|result| {
+            let server = &result.server;
+            let name_style = server_name_style(server);
+            let location_style = Style::default().fg(Color::DarkGray);
+            let no_match: &[usize] = &[];
+            let indices_for = |field: MatchField| {
+                if result.field == field {
+                    result.indices.as_slice()
+                } else {
+                    no_match
+                }
+            };
+
+            // Show the hostname in place of the code when that is what matched
+            let (name, name_field) = if result.field == MatchField::Hostname {
+                (server.hostname.as_str(), MatchField::Hostname)
+            } else {
+                (server.code.as_str(), MatchField::Code)
+            };
+
+            let mut spans = vec![favorite_indicator(app, server)];
+            spans.extend(highlight_matches(name, indices_for(name_field), name_style, highlight));
+            spans.push(Span::raw(
+                " ".repeat(20usize.saturating_sub(name.chars().count())),
+            ));
+            spans.extend(status_indicators(app, server));
+            spans.push(Span::raw(" "));
+            spans.extend(highlight_matches(
+                &server.city,
+                indices_for(MatchField::City),
+                location_style,
+                highlight,
+            ));
+            spans.push(Span::styled(", ", location_style));
+            spans.extend(highlight_matches(
+                &server.country,
+                indices_for(MatchField::Country),
+                location_style,
+                highlight,
+            ));
+
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+
+    let title = format!(" {} matches ", app.search_results.len());
+    render_list(frame, app, chunks[1], title, items);
+}
+
+/// Human readable duration (e.g. "1h 05m", "3m 12s")
+fn format_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    if h > 0 {
+        format!("{}h {:02}m", h, m)
+    } else if m > 0 {
+        format!("{}m {:02}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
+
+/// Split text into spans, applying `highlight` to the characters at `indices`
+fn highlight_matches(
+    text: &str,
+    indices: &[usize],
+    style: Style,
+    highlight: Style,
+) -> Vec<Span<'static>> {
+    text.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let style = if indices.contains(&i) { highlight } else { style };
+            Span::styled(c.to_string(), style)
+        })
+        .collect()
+}
+
+/// Row shared by the Servers and Favorites views
+fn server_row(app: &App, server: &Server) -> Vec<Span<'static>> {
+    let mut spans = vec![
+        favorite_indicator(app, server),
+        Span::styled(format!("{:<20}", server.code), server_name_style(server)),
+    ];
+    spans.extend(status_indicators(app, server));
+    spans.extend([
+        latency_column(app, server),
+        Span::styled(
+            format!(" {:<15}", server.ipv4_addr),
+            Style::default().fg(Color::DarkGray),
+        ),
+        Span::styled(
+            format!(" {}", server.provider),
+            Style::default().fg(Color::DarkGray),
+        ),
+        if server.owned {
+            Span::styled(" (owned)", Style::default().fg(Color::Cyan))
+        } else {
+            Span::raw("")
+        },
+    ]);
+    spans
+}
+
+/// Measured latency, "--" if the last probe got no answer, blank if never probed
+fn latency_column(app: &App, server: &Server) -> Span<'static> {
+    if !app.latencies.contains_key(&server.code) {
+        return Span::raw(" ".repeat(8));
+    }
+
+    match app.latency_ms(&server.code) {
+        Some(ms) => {
+            let color = if ms < 50.0 {
+                Color::Green
+            } else if ms < 150.0 {
+                Color::Yellow
+            } else {
+                Color::Red
+            };
+            Span::styled(format!("{:>5.0} ms", ms), Style::default().fg(color))
+        }
+        None => Span::styled(format!("{:>8}", "--"), Style::default().fg(Color::DarkGray)),
+    }
+}
+
+fn favorite_indicator(app: &App, server: &Server) -> Span<'static> {
+    if app.is_favorite(&server.code) {
+        Span::styled("★ ", Style::default().fg(Color::Yellow))
+    } else {
+        Span::raw("  ")
+    }
+}
+
+fn server_name_style(server: &Server) -> Style {
+    Style::default().fg(if server.active {
+        Color::White
+    } else {
+        Color::DarkGray
+    })
+}
+
+/// Connection/config/offline indicator followed by the autostart indicator
+fn status_indicators(app: &App, server: &Server) -> [Span<'static>; 2] {
+    let has_config = config::config_exists(&server.code);
+    let connected = matches!(&app.connection_status,
+        ConnectionStatus::Connected(c) if c == &server.code);
+    let is_autostart = app.autostart_server.as_ref() == Some(&server.code);
+
+    let status_indicator = if connected {
+        Span::styled(" [CONNECTED] ", Style::default().fg(Color::Green))
+    } else if !server.active {
+        Span::styled(" [OFFLINE] ", Style::default().fg(Color::DarkGray))
+    } else if has_config {
+        Span::styled(" [OK] ", Style::default().fg(Color::Blue))
+    } else {
+        Span::styled(" [NO CONFIG] ", Style::default().fg(Color::Yellow))
+    };
+
+    let autostart_indicator = if is_autostart {
+        Span::styled("[AUTOSTART] ", Style::default().fg(Color::Magenta))
+    } else {
+        Span::raw("")
+    };
+
+    [status_indicator, autostart_indicator]
+}
+
+fn render_list(frame: &mut Frame, app: &App, area: Rect, title: String, items: Vec<ListItem>) {
     let list = List::new(items)
         .block(Block::default().borders(Borders::ALL).title(title))
         .highlight_style(
@@ -201,7 +611,7 @@ fn draw_setup_view(frame: &mut Frame, app: &App, area: Rect) {
     // Input field
     let input_style = match app.input_mode {
         InputMode::AccountInput => Style::default().fg(Color::Yellow),
-        InputMode::Normal => Style::default(),
+        _ => Style::default(),
     };
 
     let input = Paragraph::new(app.input_buffer.as_str())
@@ -225,17 +635,37 @@ fn draw_setup_view(frame: &mut Frame, app: &App, area: Rect) {
 
 fn draw_help_bar(frame: &mut Frame, app: &App, area: Rect) {
     let help_text = match (&app.view, &app.input_mode) {
+        // Esc stops the task instead of its usual action
+        _ if app.task.is_some() => " Esc/Ctrl-C: Cancel | ↑/↓: Navigate | q: Quit ",
         (View::Setup, InputMode::AccountInput) => {
             " Enter: Submit | Esc: Cancel "
         }
+        (View::Search, InputMode::Search) => {
+            " Type to search | ↑/↓: Navigate | Enter: Connect | Esc: Cancel "
+        }
+        (View::Search, _) => {
+            " ↑/↓: Navigate | Enter: Connect | /: Edit search | f: Favorite | Esc: Back | d: Disconnect | q: Quit "
+        }
         (View::Countries, _) => {
-            " ↑/↓: Navigate | Enter: Select | r: Refresh | i: Setup | d: Disconnect | q: Quit "
+            " ↑/↓: Navigate | Enter: Select | /: Search | F: Favorites | H: History | g: Graphs | D: DNS | b: Connect best | c: Reconnect last | p: Ping all | r: Refresh | i: Setup | K: Kill switch | o/v: Owner/Provider filter | d: Disconnect | q: Quit "
         }
         (View::Cities, _) => {
-            " ↑/↓: Navigate | Enter: Select | Esc: Back | d: Disconnect | q: Quit "
+            " ↑/↓: Navigate | Enter: Select | /: Search | b: Connect best | p: Ping country | Esc: Back | o/v: Owner/Provider filter | d: Disconnect | q: Quit "
         }
         (View::Servers, _) => {
-            " ↑/↓: Navigate | Enter: Connect | e: Toggle Autostart | Esc: Back | d: Disconnect | q: Quit "
+            " ↑/↓: Navigate | Enter: Connect | /: Search | f: Favorite | e: Toggle Autostart | p: Ping | S: Sort | Esc: Back | d: Disconnect | q: Quit "
+        }
+        (View::History, _) => {
+            " ↑/↓: Navigate | Enter: Connect | c: Reconnect last | Esc: Back | d: Disconnect | q: Quit "
+        }
+        (View::Stats, _) => {
+            " Esc: Back | c: Reconnect last | d: Disconnect | q: Quit "
+        }
+        (View::Dns, _) => {
+            " ↑/↓: Navigate | Enter: Toggle blocking | Esc: Back | d: Disconnect | q: Quit "
+        }
+        (View::Favorites, _) => {
+            " ↑/↓: Navigate | Enter: Connect | f: Unfavorite | e: Toggle Autostart | Esc: Back | d: Disconnect | q: Quit "
         }
         _ => "",
     };
@@ -249,11 +679,14 @@ fn draw_help_bar(frame: &mut Frame, app: &App, area: Rect) {
 
 fn draw_message_bar(frame: &mut Frame, app: &App, area: Rect) {
     let (text, color) = if let Some(ref error) = app.error {
-        (error.as_str(), Color::Red)
+        match app.remedy() {
+            Some(remedy) => (format!("{} (x: {})", error, remedy.label()), Color::Red),
+            None => (error.clone(), Color::Red),
+        }
     } else if let Some(ref message) = app.message {
-        (message.as_str(), Color::Green)
+        (message.clone(), Color::Green)
     } else {
-        ("", Color::White)
+        (String::new(), Color::White)
     };
 
     let message = Paragraph::new(text)
@@ -262,3 +695,188 @@ fn draw_message_bar(frame: &mut Frame, app: &App, area: Rect) {
 
     frame.render_widget(message, area);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    use ratatui::{backend::TestBackend, buffer::Buffer, Terminal};
+
+    use super::*;
+    use crate::dns::Blocklist;
+    use crate::history::HistoryEntry;
+    use crate::latency::Latency;
+    use crate::task::Task;
+    use crate::testing::sample_app;
+
+    /// Render the app and compare it to `tests/snapshots/<name>.txt`.
+    /// Run with UPDATE_SNAPSHOTS=1 to accept changes or write new snapshots.
+    fn assert_snapshot(name: &str, app: &App) {
+        let mut terminal = Terminal::new(TestBackend::new(110, 24)).unwrap();
+        terminal.draw(|f| draw(f, app)).unwrap();
+        let rendered = buffer_text(terminal.backend().buffer());
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/snapshots")
+            .join(format!("{}.txt", name));
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &rendered).unwrap();
+            return;
+        }
+        assert!(
+            path.exists(),
+            "missing snapshot {}; rerun with UPDATE_SNAPSHOTS=1 to write it\n--- rendered\n{}",
+            name,
+            rendered
+        );
+
+        let expected = fs::read_to_string(&path).unwrap();
+        assert!(
+            rendered == expected,
+            "snapshot {} changed; rerun with UPDATE_SNAPSHOTS=1 to accept\n--- expected\n{}\n--- rendered\n{}",
+            name,
+            expected,
+            rendered
+        );
+    }
+
+    /// Text of the buffer, one line per row without trailing spaces
+    fn buffer_text(buffer: &Buffer) -> String {
+        let area = buffer.area;
+        let mut text = String::new();
+        for y in area.top()..area.bottom() {
+            let line: String = (area.left()..area.right())
+                .map(|x| buffer[(x, y)].symbol())
+                .collect();
+            text.push_str(line.trim_end());
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Open the Servers view for Frankfurt
+    fn show_frankfurt(app: &mut App) {
+        app.select();
+        app.select();
+    }
+
+    #[test]
+    fn empty_cache() {
+        let mut app = App::new();
+        app.message = Some("No servers cached. Press 'r' to refresh or 'i' to setup.".to_string());
+        assert_snapshot("empty_cache", &app);
+    }
+
+    #[test]
+    fn countries_view() {
+        assert_snapshot("countries", &sample_app());
+    }
+
+    #[test]
+    fn cities_view() {
+        let mut app = sample_app();
+        app.select();
+        app.next();
+        assert_snapshot("cities", &app);
+    }
+
+    #[test]
+    fn servers_view() {
+        let mut app = sample_app();
+        show_frankfurt(&mut app);
+        app.favorites.push("de-fra-wg-002".to_string());
+        app.latencies.insert("de-fra-wg-001".to_string(), Latency { ms: Some(23.4), measured_at: 0 });
+        app.latencies.insert("de-fra-wg-002".to_string(), Latency { ms: None, measured_at: 0 });
+        assert_snapshot("servers", &app);
+    }
+
+    #[test]
+    fn servers_view_connected_with_autostart() {
+        let mut app = sample_app();
+        show_frankfurt(&mut app);
+        app.connection_status = ConnectionStatus::Connected("de-fra-wg-001".to_string());
+        app.autostart_server = Some("de-fra-wg-002".to_string());
+        app.message = Some("Connected to de-fra-wg-001".to_string());
+        assert_snapshot("servers_connected_autostart", &app);
+    }
+
+    #[tokio::test]
+    async fn task_running() {
+        let mut app = sample_app();
+        show_frankfurt(&mut app);
+        app.task = Some(Task::spawn("Bringing up de-fra-wg-002...", |_| std::future::pending()));
+        assert_snapshot("task_running", &app);
+    }
+
+    #[test]
+    fn error_message() {
+        let mut app = sample_app();
+        show_frankfurt(&mut app);
+        app.error = Some("Failed to connect: No config for de-fra-wg-001. Press 'i' to set up.".to_string());
+        assert_snapshot("error", &app);
+    }
+
+    #[test]
+    fn setup_view_with_input() {
+        let mut app = sample_app();
+        app.enter_setup();
+        app.input_buffer = "1234 5678".to_string();
+        assert_snapshot("setup", &app);
+    }
+
+    #[test]
+    fn search_view() {
+        let mut app = sample_app();
+        app.enter_search();
+        app.search_query = "fra".to_string();
+        app.update_search();
+        assert_snapshot("search", &app);
+    }
+
+    #[test]
+    fn favorites_view() {
+        let mut app = sample_app();
+        app.favorites = vec!["nl-ams-wg-101".to_string(), "de-ber-wg-001".to_string()];
+        app.show_favorites();
+        assert_snapshot("favorites", &app);
+    }
+
+    #[test]
+    fn history_view() {
+        let mut app = sample_app();
+        let entry = |timestamp, action, code: &str, duration, error: Option<&str>| HistoryEntry {
+            timestamp,
+            action,
+            code: code.to_string(),
+            duration,
+            error: error.map(str::to_string),
+        };
+        app.history = vec![
+            entry(1_714_587_000, Action::Connect, "de-fra-wg-001", None, None),
+            entry(1_714_590_900, Action::Disconnect, "de-fra-wg-001", Some(3900), None),
+            entry(1_714_591_000, Action::Connect, "nl-ams-wg-101", None, Some("WireGuard module not loaded")),
+        ];
+        app.show_history();
+        assert_snapshot("history", &app);
+    }
+
+    #[test]
+    fn stats_view_disconnected() {
+        let mut app = sample_app();
+        app.show_stats();
+        assert_snapshot("stats", &app);
+    }
+
+    #[test]
+    fn dns_view() {
+        let mut app = sample_app();
+        app.settings.dns.toggle(Blocklist::Ads);
+        app.settings.dns.toggle(Blocklist::Malware);
+        app.show_dns();
+        app.next();
+        assert_snapshot("dns", &app);
+    }
+}
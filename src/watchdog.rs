@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::history;
+
+/// How often the watchdog looks at the tunnel
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Oldest log lines are dropped beyond this many
+const MAX_LOG_ENTRIES: usize = 50;
+
+/// Address pinged through the tunnel to check it still carries traffic
+pub const PROBE_ADDR: &str = "10.64.0.1";
+
+/// Watchdog configuration, part of the persistent settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatchdogSettings {
+    pub enabled: bool,
+    /// Handshake age after which the tunnel is probed, in seconds.
+    /// WireGuard rekeys every two minutes while traffic flows.
+    pub handshake_timeout_secs: u64,
+}
+
+impl Default for WatchdogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            handshake_timeout_secs: 180,
+        }
+    }
+}
+
+/// Where the watchdog reconnects to, tried in this order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    SameRelay,
+    SameCity,
+    SameCountry,
+    GiveUp,
+}
+
+impl Step {
+    fn next(self) -> Option<Self> {
+        match self {
+            Step::SameRelay => Some(Step::SameCity),
+            Step::SameCity => Some(Step::SameCountry),
+            Step::SameCountry => Some(Step::GiveUp),
+            Step::GiveUp => None,
+        }
+    }
+}
+
+/// A dead connection the watchdog is trying to bring back
+#[derive(Debug, Clone)]
+pub struct Incident {
+    /// Server that was connected when the tunnel died
+    pub code: String,
+    pub country: String,
+    pub city: String,
+    /// Next recovery step to try, `None` once given up
+    pub step: Option<Step>,
+    /// Servers already reconnected to during this incident
+    pub tried: Vec<String>,
+}
+
+/// One line of the watchdog log
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub timestamp: u64,
+    pub message: String,
+}
+
+impl LogEntry {
+    /// Local time of day of the entry, e.g. "18:30:12"
+    pub fn local_time(&self) -> String {
+        history::format_time(self.timestamp, "%H:%M:%S")
+    }
+}
+
+/// Tracks checks, the current incident and what was done about it
+#[derive(Debug, Default)]
+pub struct Watchdog {
+    pub log: VecDeque<LogEntry>,
+    pub incident: Option<Incident>,
+    checked_at: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Whether it is time for the next check; marks the check as done
+    pub fn check_due(&mut self) -> bool {
+        if self.checked_at.is_some_and(|at| at.elapsed() < CHECK_INTERVAL) {
+            return false;
+        }
+        self.checked_at = Some(Instant::now());
+        true
+    }
+
+    /// Take the next recovery step of the current incident
+    pub fn advance(&mut self) -> Option<Step> {
+        let incident = self.incident.as_mut()?;
+        let step = incident.step?;
+        incident.step = step.next();
+        Some(step)
+    }
+
+    pub fn record(&mut self, timestamp: u64, message: String) {
+        self.log.push_back(LogEntry { timestamp, message });
+        if self.log.len() > MAX_LOG_ENTRIES {
+            self.log.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_widen_the_search_then_stop() {
+        let mut watchdog = Watchdog::default();
+        assert_eq!(watchdog.advance(), None);
+
+        watchdog.incident = Some(Incident {
+            code: "de-fra-wg-002".to_string(),
+            country: "Germany".to_string(),
+            city: "Frankfurt".to_string(),
+            step: Some(Step::SameRelay),
+            tried: Vec::new(),
+        });
+        let steps: Vec<Step> = std::iter::from_fn(|| watchdog.advance()).collect();
+
+        assert_eq!(steps, [Step::SameRelay, Step::SameCity, Step::SameCountry, Step::GiveUp]);
+        assert_eq!(watchdog.incident.unwrap().step, None);
+    }
+
+    #[test]
+    fn log_keeps_the_latest_entries() {
+        let mut watchdog = Watchdog::default();
+        for i in 0..MAX_LOG_ENTRIES + 5 {
+            watchdog.record(i as u64, format!("check {}", i));
+        }
+
+        assert_eq!(watchdog.log.len(), MAX_LOG_ENTRIES);
+        assert_eq!(watchdog.log.front().unwrap().message, "check 5");
+    }
+
+    #[test]
+    fn checks_are_spaced_out() {
+        let mut watchdog = Watchdog::default();
+
+        assert!(watchdog.check_due());
+        assert!(!watchdog.check_due());
+    }
+}
@@ -1,20 +1,51 @@
-use anyhow::{Context, Result};
-use std::process::Command;
+use anyhow::{anyhow, Context};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use nix::errno::Errno;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::Output;
 
-const MULLVAD_DNS: &str = "10.64.0.1";
+use crate::config;
+use crate::dns::DnsBackend;
+use crate::error::ConnectError;
+use crate::firewall;
+use crate::firewall::KillSwitch;
+use crate::journal::{self, record, Applied, Journal, Operation};
+use crate::netlink::{self, NetlinkError};
+use crate::runner::CommandRunner;
+
+type Result<T> = std::result::Result<T, ConnectError>;
+
+/// Fwmark and routing table used for the tunnel, same as wg-quick's default
+const FWMARK: u32 = 51820;
+
+/// Interface MTU used by wg-quick for IPv4 endpoints
+const MTU: u32 = 1420;
+
+/// How tunnels are brought up and down
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// Configure the interface directly over netlink
+    #[default]
+    Netlink,
+    /// Shell out to wg-quick
+    WgQuick,
+}
 
 /// Connection status
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", content = "server", rename_all = "lowercase")]
 pub enum ConnectionStatus {
     Connected(String), // Connected to server code
     Disconnected,
 }
 
 /// Check if a server is enabled for auto-start
-pub fn is_enabled(code: &str) -> bool {
-    let output = Command::new("systemctl")
-        .args(["is-enabled", &format!("wg-quick@{}", code)])
-        .output();
+pub fn is_enabled(runner: &dyn CommandRunner, code: &str) -> bool {
+    let output = runner.output("systemctl", &["is-enabled", &format!("wg-quick@{}", code)]);
 
     match output {
         Ok(o) => o.status.success(),
@@ -23,11 +54,10 @@ pub fn is_enabled(code: &str) -> bool {
 }
 
 /// Get the currently enabled server (if any)
-pub fn get_enabled_server() -> Option<String> {
+pub fn get_enabled_server(runner: &dyn CommandRunner) -> Option<String> {
     // List all wg-quick services and find enabled ones
-    let output = Command::new("systemctl")
-        .args(["list-unit-files", "wg-quick@*.service", "--no-legend"])
-        .output()
+    let output = runner
+        .output("systemctl", &["list-unit-files", "wg-quick@*.service", "--no-legend"])
         .ok()?;
 
     let stdout = String::from_utf8_lossy(&output.stdout);
@@ -48,54 +78,160 @@ pub fn get_enabled_server() -> Option<String> {
 }
 
 /// Enable a server for auto-start on boot
-pub fn enable_autostart(code: &str) -> Result<()> {
+pub fn enable_autostart(runner: &dyn CommandRunner, code: &str) -> Result<()> {
     // First disable any currently enabled Mullvad server
-    if let Some(current) = get_enabled_server() {
+    if let Some(current) = get_enabled_server(runner) {
         if current != code {
-            let _ = Command::new("systemctl")
-                .args(["disable", &format!("wg-quick@{}", current)])
-                .output();
+            let _ = runner.output("systemctl", &["disable", &format!("wg-quick@{}", current)]);
         }
     }
 
-    let output = Command::new("systemctl")
-        .args(["enable", &format!("wg-quick@{}", code)])
-        .output()
-        .context("Failed to run systemctl enable")?;
+    let output = runner
+        .output("systemctl", &["enable", &format!("wg-quick@{}", code)])
+        .map_err(|e| ConnectError::spawn("systemctl", e))?;
 
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Failed to enable service: {}", stderr);
+        return Err(command_failed("Failed to enable service", &output));
     }
 
     Ok(())
 }
 
 /// Disable auto-start for a server
-pub fn disable_autostart(code: &str) -> Result<()> {
-    let output = Command::new("systemctl")
-        .args(["disable", &format!("wg-quick@{}", code)])
-        .output()
-        .context("Failed to run systemctl disable")?;
+pub fn disable_autostart(runner: &dyn CommandRunner, code: &str) -> Result<()> {
+    let output = runner
+        .output("systemctl", &["disable", &format!("wg-quick@{}", code)])
+        .map_err(|e| ConnectError::spawn("systemctl", e))?;
 
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("Failed to disable service: {}", stderr);
+        return Err(command_failed("Failed to disable service", &output));
     }
 
     Ok(())
 }
 
-/// Connect to a WireGuard server using wg-quick
-pub fn connect(code: &str) -> Result<()> {
+/// Connect to a WireGuard server, resolving through `resolver` inside the tunnel
+pub fn connect(
+    runner: &dyn CommandRunner,
+    code: &str,
+    backend: Backend,
+    dns: DnsBackend,
+    resolver: IpAddr,
+) -> Result<()> {
     // Check if config exists
-    let config_path = format!("/etc/wireguard/{}.conf", code);
-    if !std::path::Path::new(&config_path).exists() {
-        anyhow::bail!("Config file not found: {}. Press 'i' to setup.", config_path);
+    if !config::config_exists(code) {
+        return Err(ConnectError::MissingConfig(code.to_string()));
+    }
+
+    let interface = Applied::Interface {
+        code: code.to_string(),
+        backend,
+    };
+    record(|j| {
+        j.in_progress = Some(Operation::Connect);
+        j.applied.push(interface.clone());
+    })?;
+
+    let result = match backend {
+        Backend::Netlink => connect_netlink(code),
+        Backend::WgQuick => connect_wg_quick(runner, code),
+    };
+    // A failed bring-up cleans up after itself
+    if let Err(e) = result {
+        record(|j| {
+            j.in_progress = None;
+            j.applied.retain(|a| *a != interface);
+        })?;
+        return Err(e);
+    }
+
+    // Configure DNS leak prevention, and don't leave a tunnel without it
+    if let Err(e) = configure_dns_leak_prevention(runner, code, dns, resolver) {
+        let _ = disconnect(runner, code, backend);
+        return Err(e);
+    }
+
+    record(|j| j.in_progress = None)
+}
+
+/// Find state left behind by an interrupted connect or disconnect, or by a
+/// tunnel that went away on its own, and roll it back. Returns what was done.
+pub fn reconcile(runner: &dyn CommandRunner) -> Result<Vec<String>> {
+    let journal = journal::load()
+        .map_err(|e| ConnectError::Other(format!("Failed to read state journal: {}", e)))?;
+    if journal.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut actions = Vec::new();
+    let interface = journal.interface().map(|(code, backend)| (code.to_string(), backend));
+    let live = interface
+        .as_ref()
+        .is_some_and(|(code, _)| netlink::interface_index(code).is_ok());
+
+    match (journal.in_progress, interface) {
+        // Consistent: the tunnel is up and nothing was interrupted.
+        // Re-applying the DNS rules is idempotent and repairs them if they were flushed.
+        (None, Some((code, _))) if live => {
+            if journal.applied.contains(&Applied::DnsRules) {
+                firewall::apply_dns_rules(runner, &code)?;
+            }
+            return Ok(actions);
+        }
+        (Some(operation), Some((code, backend))) => {
+            let operation = match operation {
+                Operation::Connect => "connect to",
+                Operation::Disconnect => "disconnect from",
+            };
+            actions.push(format!("rolled back interrupted {} {}", operation, code));
+            if live {
+                teardown_interface(runner, &code, backend)?;
+            }
+        }
+        (_, Some((code, _))) => {
+            actions.push(format!("cleaned up after {}, which went down", code));
+        }
+        (_, None) => {}
+    }
+
+    // Resolver settings can outlive the interface (resolv.conf, resolvconf records)
+    for applied in &journal.applied {
+        if let Applied::Resolver { interface, .. } = applied {
+            revert_resolver(runner, interface)?;
+        }
+    }
+
+    if journal.applied.contains(&Applied::DnsRules) {
+        firewall::remove_dns_rules(runner)?;
+        actions.push("removed leftover DNS rules".to_string());
+    }
+
+    // Without a tunnel, only lockdown keeps blocking
+    if journal.kill_switch() == Some(KillSwitch::On) {
+        firewall::remove_kill_switch(runner)?;
+        actions.push("removed leftover kill switch".to_string());
     }
 
-    // Try to connect
-    let output = try_wg_quick_up(code)?;
+    record(Journal::clear_tunnel)?;
+    Ok(actions)
+}
+
+/// Load the wireguard kernel module
+pub fn load_module(runner: &dyn CommandRunner) -> Result<()> {
+    let output = runner
+        .output("modprobe", &["wireguard"])
+        .map_err(|e| ConnectError::spawn("modprobe", e))?;
+
+    if !output.status.success() {
+        return Err(command_failed("modprobe wireguard failed", &output));
+    }
+
+    Ok(())
+}
+
+/// Bring the tunnel up with wg-quick
+fn connect_wg_quick(runner: &dyn CommandRunner, code: &str) -> Result<()> {
+    let output = try_wg_quick_up(runner, code)?;
 
     if !output.status.success() {
         let stdout = String::from_utf8_lossy(&output.stdout);
@@ -105,13 +241,11 @@ pub fn connect(code: &str) -> Result<()> {
         // Check for resolvconf signature mismatch - fix it and retry
         if combined.contains("signature mismatch") {
             // Run resolvconf -u to fix
-            let _ = Command::new("resolvconf").arg("-u").output();
+            let _ = runner.output("resolvconf", &["-u"]);
 
             // Retry connection
-            let retry_output = try_wg_quick_up(code)?;
+            let retry_output = try_wg_quick_up(runner, code)?;
             if retry_output.status.success() {
-                // Configure DNS leak prevention
-                configure_dns_leak_prevention(code);
                 return Ok(());
             }
 
@@ -119,115 +253,273 @@ pub fn connect(code: &str) -> Result<()> {
             let retry_stdout = String::from_utf8_lossy(&retry_output.stdout);
             let retry_stderr = String::from_utf8_lossy(&retry_output.stderr);
             let retry_combined = format!("{}{}", retry_stdout, retry_stderr);
-            anyhow::bail!("wg-quick up failed after resolvconf fix:\n{}", retry_combined.trim());
+            return Err(ConnectError::Dns(format!(
+                "wg-quick up failed after resolvconf fix:\n{}",
+                retry_combined.trim()
+            )));
         }
 
         // Check for common errors - be specific about module loading failures
         if combined.contains("RTNETLINK answers: Operation not supported") {
-            anyhow::bail!(
-                "WireGuard module not loaded. Run: sudo modprobe wireguard"
-            );
+            return Err(ConnectError::ModuleMissing);
         }
 
         // Check if interface already exists
         if combined.contains("already exists") {
-            anyhow::bail!("Interface already exists. Try disconnecting first (press 'd')");
+            return Err(ConnectError::InterfaceExists(code.to_string()));
         }
 
-        anyhow::bail!("wg-quick up failed:\n{}", combined.trim());
+        if combined.contains("Operation not permitted") {
+            return Err(ConnectError::Permission(combined.trim().to_string()));
+        }
+
+        return Err(ConnectError::Other(format!("wg-quick up failed:\n{}", combined.trim())));
     }
 
-    // Configure DNS leak prevention
-    configure_dns_leak_prevention(code);
+    Ok(())
+}
+
+/// Bring the tunnel up natively over netlink, mirroring what wg-quick does
+fn connect_netlink(code: &str) -> Result<()> {
+    let config = config::read_config(code)?;
+    let private_key = decode_key(&config.private_key).context("Invalid PrivateKey")?;
+    let peer = netlink::Peer {
+        public_key: decode_key(&config.peer_public_key).context("Invalid peer PublicKey")?,
+        endpoint: config
+            .endpoint
+            .parse()
+            .with_context(|| format!("Invalid Endpoint: {}", config.endpoint))?,
+        allowed_ips: parse_cidrs(&config.allowed_ips)?,
+    };
+    let addresses = parse_cidrs(&config.addresses)?;
+
+    netlink::create_interface(code, MTU).map_err(|e| match e {
+        NetlinkError::Kernel(Errno::EEXIST) => ConnectError::InterfaceExists(code.to_string()),
+        NetlinkError::Kernel(Errno::EOPNOTSUPP) => ConnectError::ModuleMissing,
+        e => netlink_error("Failed to create interface", e),
+    })?;
+
+    // Don't leave a half-configured interface behind
+    if let Err(e) = setup_netlink_interface(code, &private_key, &peer, &addresses) {
+        let _ = disconnect_netlink(code);
+        return Err(e);
+    }
 
     Ok(())
 }
 
-/// Configure DNS to prevent leaks
-fn configure_dns_leak_prevention(interface: &str) {
-    // Set DNS for the WireGuard interface
-    let _ = Command::new("resolvectl")
-        .args(["dns", interface, MULLVAD_DNS])
-        .output();
-
-    // Set this interface as the default route for DNS (~. means all domains)
-    let _ = Command::new("resolvectl")
-        .args(["domain", interface, "~."])
-        .output();
-
-    // Flush DNS cache
-    let _ = Command::new("resolvectl")
-        .arg("flush-caches")
-        .output();
-
-    // Block DNS on other interfaces with iptables (IPv4)
-    let _ = Command::new("iptables")
-        .args(["-I", "OUTPUT", "!", "-o", interface, "-p", "udp", "--dport", "53", "-j", "REJECT"])
-        .output();
-    let _ = Command::new("iptables")
-        .args(["-I", "OUTPUT", "!", "-o", interface, "-p", "tcp", "--dport", "53", "-j", "REJECT"])
-        .output();
-
-    // Block DNS on other interfaces with iptables (IPv6)
-    let _ = Command::new("ip6tables")
-        .args(["-I", "OUTPUT", "!", "-o", interface, "-p", "udp", "--dport", "53", "-j", "REJECT"])
-        .output();
-    let _ = Command::new("ip6tables")
-        .args(["-I", "OUTPUT", "!", "-o", interface, "-p", "tcp", "--dport", "53", "-j", "REJECT"])
-        .output();
-}
-
-fn try_wg_quick_up(code: &str) -> Result<std::process::Output> {
-    Command::new("wg-quick")
-        .args(["up", code])
-        .output()
-        .context("Failed to execute wg-quick")
-}
-
-/// Disconnect from a WireGuard server using wg-quick
-pub fn disconnect(code: &str) -> Result<()> {
-    // Clean up DNS leak prevention rules first
-    cleanup_dns_leak_prevention(code);
-
-    let output = Command::new("wg-quick")
-        .args(["down", code])
-        .output()
-        .context("Failed to execute wg-quick")?;
+fn setup_netlink_interface(
+    code: &str,
+    private_key: &[u8; 32],
+    peer: &netlink::Peer,
+    addresses: &[(IpAddr, u8)],
+) -> Result<()> {
+    netlink::configure_device(code, private_key, FWMARK, peer)
+        .map_err(|e| netlink_error("Failed to configure WireGuard device", e))?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("wg-quick down failed: {}", stderr);
+    let index = netlink::interface_index(code).context("Interface disappeared")?;
+    for (addr, prefix_len) in addresses {
+        netlink::add_address(index, *addr, *prefix_len)
+            .map_err(|e| netlink_error(&format!("Failed to add address {}", addr), e))?;
+    }
+    netlink::set_up(index).map_err(|e| netlink_error("Failed to bring interface up", e))?;
+
+    // Like wg-quick, let replies to marked packets pass reverse path filtering
+    let _ = fs::write(src_valid_mark_path(), "1");
+
+    for ipv6 in [false, true] {
+        if addresses.iter().any(|(addr, _)| addr.is_ipv6() == ipv6) {
+            netlink::add_default_route(index, ipv6, FWMARK)
+                .map_err(|e| netlink_error("Failed to add route", e))?;
+            netlink::add_tunnel_rules(ipv6, FWMARK)
+                .map_err(|e| netlink_error("Failed to add routing rules", e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reverse path filtering switch that `setup_netlink_interface` turns on
+#[cfg(not(test))]
+fn src_valid_mark_path() -> PathBuf {
+    PathBuf::from("/proc/sys/net/ipv4/conf/all/src_valid_mark")
+}
+
+/// Tests leave the real sysctl alone
+#[cfg(test)]
+fn src_valid_mark_path() -> PathBuf {
+    crate::testing::scratch_dir().join("src_valid_mark")
+}
+
+/// Tear down an interface created by `connect_netlink`
+fn disconnect_netlink(code: &str) -> Result<()> {
+    netlink::delete_tunnel_rules(false, FWMARK)
+        .map_err(|e| netlink_error("Failed to remove routing rules", e))?;
+    // Fails if IPv6 is disabled, in which case no rules were added either
+    let _ = netlink::delete_tunnel_rules(true, FWMARK);
+
+    match netlink::delete_interface(code) {
+        Ok(()) | Err(NetlinkError::Kernel(Errno::ENODEV)) => Ok(()),
+        Err(e) => Err(netlink_error("Failed to delete interface", e)),
     }
+}
 
-    // Flush DNS cache after disconnect
-    let _ = Command::new("resolvectl").arg("flush-caches").output();
+/// Classify a netlink failure, keeping what was being done for the message
+fn netlink_error(context: &str, error: NetlinkError) -> ConnectError {
+    match error {
+        NetlinkError::FamilyNotFound(_) => ConnectError::ModuleMissing,
+        NetlinkError::Io(Errno::EPERM | Errno::EACCES)
+        | NetlinkError::Kernel(Errno::EPERM | Errno::EACCES) => {
+            ConnectError::Permission(format!("{}: {}", context, error))
+        }
+        error => ConnectError::Other(format!("{}: {}", context, error)),
+    }
+}
+
+/// Error for a command that ran but exited unsuccessfully
+fn command_failed(context: &str, output: &Output) -> ConnectError {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("Permission denied") || stderr.contains("Operation not permitted") {
+        ConnectError::Permission(format!("{}: {}", context, stderr.trim()))
+    } else {
+        ConnectError::Other(format!("{}: {}", context, stderr.trim()))
+    }
+}
+
+/// Decode a base64 WireGuard key
+fn decode_key(key: &str) -> anyhow::Result<[u8; 32]> {
+    let bytes = BASE64_STANDARD.decode(key.trim())?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("key must be 32 bytes"))
+}
+
+/// Parse "addr/prefix" entries; a missing prefix means a single host
+fn parse_cidrs(entries: &[String]) -> anyhow::Result<Vec<(IpAddr, u8)>> {
+    entries
+        .iter()
+        .map(|entry| {
+            let (addr, prefix_len) = match entry.split_once('/') {
+                Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+                None => (entry.as_str(), None),
+            };
+            let addr: IpAddr = addr
+                .parse()
+                .with_context(|| format!("Invalid address: {}", entry))?;
+            let max = if addr.is_ipv6() { 128 } else { 32 };
+            let prefix_len = match prefix_len {
+                Some(p) => p
+                    .parse()
+                    .ok()
+                    .filter(|p| *p <= max)
+                    .with_context(|| format!("Invalid prefix length: {}", entry))?,
+                None => max,
+            };
+            Ok((addr, prefix_len))
+        })
+        .collect()
+}
+
+/// Configure DNS to prevent leaks
+fn configure_dns_leak_prevention(
+    runner: &dyn CommandRunner,
+    interface: &str,
+    dns: DnsBackend,
+    resolver: IpAddr,
+) -> Result<()> {
+    record(|j| {
+        j.applied.push(Applied::Resolver {
+            interface: interface.to_string(),
+            dns,
+        })
+    })?;
+
+    // Send all queries to Mullvad's resolver through the tunnel
+    dns.apply(runner, interface, &[resolver])?;
+
+    // Reject DNS on all other interfaces
+    record(|j| j.applied.push(Applied::DnsRules))?;
+    firewall::apply_dns_rules(runner, interface)?;
 
     Ok(())
 }
 
-/// Clean up DNS leak prevention iptables rules
-fn cleanup_dns_leak_prevention(interface: &str) {
-    // Remove iptables rules (IPv4)
-    let _ = Command::new("iptables")
-        .args(["-D", "OUTPUT", "!", "-o", interface, "-p", "udp", "--dport", "53", "-j", "REJECT"])
-        .output();
-    let _ = Command::new("iptables")
-        .args(["-D", "OUTPUT", "!", "-o", interface, "-p", "tcp", "--dport", "53", "-j", "REJECT"])
-        .output();
+fn try_wg_quick_up(runner: &dyn CommandRunner, code: &str) -> Result<Output> {
+    runner
+        .output("wg-quick", &["up", code])
+        .map_err(|e| ConnectError::spawn("wg-quick", e))
+}
+
+/// Disconnect from a WireGuard server with the backend that brought the tunnel up.
+/// `fallback` is only used for tunnels missing from the journal.
+pub fn disconnect(runner: &dyn CommandRunner, code: &str, fallback: Backend) -> Result<()> {
+    let journal = journal::load()
+        .map_err(|e| ConnectError::Other(format!("Failed to read state journal: {}", e)))?;
+    let backend = journal
+        .interface()
+        .filter(|(c, _)| *c == code)
+        .map_or(fallback, |(_, backend)| backend);
+
+    record(|j| j.in_progress = Some(Operation::Disconnect))?;
+
+    // Clean up DNS leak prevention rules first, but take the tunnel down even if that fails
+    let cleanup = firewall::remove_dns_rules(runner);
+    if cleanup.is_ok() {
+        record(|j| j.applied.retain(|a| *a != Applied::DnsRules))?;
+    }
+
+    teardown_interface(runner, code, backend)?;
+
+    cleanup?;
+    record(Journal::clear_tunnel)
+}
+
+/// Revert the resolver settings and take the interface down
+fn teardown_interface(runner: &dyn CommandRunner, code: &str, backend: Backend) -> Result<()> {
+    // Take the interface down even if the resolver can't be restored
+    let resolver = revert_resolver(runner, code);
+
+    match backend {
+        Backend::Netlink => disconnect_netlink(code)?,
+        Backend::WgQuick => disconnect_wg_quick(runner, code)?,
+    }
+
+    record(|j| j.applied.retain(|a| !matches!(a, Applied::Interface { code: c, .. } if c == code)))?;
+    resolver
+}
+
+/// Undo the resolver settings recorded for an interface, with the backend that made them
+fn revert_resolver(runner: &dyn CommandRunner, interface: &str) -> Result<()> {
+    let journal = journal::load()
+        .map_err(|e| ConnectError::Other(format!("Failed to read state journal: {}", e)))?;
+    let Some(dns) = journal.resolver(interface) else {
+        return Ok(());
+    };
 
-    // Remove iptables rules (IPv6)
-    let _ = Command::new("ip6tables")
-        .args(["-D", "OUTPUT", "!", "-o", interface, "-p", "udp", "--dport", "53", "-j", "REJECT"])
-        .output();
-    let _ = Command::new("ip6tables")
-        .args(["-D", "OUTPUT", "!", "-o", interface, "-p", "tcp", "--dport", "53", "-j", "REJECT"])
-        .output();
+    dns.revert(runner, interface)?;
+    record(|j| {
+        j.applied
+            .retain(|a| !matches!(a, Applied::Resolver { interface: i, .. } if i == interface))
+    })
+}
+
+/// Bring the tunnel down with wg-quick
+fn disconnect_wg_quick(runner: &dyn CommandRunner, code: &str) -> Result<()> {
+    let output = runner
+        .output("wg-quick", &["down", code])
+        .map_err(|e| ConnectError::spawn("wg-quick", e))?;
+
+    if !output.status.success() {
+        return Err(command_failed("wg-quick down failed", &output));
+    }
+
+    Ok(())
 }
 
 /// Get current connection status by checking active interfaces
-pub fn get_status() -> ConnectionStatus {
+pub fn get_status(runner: &dyn CommandRunner) -> ConnectionStatus {
     // Try to get active WireGuard interfaces
-    let output = Command::new("wg").arg("show").output();
+    let output = runner.output("wg", &["show"]);
 
     match output {
         Ok(output) if output.status.success() => {
@@ -251,16 +543,56 @@ pub fn get_status() -> ConnectionStatus {
     }
 }
 
+/// Counters and peer state of a tunnel, as reported by `wg show <iface> dump`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TunnelStats {
+    pub endpoint: Option<String>,
+    /// Unix time of the latest handshake, `None` if there was none yet
+    pub latest_handshake: Option<u64>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Persistent keepalive interval in seconds, `None` if off
+    pub keepalive: Option<u64>,
+}
+
+/// Read the statistics of the peer on a tunnel interface
+pub fn get_stats(runner: &dyn CommandRunner, interface: &str) -> Option<TunnelStats> {
+    let output = runner.output("wg", &["show", interface, "dump"]).ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    parse_dump(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse the peer line of `wg show <iface> dump`
+fn parse_dump(dump: &str) -> Option<TunnelStats> {
+    // The first line describes the interface, then one line per peer:
+    // public-key preshared-key endpoint allowed-ips latest-handshake rx tx keepalive
+    let line = dump.lines().nth(1)?;
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 8 {
+        return None;
+    }
+
+    Some(TunnelStats {
+        endpoint: Some(fields[2]).filter(|e| *e != "(none)").map(str::to_string),
+        latest_handshake: fields[4].parse().ok().filter(|t| *t != 0),
+        rx_bytes: fields[5].parse().ok()?,
+        tx_bytes: fields[6].parse().ok()?,
+        keepalive: fields[7].parse().ok(),
+    })
+}
+
 /// Generate a new WireGuard private key
-pub fn generate_private_key() -> Result<String> {
-    let output = Command::new("wg")
-        .arg("genkey")
-        .output()
-        .context("Failed to execute wg genkey")?;
+pub fn generate_private_key(runner: &dyn CommandRunner) -> Result<String> {
+    let output = runner
+        .output("wg", &["genkey"])
+        .map_err(|e| ConnectError::spawn("wg", e))?;
 
     if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("wg genkey failed: {}", stderr);
+        return Err(command_failed("wg genkey failed", &output));
     }
 
     let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
@@ -268,32 +600,504 @@ pub fn generate_private_key() -> Result<String> {
 }
 
 /// Get the public key from a private key
-pub fn get_public_key(private_key: &str) -> Result<String> {
-    // wg pubkey reads from stdin, so we need to pipe the private key
-    use std::io::Write;
-    use std::process::Stdio;
+pub fn get_public_key(runner: &dyn CommandRunner, private_key: &str) -> Result<String> {
+    // wg pubkey reads the private key from stdin
+    let output = runner
+        .run("wg", &["pubkey"], Some(private_key))
+        .map_err(|e| ConnectError::spawn("wg", e))?;
 
-    let mut child = Command::new("wg")
-        .arg("pubkey")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .context("Failed to spawn wg pubkey")?;
+    if !output.status.success() {
+        return Err(command_failed("wg pubkey failed", &output));
+    }
+
+    let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::netlink::kernel;
+    use crate::runner::FakeRunner;
+    use std::io;
+
+    const CODE: &str = "se-mma-wg-001";
 
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin
-            .write_all(private_key.as_bytes())
-            .context("Failed to write to wg pubkey stdin")?;
+    /// Empty journal and kernel; the journal is shared, so hold on to the guard
+    fn fresh_journal() -> tokio::sync::MutexGuard<'static, ()> {
+        let guard = crate::testing::lock_journal();
+        kernel::reset();
+        guard
     }
 
-    let output = child.wait_with_output().context("Failed to wait for wg pubkey")?;
+    fn write_config() {
+        let server = crate::testing::sample_servers().remove(0);
+        let server = crate::server::Server {
+            code: CODE.to_string(),
+            ..server
+        };
+        config::generate_config(
+            &server,
+            "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=",
+            "10.68.12.34/32,fc00:bbbb:bbbb:bb01::5:c21/128",
+            "10.64.0.1".parse().unwrap(),
+            false,
+        )
+        .unwrap();
+    }
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        anyhow::bail!("wg pubkey failed: {}", stderr);
+    fn connect_with(runner: &FakeRunner, backend: Backend) -> Result<()> {
+        write_config();
+        connect(
+            runner,
+            CODE,
+            backend,
+            DnsBackend::Resolvconf,
+            "10.64.0.1".parse().unwrap(),
+        )
     }
 
-    let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    Ok(key)
+    /// Programs run, with the first argument
+    fn commands(runner: &FakeRunner) -> Vec<String> {
+        runner
+            .calls()
+            .iter()
+            .map(|call| call.split(' ').take(2).collect::<Vec<_>>().join(" "))
+            .collect()
+    }
+
+    fn interface(backend: Backend) -> Applied {
+        Applied::Interface {
+            code: CODE.to_string(),
+            backend,
+        }
+    }
+
+    fn resolver() -> Applied {
+        Applied::Resolver {
+            interface: CODE.to_string(),
+            dns: DnsBackend::Resolvconf,
+        }
+    }
+
+    #[test]
+    fn netlink_connect_records_each_step() {
+        let _journal = fresh_journal();
+        let runner = FakeRunner::new();
+
+        connect_with(&runner, Backend::Netlink).unwrap();
+
+        assert_eq!(kernel::links(), [CODE]);
+        // Both rules for IPv4 and IPv6
+        assert_eq!(kernel::rule_count(), 4);
+        assert_eq!(commands(&runner), ["resolvconf -a", "nft -f"]);
+        let journal = journal::load().unwrap();
+        assert_eq!(journal.in_progress, None);
+        assert_eq!(
+            journal.applied,
+            [interface(Backend::Netlink), resolver(), Applied::DnsRules]
+        );
+    }
+
+    #[test]
+    fn wg_quick_connect_records_each_step() {
+        let _journal = fresh_journal();
+        let runner = FakeRunner::new();
+
+        connect_with(&runner, Backend::WgQuick).unwrap();
+
+        assert_eq!(commands(&runner), ["wg-quick up", "resolvconf -a", "nft -f"]);
+        assert!(kernel::links().is_empty());
+        let journal = journal::load().unwrap();
+        assert_eq!(journal.in_progress, None);
+        assert_eq!(
+            journal.applied,
+            [interface(Backend::WgQuick), resolver(), Applied::DnsRules]
+        );
+    }
+
+    #[test]
+    fn netlink_connect_failing_midway_removes_interface() {
+        let _journal = fresh_journal();
+        let runner = FakeRunner::new();
+        kernel::fail(kernel::RTM_NEWROUTE, Errno::EPERM);
+
+        let error = connect_with(&runner, Backend::Netlink).unwrap_err();
+
+        assert!(matches!(error, ConnectError::Permission(msg) if msg.starts_with("Failed to add route")));
+        assert!(kernel::links().is_empty());
+        assert_eq!(kernel::rule_count(), 0);
+        assert!(runner.calls().is_empty());
+        assert!(journal::load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn failing_dns_takes_the_tunnel_down_again() {
+        let _journal = fresh_journal();
+        let runner = FakeRunner::new();
+        runner.respond("resolvconf -a", 1, "", "resolvconf: Permission denied\n");
+
+        let error = connect_with(&runner, Backend::WgQuick).unwrap_err();
+
+        assert!(matches!(error, ConnectError::Dns(_)));
+        assert_eq!(
+            commands(&runner),
+            ["wg-quick up", "resolvconf -a", "nft -f", "resolvconf -d", "wg-quick down"]
+        );
+        assert!(journal::load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn netlink_disconnect_undoes_connect() {
+        let _journal = fresh_journal();
+        let runner = FakeRunner::new();
+        connect_with(&runner, Backend::Netlink).unwrap();
+
+        disconnect(&runner, CODE, Backend::Netlink).unwrap();
+
+        assert!(kernel::links().is_empty());
+        assert_eq!(kernel::rule_count(), 0);
+        assert_eq!(
+            commands(&runner),
+            ["resolvconf -a", "nft -f", "nft -f", "resolvconf -d"]
+        );
+        assert!(journal::load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn disconnect_uses_backend_from_journal() {
+        let _journal = fresh_journal();
+        let runner = FakeRunner::new();
+        connect_with(&runner, Backend::WgQuick).unwrap();
+
+        disconnect(&runner, CODE, Backend::Netlink).unwrap();
+
+        assert!(runner.calls().contains(&"wg-quick down se-mma-wg-001".to_string()));
+        assert!(journal::load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn disconnect_falls_back_for_unknown_tunnel() {
+        let _journal = fresh_journal();
+        let runner = FakeRunner::new();
+
+        disconnect(&runner, CODE, Backend::WgQuick).unwrap();
+
+        assert_eq!(commands(&runner), ["nft -f", "wg-quick down"]);
+    }
+
+    #[test]
+    fn disconnect_takes_tunnel_down_when_dns_rules_stay() {
+        let _journal = fresh_journal();
+        let runner = FakeRunner::new();
+        connect_with(&runner, Backend::WgQuick).unwrap();
+        runner.respond("nft", 1, "", "netlink: Error: Operation not permitted\n");
+
+        let error = disconnect(&runner, CODE, Backend::WgQuick).unwrap_err();
+
+        assert!(matches!(error, ConnectError::Firewall(_)));
+        assert!(runner.calls().contains(&"wg-quick down se-mma-wg-001".to_string()));
+        // Only the rules are left for reconcile to remove
+        let journal = journal::load().unwrap();
+        assert_eq!(journal.in_progress, Some(Operation::Disconnect));
+        assert_eq!(journal.applied, [Applied::DnsRules]);
+    }
+
+    #[test]
+    fn reconcile_rolls_back_interrupted_connect() {
+        let _journal = fresh_journal();
+        kernel::add_link(CODE);
+        journal::save(&Journal {
+            in_progress: Some(Operation::Connect),
+            applied: vec![interface(Backend::Netlink), resolver()],
+        })
+        .unwrap();
+        let runner = FakeRunner::new();
+
+        let actions = reconcile(&runner).unwrap();
+
+        assert_eq!(actions, ["rolled back interrupted connect to se-mma-wg-001"]);
+        assert!(kernel::links().is_empty());
+        assert_eq!(commands(&runner), ["resolvconf -d"]);
+        assert!(journal::load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reconcile_reapplies_dns_rules_of_live_tunnel() {
+        let _journal = fresh_journal();
+        let runner = FakeRunner::new();
+        connect_with(&runner, Backend::Netlink).unwrap();
+        let before = journal::load().unwrap().applied;
+
+        let actions = reconcile(&runner).unwrap();
+
+        assert!(actions.is_empty());
+        assert_eq!(commands(&runner), ["resolvconf -a", "nft -f", "nft -f"]);
+        assert_eq!(kernel::links(), [CODE]);
+        assert_eq!(journal::load().unwrap().applied, before);
+    }
+
+    #[test]
+    fn reconcile_cleans_up_after_tunnel_that_went_down() {
+        let _journal = fresh_journal();
+        journal::save(&Journal {
+            in_progress: None,
+            applied: vec![interface(Backend::WgQuick), resolver(), Applied::DnsRules],
+        })
+        .unwrap();
+        let runner = FakeRunner::new();
+
+        let actions = reconcile(&runner).unwrap();
+
+        assert_eq!(
+            actions,
+            [
+                "cleaned up after se-mma-wg-001, which went down",
+                "removed leftover DNS rules"
+            ]
+        );
+        // The interface is gone already, so wg-quick isn't asked to take it down
+        assert_eq!(commands(&runner), ["resolvconf -d", "nft -f"]);
+        assert!(journal::load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reconcile_removes_kill_switch_of_tunnel_that_went_down() {
+        let _journal = fresh_journal();
+        journal::save(&Journal {
+            in_progress: None,
+            applied: vec![Applied::KillSwitch { mode: KillSwitch::On }, interface(Backend::WgQuick)],
+        })
+        .unwrap();
+        let runner = FakeRunner::new();
+
+        let actions = reconcile(&runner).unwrap();
+
+        assert_eq!(
+            actions,
+            [
+                "cleaned up after se-mma-wg-001, which went down",
+                "removed leftover kill switch"
+            ]
+        );
+        assert_eq!(commands(&runner), ["nft -f"]);
+        assert!(journal::load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reconcile_keeps_lockdown_without_tunnel() {
+        let _journal = fresh_journal();
+        let lockdown = Applied::KillSwitch {
+            mode: KillSwitch::Lockdown,
+        };
+        journal::save(&Journal {
+            in_progress: Some(Operation::Disconnect),
+            applied: vec![lockdown.clone(), interface(Backend::WgQuick)],
+        })
+        .unwrap();
+        let runner = FakeRunner::new();
+
+        let actions = reconcile(&runner).unwrap();
+
+        assert_eq!(actions, ["rolled back interrupted disconnect from se-mma-wg-001"]);
+        assert!(runner.calls().is_empty());
+        let journal = journal::load().unwrap();
+        assert_eq!(journal.in_progress, None);
+        assert_eq!(journal.applied, [lockdown]);
+    }
+
+    #[test]
+    fn wg_quick_up_retries_after_resolvconf_signature_mismatch() {
+        let runner = FakeRunner::new();
+        runner.respond("wg-quick up", 1, "", "resolvconf: signature mismatch: /etc/resolv.conf\n");
+
+        connect_wg_quick(&runner, CODE).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            [
+                "wg-quick up se-mma-wg-001",
+                "resolvconf -u",
+                "wg-quick up se-mma-wg-001"
+            ]
+        );
+    }
+
+    #[test]
+    fn wg_quick_up_reports_dns_error_when_retry_fails() {
+        let runner = FakeRunner::new();
+        runner
+            .respond("wg-quick up", 1, "", "resolvconf: signature mismatch: /etc/resolv.conf\n")
+            .respond("wg-quick up", 1, "", "resolvconf: signature mismatch: /etc/resolv.conf\n");
+
+        let error = connect_wg_quick(&runner, CODE).unwrap_err();
+
+        assert!(matches!(error, ConnectError::Dns(msg) if msg.contains("after resolvconf fix")));
+        assert_eq!(runner.calls().len(), 3);
+    }
+
+    #[test]
+    fn wg_quick_up_detects_missing_module() {
+        let runner = FakeRunner::new();
+        runner.respond(
+            "wg-quick up",
+            1,
+            "[#] ip link add se-mma-wg-001 type wireguard\n",
+            "RTNETLINK answers: Operation not supported\nUnable to access interface: Protocol not supported\n",
+        );
+
+        let error = connect_wg_quick(&runner, CODE).unwrap_err();
+
+        assert!(matches!(error, ConnectError::ModuleMissing));
+        assert_eq!(error.exit_code(), 3);
+    }
+
+    #[test]
+    fn wg_quick_up_detects_existing_interface() {
+        let runner = FakeRunner::new();
+        runner.respond("wg-quick up", 1, "", "wg-quick: `se-mma-wg-001' already exists\n");
+
+        let error = connect_wg_quick(&runner, CODE).unwrap_err();
+
+        assert!(matches!(&error, ConnectError::InterfaceExists(name) if name == CODE));
+        assert_eq!(runner.calls(), ["wg-quick up se-mma-wg-001"]);
+    }
+
+    #[test]
+    fn wg_quick_missing_is_reported() {
+        let runner = FakeRunner::new();
+        runner.fail_spawn("wg-quick", io::ErrorKind::NotFound);
+
+        let error = connect_wg_quick(&runner, CODE).unwrap_err();
+
+        assert!(matches!(error, ConnectError::Other(msg) if msg.contains("wg-quick not found")));
+    }
+
+    #[test]
+    fn enable_autostart_disables_previous_server() {
+        let runner = FakeRunner::new();
+        runner.respond(
+            "systemctl list-unit-files",
+            0,
+            "wg-quick@wg0.service enabled enabled\n\
+             wg-quick@de-fra-wg-002.service enabled enabled\n",
+            "",
+        );
+
+        enable_autostart(&runner, CODE).unwrap();
+
+        assert_eq!(
+            runner.calls(),
+            [
+                "systemctl list-unit-files wg-quick@*.service --no-legend",
+                "systemctl disable wg-quick@de-fra-wg-002",
+                "systemctl enable wg-quick@se-mma-wg-001"
+            ]
+        );
+    }
+
+    #[test]
+    fn enable_autostart_keeps_same_server() {
+        let runner = FakeRunner::new();
+        runner.respond(
+            "systemctl list-unit-files",
+            0,
+            "wg-quick@se-mma-wg-001.service enabled enabled\n",
+            "",
+        );
+
+        enable_autostart(&runner, CODE).unwrap();
+
+        assert!(!runner.calls().iter().any(|call| call.starts_with("systemctl disable")));
+    }
+
+    #[test]
+    fn enable_autostart_reports_permission_error() {
+        let runner = FakeRunner::new();
+        runner.respond(
+            "systemctl enable",
+            1,
+            "",
+            "Failed to enable unit: Access denied\nPermission denied\n",
+        );
+
+        let error = enable_autostart(&runner, CODE).unwrap_err();
+
+        assert!(matches!(error, ConnectError::Permission(_)));
+    }
+
+    #[test]
+    fn get_enabled_server_ignores_disabled_and_foreign_units() {
+        let runner = FakeRunner::new();
+        runner.respond(
+            "systemctl list-unit-files",
+            0,
+            "wg-quick@ch-zrh-wg-001.service disabled enabled\n\
+             wg-quick@home.service enabled enabled\n",
+            "",
+        );
+
+        assert_eq!(get_enabled_server(&runner), None);
+    }
+
+    #[test]
+    fn parse_dump_reads_peer_line() {
+        let interface = "cHJpdmF0ZQ==\tcHVibGlj\t51820\t51820\n";
+        let peer = |endpoint: &str, handshake: &str, keepalive: &str| {
+            format!(
+                "{}cGVlcg==\t(none)\t{}\t0.0.0.0/0,::/0\t{}\t2048\t1024\t{}\n",
+                interface, endpoint, handshake, keepalive
+            )
+        };
+        let stats = |endpoint: Option<&str>, handshake, keepalive| {
+            Some(TunnelStats {
+                endpoint: endpoint.map(str::to_string),
+                latest_handshake: handshake,
+                rx_bytes: 2048,
+                tx_bytes: 1024,
+                keepalive,
+            })
+        };
+
+        let cases = [
+            (
+                peer("185.65.134.2:51820", "1700000000", "25"),
+                stats(Some("185.65.134.2:51820"), Some(1700000000), Some(25)),
+            ),
+            // No endpoint known yet
+            (peer("(none)", "1700000000", "25"), stats(None, Some(1700000000), Some(25))),
+            // No handshake yet
+            (
+                peer("185.65.134.2:51820", "0", "25"),
+                stats(Some("185.65.134.2:51820"), None, Some(25)),
+            ),
+            // Keepalive off
+            (
+                peer("185.65.134.2:51820", "1700000000", "off"),
+                stats(Some("185.65.134.2:51820"), Some(1700000000), None),
+            ),
+            // Only the interface line: no peer
+            (interface.to_string(), None),
+            // Truncated peer line
+            (format!("{}cGVlcg==\t(none)\t(none)\n", interface), None),
+            (String::new(), None),
+        ];
+
+        for (dump, expected) in cases {
+            assert_eq!(parse_dump(&dump), expected, "{:?}", dump);
+        }
+    }
+
+    #[test]
+    fn get_status_finds_mullvad_interface() {
+        let runner = FakeRunner::new();
+        runner.respond(
+            "wg show",
+            0,
+            "interface: wg0\n  listening port: 51820\n\ninterface: se-mma-wg-001\n  listening port: 40000\n",
+            "",
+        );
+
+        assert_eq!(get_status(&runner), ConnectionStatus::Connected(CODE.to_string()));
+    }
 }